@@ -1,139 +1,300 @@
 use anarchy_core::{
-  parse, ExecutionContext, LanguageError, ParsedLanguage, UntrackedValue, Value, VariableKey,
+  eval_expression, execute, BlendMode, CompiledProgram, ExecutionContext, FrameParams,
+  MissingChannelMode, NonFiniteMode, ParseError, ParseOptions, Value, STACK_SIZE,
 };
-use std::rc::Rc;
-use std::sync::Mutex;
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 #[global_allocator]
 static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
 
+// RGBA for pixels that return before writing a color
+const CLEAR_COLOR: [u8; 4] = [0, 0, 0, 255];
+const FRAMES: u32 = 500;
+// print() runs for every pixel of every frame, so only show the start
+const MAX_PRINTS: usize = 100;
+
+struct Args {
+  // `-` for stdin
+  path: Option<String>,
+  // Where to save a PNG of the rendered frame, if anywhere
+  out: Option<String>,
+  frame: u32,
+  // Read lines from stdin and run them one at a time instead of rendering
+  repl: bool,
+}
+
+// anarchy_cli [--out path.png] [--frame N] [--repl] [path]
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+  let mut parsed = Args {
+    path: None,
+    out: None,
+    frame: FRAMES - 1,
+    repl: false,
+  };
+  while let Some(arg) = args.next() {
+    match arg.as_str() {
+      "--out" => parsed.out = Some(args.next().ok_or("--out needs a path")?),
+      "--frame" => {
+        let value = args.next().ok_or("--frame needs a value")?;
+        parsed.frame = match value.parse::<u32>() {
+          Ok(frame) if frame < FRAMES => frame,
+          _ => return Err(format!("--frame must be between 0 and {}", FRAMES - 1)),
+        };
+      }
+      "--repl" => parsed.repl = true,
+      flag if flag.starts_with("--") => return Err(format!("Unknown flag {flag}")),
+      path => parsed.path = Some(path.to_string()),
+    }
+  }
+  Ok(parsed)
+}
+
 fn main() {
-  let code = std::fs::read("./input.anarchy").unwrap();
-  let code = String::from_utf8_lossy(&code);
+  // Recursive programs need more stack than the main thread is given in
+  // debug builds
+  let cli = std::thread::Builder::new()
+    .stack_size(STACK_SIZE)
+    .spawn(run)
+    .unwrap();
+  if cli.join().is_err() {
+    std::process::exit(101);
+  }
+}
+
+fn run() {
+  let args = match parse_args(std::env::args().skip(1)) {
+    Ok(args) => args,
+    Err(err) => {
+      eprintln!("{err}");
+      eprintln!("Usage: anarchy_cli [--out path.png] [--frame N] [--repl] [path]");
+      std::process::exit(1);
+    }
+  };
+  if args.repl {
+    repl();
+    return;
+  }
+  let code = match args.path.as_deref() {
+    Some("-") => std::io::read_to_string(std::io::stdin()).unwrap(),
+    None if !std::io::stdin().is_terminal() => std::io::read_to_string(std::io::stdin()).unwrap(),
+    path => {
+      let code = std::fs::read(path.unwrap_or("./input.anarchy")).unwrap();
+      String::from_utf8_lossy(&code).into_owned()
+    }
+  };
   // let mut context = ExecutionContext::default();
   // execute(&mut context, pairs).unwrap();
   // println!("Executed program at ./input.anarchy Resulting state: {context}");
   //torture_test();
   // let code = include_str!("../../input.anarchy"); // r=time&255;g=time&255;b=time&255;".to_owned();
-  let context = Rc::new(Mutex::new(ExecutionContext::default()));
-  let parsed_language = parse(context.clone(), &code).unwrap();
+  let mut context = ExecutionContext::default();
+  let options = ParseOptions {
+    implicit_color_output: true,
+  };
+  let mut parsed_language = match options.parse(&mut context, &code) {
+    Ok(parsed_language) => parsed_language,
+    Err(error) => {
+      eprintln!("{}", error.render(&code));
+      std::process::exit(1);
+    }
+  };
   println!("Finished parsing!");
-  let mut context = Rc::try_unwrap(context).unwrap().into_inner().unwrap();
+  for warning in parsed_language.lint(&context.export_scope_locations()) {
+    println!("{warning}");
+  }
+  parsed_language.optimize();
+  let prints = AtomicUsize::new(0);
+  context.set_print_sink(move |line| match prints.fetch_add(1, Ordering::Relaxed) {
+    count if count < MAX_PRINTS => println!("{line}"),
+    MAX_PRINTS => println!("(print output stopped after {MAX_PRINTS} lines)"),
+    _ => {}
+  });
+  if let Err(errors) = parsed_language.typecheck(&context.export_scope_locations()) {
+    for error in errors {
+      println!("{}", error.render(&code));
+    }
+  }
   const HEIGHT: usize = 100;
   const WIDTH: usize = 100;
   let random = 0f32;
   let mut image = [0u8; WIDTH * HEIGHT * 4];
 
-  context.set_runtime("x", Value::Number(0.0));
-  context.set_runtime("y", Value::Number(0.0));
-  context.set_runtime("time", Value::Number(0.0));
-  context.set_runtime("random", Value::Number(0.0));
-  Result::from(anarchy_core::execute(&mut context, &parsed_language)).unwrap();
+  context.set_runtime("x", Value::number(0.0));
+  context.set_runtime("y", Value::number(0.0));
+  context.set_runtime("time", Value::number(0.0));
+  context.set_runtime("random", Value::number(0.0));
+  if let Err(error) = Result::from(anarchy_core::execute(&mut context, &parsed_language)) {
+    eprintln!("{}", error.render(&code));
+    std::process::exit(1);
+  }
   println!("After execution: {context}");
 
-  let r_identifier = context.register(VariableKey {
-    name: "r".to_string(),
-    scope: "".to_string(),
-  });
-  let g_identifier = context.register(VariableKey {
-    name: "g".to_string(),
-    scope: "".to_string(),
-  });
-  let b_identifier = context.register(VariableKey {
-    name: "b".to_string(),
-    scope: "".to_string(),
-  });
-  let time_identifier = context.register(VariableKey {
-    name: "time".to_string(),
-    scope: "".to_string(),
-  });
-  let random_identifier = context.register(VariableKey {
-    name: "random".to_string(),
-    scope: "".to_string(),
-  });
-  let x_identifier = context.register(VariableKey {
-    name: "x".to_string(),
-    scope: "".to_string(),
-  });
-  let y_identifier = context.register(VariableKey {
-    name: "y".to_string(),
-    scope: "".to_string(),
-  });
-
-  for time in 0..500 {
-    run_iteration(
-      &parsed_language,
-      &mut image,
-      WIDTH,
-      HEIGHT,
-      time,
-      random,
-      IdentifierBundle {
-        r_identifier,
-        g_identifier,
-        b_identifier,
-        x_identifier,
-        y_identifier,
-        time_identifier,
-        random_identifier,
-      },
-      &mut context,
+  let mut compiled = CompiledProgram::new(context, parsed_language);
+  let params = FrameParams {
+    width: WIDTH,
+    height: HEIGHT,
+    time: args.frame as f32,
+    random,
+    identifiers: compiled.identifiers(),
+    variables: &[],
+    invariant: None,
+    clear_color: CLEAR_COLOR,
+    blend: BlendMode::Replace,
+    non_finite: NonFiniteMode::Error,
+    missing_channel: MissingChannelMode::Error,
+  };
+  if let Err(error) = compiled.render(params, &mut image) {
+    eprintln!("{}", error.render(&code));
+    std::process::exit(1);
+  }
+  if let Some(out) = &args.out {
+    image::save_buffer(
+      out,
+      &image,
+      WIDTH as u32,
+      HEIGHT as u32,
+      image::ColorType::Rgba8,
     )
     .unwrap();
+    println!("Saved frame {} to {out}", args.frame);
   }
 }
 
-struct IdentifierBundle {
-  r_identifier: usize,
-  g_identifier: usize,
-  b_identifier: usize,
-  x_identifier: usize,
-  y_identifier: usize,
-  time_identifier: usize,
-  random_identifier: usize,
+// Runs lines from stdin against the same context until EOF, so variables
+// carry over from one line to the next. Functions only last for the line
+// that defines them
+fn repl() {
+  let mut context = ExecutionContext::default();
+  context.set_print_sink(|line| println!("{line}"));
+  let interactive = std::io::stdin().is_terminal();
+  let mut lines = std::io::stdin().lines();
+  loop {
+    if interactive {
+      print!("> ");
+      std::io::stdout().flush().unwrap();
+    }
+    let Some(Ok(line)) = lines.next() else {
+      break;
+    };
+    let line = line.trim();
+    if line.is_empty() {
+      continue;
+    }
+    match run_line(&mut context, line) {
+      Ok(Some(value)) => println!("{value}"),
+      Ok(None) => {}
+      Err((error, code)) => println!("{}", error.render(&code)),
+    }
+  }
 }
 
-#[allow(clippy::too_many_arguments)]
-fn run_iteration(
-  parsed_language: &ParsedLanguage,
-  image: &mut [u8],
-  width: usize,
-  height: usize,
-  time: u32,
-  random: f32,
-  IdentifierBundle {
-    r_identifier,
-    g_identifier,
-    b_identifier,
-    x_identifier,
-    y_identifier,
-    time_identifier,
-    random_identifier,
-  }: IdentifierBundle,
+// Evaluates `line` if it's an expression, otherwise runs it as statements,
+// adding the final `;` if it was left off. Errors come with the code they
+// point into
+fn run_line(
   context: &mut ExecutionContext,
-) -> Result<(), LanguageError> {
-  let time_float: Value = (time as f32).into();
-  let random_float: Value = random.into();
-  for y in 0..height {
-    let y_float: Value = (y as f32).into();
-    for x in 0..width {
-      context.reset();
-      context.set(x_identifier, (x as f32).into());
-      context.set(y_identifier, y_float.clone());
-      context.set(time_identifier, time_float.clone());
-      context.set(random_identifier, random_float.clone());
-
-      Result::from(anarchy_core::execute(context, parsed_language))?;
-
-      let base_position = height * x * 4 + y * 4;
-      println!("Seems legit {context}");
-      let r: f32 = UntrackedValue(context.unattributed_get(r_identifier)?).try_into()?;
-      let g: f32 = UntrackedValue(context.unattributed_get(g_identifier)?).try_into()?;
-      let b: f32 = UntrackedValue(context.unattributed_get(b_identifier)?).try_into()?;
-      image[base_position] = r as u8;
-      image[base_position + 1] = g as u8;
-      image[base_position + 2] = b as u8;
+  line: &str,
+) -> Result<Option<Value>, (ParseError, String)> {
+  match eval_expression(context, line) {
+    Err(ParseError::PestError(_)) => {}
+    // print() already showed the value, so echoing it would repeat it
+    Ok(_) if is_print_call(line) => return Ok(None),
+    result => return result.map(Some).map_err(|error| (error, line.to_string())),
+  }
+  let code = if line.ends_with(';') || line.ends_with('}') {
+    line.to_string()
+  } else {
+    format!("{line};")
+  };
+  let parsed = match ParseOptions::default().parse(context, &code) {
+    Ok(parsed) => parsed,
+    Err(error) => return Err((error, code)),
+  };
+  if let Err(error) = Result::from(execute(context, &parsed)) {
+    return Err((ParseError::from(error), code));
+  }
+  Ok(None)
+}
+
+// Whether `line` is a single call to print(), as opposed to one with more
+// after it like `print(x) + 1`
+fn is_print_call(line: &str) -> bool {
+  let Some(arguments) = line
+    .strip_prefix("print")
+    .and_then(|rest| rest.trim_start().strip_prefix('('))
+  else {
+    return false;
+  };
+  let mut depth = 0;
+  let mut chars = arguments.char_indices();
+  while let Some((index, char)) = chars.next() {
+    match char {
+      '"' => {
+        // Skip to the end of the string, past any \" or \\ in it
+        while let Some((_, char)) = chars.next() {
+          match char {
+            '\\' => {
+              chars.next();
+            }
+            '"' => break,
+            _ => {}
+          }
+        }
+      }
+      '(' => depth += 1,
+      ')' if depth == 0 => return arguments[index + 1..].trim().is_empty(),
+      ')' => depth -= 1,
+      _ => {}
     }
   }
-  Ok(())
+  false
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::{Arc, Mutex};
+
+  // A context whose print() output ends up in the returned Vec
+  fn printing_context() -> (ExecutionContext, Arc<Mutex<Vec<String>>>) {
+    let mut context = ExecutionContext::default();
+    let printed = Arc::new(Mutex::new(Vec::new()));
+    let sink = Arc::clone(&printed);
+    context.set_print_sink(move |line| sink.lock().unwrap().push(line.to_string()));
+    (context, printed)
+  }
+
+  fn echoed(context: &mut ExecutionContext, line: &str) -> Option<f32> {
+    run_line(context, line)
+      .map_err(|(error, code)| error.render(&code))
+      .unwrap()
+      .map(|value| value.as_number().unwrap())
+  }
+
+  #[test]
+  fn print_is_not_echoed() {
+    let (mut context, printed) = printing_context();
+    assert_eq!(echoed(&mut context, "print(3)"), None);
+    assert_eq!(echoed(&mut context, "print (\")\" )"), None);
+    assert_eq!(*printed.lock().unwrap(), ["Number(3)", "Str(\")\")"]);
+  }
+
+  #[test]
+  fn expressions_around_print_are_echoed() {
+    let (mut context, printed) = printing_context();
+    assert_eq!(echoed(&mut context, "print(1) + print(2)"), Some(3.0));
+    assert_eq!(echoed(&mut context, "(print(4))"), Some(4.0));
+    assert_eq!(
+      *printed.lock().unwrap(),
+      ["Number(1)", "Number(2)", "Number(4)"]
+    );
+  }
+
+  #[test]
+  fn expressions_are_echoed_and_statements_are_not() {
+    let (mut context, _) = printing_context();
+    assert_eq!(echoed(&mut context, "x = 2"), None);
+    assert_eq!(echoed(&mut context, "x * 3"), Some(6.0));
+  }
 }
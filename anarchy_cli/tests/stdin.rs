@@ -0,0 +1,69 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+fn run_with_stdin(args: &[&str], program: &str) -> Output {
+  let mut cli = Command::new(env!("CARGO_BIN_EXE_anarchy_cli"))
+    .args(args)
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()
+    .unwrap();
+  cli
+    .stdin
+    .take()
+    .unwrap()
+    .write_all(program.as_bytes())
+    .unwrap();
+  cli.wait_with_output().unwrap()
+}
+
+#[test]
+fn reads_the_program_from_stdin_given_a_dash() {
+  let output = run_with_stdin(&["--frame", "0", "-"], "r = 12; g = 34; b = 56;");
+  let stdout = String::from_utf8(output.stdout).unwrap();
+  assert!(output.status.success(), "{stdout}");
+  assert!(stdout.contains("::r = Number(12)"), "{stdout}");
+  assert!(stdout.contains("::b = Number(56)"), "{stdout}");
+}
+
+#[test]
+fn reads_piped_stdin_without_a_path() {
+  let output = run_with_stdin(&["--frame", "0"], "r = 12; g = 34; b = 56;");
+  let stdout = String::from_utf8(output.stdout).unwrap();
+  assert!(output.status.success(), "{stdout}");
+  assert!(stdout.contains("::g = Number(34)"), "{stdout}");
+}
+
+#[test]
+fn saves_a_piped_program_as_png() {
+  let out = std::env::temp_dir().join(format!("anarchy_cli_stdin_{}.png", std::process::id()));
+  let output = run_with_stdin(
+    &["--out", out.to_str().unwrap(), "--frame", "0", "-"],
+    "r = x; g = y; b = 7;",
+  );
+  assert!(output.status.success());
+  let image = image::open(&out).unwrap().into_rgba8();
+  std::fs::remove_file(&out).unwrap();
+  assert_eq!(image.dimensions(), (100, 100));
+  assert_eq!(image.get_pixel(3, 2).0, [3, 2, 7, 255]);
+}
+
+#[test]
+fn renders_the_chosen_frame() {
+  let out = std::env::temp_dir().join(format!("anarchy_cli_frame_{}.png", std::process::id()));
+  let output = run_with_stdin(
+    &["--out", out.to_str().unwrap(), "--frame", "42", "-"],
+    "r = time; g = 0; b = 0;",
+  );
+  assert!(output.status.success());
+  let image = image::open(&out).unwrap().into_rgba8();
+  std::fs::remove_file(&out).unwrap();
+  assert_eq!(image.get_pixel(0, 0).0, [42, 0, 0, 255]);
+}
+
+#[test]
+fn parse_errors_from_stdin_fail() {
+  let output = run_with_stdin(&["-"], "r = ;");
+  assert_eq!(output.status.code(), Some(1));
+}
@@ -0,0 +1,168 @@
+// Times whole frames of a few programs. Run with
+// `cargo bench -p anarchy_core --features rayon,simd`, and on a parent commit to
+// compare before and after a change
+use anarchy_core::*;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+// Counts heap allocations, so changes that avoid them show up even when the
+// timing is too noisy to tell
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+  unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    unsafe { System.alloc(layout) }
+  }
+  unsafe fn dealloc(&self, pointer: *mut u8, layout: Layout) {
+    unsafe { System.dealloc(pointer, layout) }
+  }
+  unsafe fn realloc(&self, pointer: *mut u8, layout: Layout, size: usize) -> *mut u8 {
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    unsafe { System.realloc(pointer, layout, size) }
+  }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const WIDTH: usize = 512;
+const HEIGHT: usize = 512;
+
+const ARITHMETIC: &str = "r = (x * time + y) % 256;
+g = (x ^ y) & 255;
+b = sin(x / 20) * 128 + 128;";
+
+// Lots of variables that every pixel has to start without
+fn many_variables() -> String {
+  let mut source = String::from("t0 = x;\n");
+  for index in 1..64 {
+    source += &format!("t{index} = t{} + y;\n", index - 1);
+  }
+  source + "r = t63 % 256; g = t31 % 256; b = 0;"
+}
+
+// Looks every pixel's color up in a 64 color literal palette, which is
+// built once per context rather than once per pixel
+fn palette() -> String {
+  let colors = (0..64)
+    .map(|index| format!("[{}, {}, {}]", index * 4, 255 - index * 4, index * 2))
+    .collect::<Vec<_>>();
+  format!(
+    "c = [{}][(x + y) % 64];\nr = c[0]; g = c[1]; b = c[2];",
+    colors.join(", ")
+  )
+}
+
+// Small tuples built, combined and taken apart again for every pixel
+const SMALL_TUPLES: &str = "p = [x, y];
+q = p * 2 + [1, 3];
+c = [q[0] % 256, q[1] % 256, (x ^ y) & 255];
+r = c[0]; g = c[1]; b = c[2];";
+
+// User-defined functions called a few times per pixel, with more arguments
+// than fit inline as well as fewer
+const FUNCTION_CALLS: &str = "function shade(a, b) { return (a * b + a) % 256; }
+function mix5(a, b, c, d, e) { return (a + b * 2 + c * 3 + d * 4 + e * 5) % 256; }
+r = shade(x, y);
+g = shade(y, x + 1);
+b = mix5(x, y, r, g, 3);";
+
+// A tuple variable read over and over by indexing and the vector builtins
+const TUPLE_READS: &str = "t = [x, y, x * y, 1];
+r = (t[0] + t[1] + len(t)) % 256;
+g = dot(t, t) % 256;
+b = (sum(t) + magnitude(t) + t[2] * t[3]) % 256;";
+
+// Number variables read many times each in nested arithmetic
+const NUMBER_READS: &str = "a = x * 0.5; c = y + 1; d = a * c - a / c;
+r = (a + c + d * (a - c)) % 256;
+g = (d * a - c * d + a * (c + d)) % 256;
+b = ((a > c) * 255 + d * d - a * c) % 256;";
+
+// Prints the mean time and heap allocations per call of `frame`, run for
+// about a second after a warm-up call
+fn bench(name: &str, mut frame: impl FnMut()) {
+  frame();
+  let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+  let start = Instant::now();
+  let mut frames = 0;
+  while start.elapsed() < Duration::from_secs(1) {
+    frame();
+    frames += 1;
+  }
+  let mean = start.elapsed() / frames;
+  let allocations = (ALLOCATIONS.load(Ordering::Relaxed) - allocations) / u64::from(frames);
+  println!(
+    "{name:<32} {:>8.2}ms {allocations:>10} allocations",
+    mean.as_secs_f64() * 1000.0
+  );
+}
+
+fn params(identifiers: FrameIdentifiers) -> FrameParams<'static> {
+  FrameParams {
+    width: WIDTH,
+    height: HEIGHT,
+    time: 10.0,
+    random: 0.0,
+    identifiers,
+    variables: &[],
+    invariant: None,
+    clear_color: [0, 0, 0, 255],
+    blend: BlendMode::Replace,
+    non_finite: NonFiniteMode::Error,
+    missing_channel: MissingChannelMode::Error,
+  }
+}
+
+fn serial(name: &str, source: &str) {
+  let mut context = ExecutionContext::default();
+  let program = ParseOptions::default().parse(&mut context, source).unwrap();
+  let mut compiled = CompiledProgram::new(context, program);
+  let mut out = vec![0; WIDTH * HEIGHT * 4];
+  bench(name, || {
+    compiled
+      .render(params(compiled.identifiers()), &mut out)
+      .unwrap()
+  });
+}
+
+#[cfg(feature = "rayon")]
+fn parallel(name: &str, source: &str) {
+  let mut context = ExecutionContext::default();
+  let program = ParseOptions::default().parse(&mut context, source).unwrap();
+  let params = params(FrameIdentifiers::register(&mut context));
+  let lut = context.export_scope_locations();
+  let mut out = vec![0; WIDTH * HEIGHT * 4];
+  bench(name, || {
+    render_frame_parallel(&lut, &program, &params, &mut out).unwrap()
+  });
+}
+
+#[cfg(feature = "simd")]
+fn simd(name: &str, source: &str) {
+  let mut context = ExecutionContext::default();
+  let program = ParseOptions::default().parse(&mut context, source).unwrap();
+  let params = params(FrameIdentifiers::register(&mut context));
+  let mut out = vec![0; WIDTH * HEIGHT * 4];
+  bench(name, || {
+    render_frame_simd(&mut context, &program, &params, &mut out).unwrap()
+  });
+}
+
+fn main() {
+  serial("arithmetic", ARITHMETIC);
+  #[cfg(feature = "rayon")]
+  parallel("arithmetic, parallel", ARITHMETIC);
+  #[cfg(feature = "simd")]
+  simd("arithmetic, simd", ARITHMETIC);
+  serial("64 variables", &many_variables());
+  serial("palette", &palette());
+  serial("small tuples", SMALL_TUPLES);
+  serial("function calls", FUNCTION_CALLS);
+  serial("tuple reads", TUPLE_READS);
+  serial("number reads", NUMBER_READS);
+}
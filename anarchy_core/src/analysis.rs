@@ -0,0 +1,736 @@
+use crate::{
+  ElseBranch, ExecutionContextLUT, Expression, ExpressionOp, FunctionIdentifier, Identifier,
+  Location, ParsedLanguage, Statement, VariableKey,
+};
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::iter::zip;
+
+#[derive(Debug, Clone)]
+pub struct Warning {
+  pub location: Location,
+  pub warning: WarningType,
+}
+
+#[derive(Debug, Clone)]
+pub enum WarningType {
+  // A constant division like `255 / 256` used as a bitwise operand, which
+  // truncates to 0 (usually a missing pair of parentheses)
+  TruncatedBitwiseOperand(f32, f32),
+  // A variable that's assigned but never read anywhere, often a typo like
+  // `rr` for `r`
+  UnusedVariable(String),
+}
+
+// What a span returned by ParsedLanguage::spans covers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpanKind {
+  Number,
+  String,
+  // A whole tuple literal, brackets and all
+  Tuple,
+  Variable,
+  // A whole call to a builtin (map and reduce too), host function or function
+  // the program defines
+  Builtin,
+  HostFunction,
+  Function,
+  // An operator's symbol, though indexing, slicing and ?: run on to the end
+  // of what they take
+  Operator,
+}
+
+impl fmt::Display for Warning {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "Warning @ {}: {}", self.location, self.warning)
+  }
+}
+
+impl fmt::Display for WarningType {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      WarningType::TruncatedBitwiseOperand(lhs, rhs) => write!(
+        f,
+        "Hint: {lhs} / {rhs} is truncated to 0 by the surrounding bitwise operator, did you mean to parenthesize it?"
+      ),
+      WarningType::UnusedVariable(name) => {
+        write!(f, "Hint: {name} is assigned but never read")
+      }
+    }
+  }
+}
+
+impl ParsedLanguage {
+  pub fn lint(&self, lut: &ExecutionContextLUT) -> Vec<Warning> {
+    let mut warnings = Vec::new();
+    let mut read = BTreeSet::new();
+    self.walk_expressions(&mut |expression| {
+      if let ExpressionOp::Reference(identifier) = &expression.op {
+        read.insert(*identifier);
+      }
+      if let ExpressionOp::BinaryAnd(lhs, rhs)
+      | ExpressionOp::BinaryOr(lhs, rhs)
+      | ExpressionOp::Xor(lhs, rhs)
+      | ExpressionOp::ShiftLeft(lhs, rhs)
+      | ExpressionOp::ShiftRight(lhs, rhs) = &expression.op
+      {
+        for operand in [lhs, rhs] {
+          if let ExpressionOp::Div(numerator, denominator) = &operand.op {
+            if let (
+              ExpressionOp::NumberLiteral(numerator),
+              ExpressionOp::NumberLiteral(denominator),
+            ) = (&numerator.op, &denominator.op)
+            {
+              if numerator.abs() < denominator.abs() {
+                warnings.push(Warning {
+                  location: operand.location.clone(),
+                  warning: WarningType::TruncatedBitwiseOperand(*numerator, *denominator),
+                });
+              }
+            }
+          }
+        }
+      }
+    });
+
+    let mut assigned = BTreeMap::new();
+    first_assignments(&self.top_level, &mut assigned);
+    for function in &self.functions {
+      first_assignments(&function.contents, &mut assigned);
+    }
+    for (identifier, location) in assigned {
+      let key = lut.scope_locations.get_by_right(&identifier).unwrap();
+      // The host reads the outputs and sets the inputs itself
+      let host_owned = key.scope.is_empty()
+        && matches!(
+          key.name.as_str(),
+          "r" | "g" | "b" | "a" | "x" | "y" | "u" | "v" | "aspect" | "time" | "random"
+        );
+      if !read.contains(&identifier) && !host_owned {
+        warnings.push(Warning {
+          location: location.clone(),
+          warning: WarningType::UnusedVariable(key.name.clone()),
+        });
+      }
+    }
+    warnings.sort_by_key(|warning| (warning.location.start_line, warning.location.start_column));
+    warnings
+  }
+
+  // Where each expression came from in the source, in source order, for
+  // editors to highlight. Statements don't keep their own spans, only the
+  // expressions in them do. Call it before optimize(), which folds away
+  // whatever it can
+  pub fn spans(&self) -> Vec<(Location, SpanKind)> {
+    let mut spans = Vec::new();
+    self.walk_expressions(&mut |expression| {
+      let kind = match &expression.op {
+        ExpressionOp::NumberLiteral(_) => SpanKind::Number,
+        ExpressionOp::StringLiteral(_) => SpanKind::String,
+        ExpressionOp::TupleLiteral(_) | ExpressionOp::ConstantTuple(..) => SpanKind::Tuple,
+        ExpressionOp::Reference(_) => SpanKind::Variable,
+        ExpressionOp::FunctionCall(FunctionIdentifier::UserDefined(_), _) => SpanKind::Function,
+        ExpressionOp::FunctionCall(FunctionIdentifier::Host(_), _) => SpanKind::HostFunction,
+        ExpressionOp::FunctionCall(..) => SpanKind::Builtin,
+        _ => SpanKind::Operator,
+      };
+      spans.push((expression.location.clone(), kind));
+    });
+    spans.sort_by_key(|(location, _)| (location.start_line, location.start_column));
+    spans
+  }
+
+  // Every top-level variable the program reads, in name order. Functions have
+  // their own scope, so nothing they read shows up here. Variables the
+  // program assigns itself are included, so a host can check this against
+  // what it provides (x, y, time and so on)
+  pub fn referenced_identifiers(&self, lut: &ExecutionContextLUT) -> Vec<VariableKey> {
+    let mut referenced = BTreeSet::new();
+    self.walk_expressions(&mut |expression| {
+      if let ExpressionOp::Reference(identifier) = &expression.op {
+        let key = lut.scope_locations.get_by_right(identifier).unwrap();
+        if key.scope.is_empty() {
+          referenced.insert(key.clone());
+        }
+      }
+    });
+    referenced.into_iter().collect()
+  }
+
+  // Splits the program in two: top-level statements that come out the same
+  // no matter what the `varying` variables (usually x and y) hold, and the
+  // rest. Running the first once per frame and the second once per pixel, in
+  // the same context, behaves like running the whole program per pixel
+  pub fn partition_invariant(&self, varying: &[Identifier]) -> (ParsedLanguage, ParsedLanguage) {
+    let mut varying = varying.iter().copied().collect::<BTreeSet<Identifier>>();
+    let assigned = self
+      .top_level
+      .iter()
+      .flat_map(|statement| statement_effects(statement).writes)
+      .collect::<BTreeSet<Identifier>>();
+    // Anything assigned by a per-pixel statement varies too, which can knock
+    // out statements that were invariant, so repeat until it settles
+    let hoisted = loop {
+      let hoisted = self.hoistable(&varying, &assigned);
+      let mut changed = false;
+      for (statement, hoisted) in zip(&self.top_level, &hoisted) {
+        if !hoisted {
+          for variable in statement_effects(statement).writes {
+            changed |= varying.insert(variable);
+          }
+        }
+      }
+      if !changed {
+        break hoisted;
+      }
+    };
+
+    let (invariant, per_pixel): (Vec<_>, Vec<_>) =
+      zip(&self.top_level, hoisted).partition(|(_, hoisted)| *hoisted);
+    (
+      ParsedLanguage {
+        top_level: invariant
+          .into_iter()
+          .map(|(statement, _)| statement.clone())
+          .collect(),
+        functions: self.functions.clone(),
+        output: None,
+      },
+      ParsedLanguage {
+        top_level: per_pixel
+          .into_iter()
+          .map(|(statement, _)| statement.clone())
+          .collect(),
+        functions: self.functions.clone(),
+        output: self.output.clone(),
+      },
+    )
+  }
+
+  fn hoistable(
+    &self,
+    varying: &BTreeSet<Identifier>,
+    assigned: &BTreeSet<Identifier>,
+  ) -> Vec<bool> {
+    // Set by every frame's hoisted statements before any per-pixel ones run
+    let mut defined = BTreeSet::new();
+    let mut read_per_pixel = BTreeSet::new();
+    let mut returned = false;
+    self
+      .top_level
+      .iter()
+      .map(|statement| {
+        let effects = statement_effects(statement);
+        let hoistable = !returned
+          && !effects.returns
+          && effects.pure
+          && effects.reads.iter().all(|variable| {
+            !varying.contains(variable)
+              // Otherwise it would see whatever the previous pixel left
+              && (defined.contains(variable)
+                || effects.bindings.contains(variable)
+                || !assigned.contains(variable))
+          })
+          && effects
+            .writes
+            .iter()
+            .all(|variable| !varying.contains(variable) && !read_per_pixel.contains(variable));
+        if hoistable {
+          if let Statement::Assignment { variable, .. } = statement {
+            defined.insert(*variable);
+          }
+        } else {
+          read_per_pixel.extend(effects.reads);
+          returned |= effects.returns;
+        }
+        hoistable
+      })
+      .collect()
+  }
+
+  // Visits every expression in the program, parents before children
+  pub(crate) fn walk_expressions(&self, visit: &mut impl FnMut(&Expression)) {
+    walk_statements(&self.top_level, visit);
+    if let Some(output) = &self.output {
+      walk_expression(output, visit);
+    }
+    for function in &self.functions {
+      walk_statements(&function.contents, visit);
+    }
+  }
+}
+
+#[derive(Default)]
+struct Effects {
+  reads: BTreeSet<Identifier>,
+  writes: BTreeSet<Identifier>,
+  // Loop variables, which are assigned before the body can read them
+  bindings: BTreeSet<Identifier>,
+  returns: bool,
+  // Doesn't call into functions that could read or do anything else
+  pure: bool,
+}
+
+fn statement_effects(statement: &Statement) -> Effects {
+  let mut effects = Effects {
+    pure: true,
+    ..Default::default()
+  };
+  collect_writes(core::slice::from_ref(statement), &mut effects);
+  walk_statements(
+    core::slice::from_ref(statement),
+    &mut |expression| match &expression.op {
+      ExpressionOp::Reference(identifier) => {
+        effects.reads.insert(*identifier);
+      }
+      ExpressionOp::FunctionCall(FunctionIdentifier::UserDefined(_), _)
+      | ExpressionOp::FunctionCall(FunctionIdentifier::Map(_), _)
+      | ExpressionOp::FunctionCall(FunctionIdentifier::Reduce(_), _)
+      | ExpressionOp::FunctionCall(FunctionIdentifier::Host(_), _)
+      | ExpressionOp::FunctionCall(FunctionIdentifier::Print, _) => effects.pure = false,
+      _ => {}
+    },
+  );
+  effects
+}
+
+fn collect_writes(statements: &[Statement], effects: &mut Effects) {
+  for statement in statements {
+    match statement {
+      Statement::Assignment { variable, .. } => {
+        effects.writes.insert(*variable);
+      }
+      Statement::Return(_) => effects.returns = true,
+      Statement::Call(_) | Statement::Break | Statement::Continue => {}
+      Statement::Repeat(repeat_statement) => {
+        effects.writes.insert(repeat_statement.variable);
+        effects.bindings.insert(repeat_statement.variable);
+        collect_writes(&repeat_statement.block, effects);
+      }
+      Statement::ForEach { binding, body, .. } => {
+        effects.writes.insert(*binding);
+        effects.bindings.insert(*binding);
+        collect_writes(body, effects);
+      }
+      Statement::If(if_statement) => {
+        let mut if_statement = if_statement;
+        loop {
+          collect_writes(&if_statement.if_branch, effects);
+          match &if_statement.else_branch {
+            ElseBranch::IfStatement(else_if) => if_statement = else_if,
+            ElseBranch::ElseStatement(else_block) => {
+              collect_writes(else_block, effects);
+              break;
+            }
+            ElseBranch::None => break,
+          }
+        }
+      }
+    }
+  }
+}
+
+// Where each variable is first assigned, ignoring loop bindings
+fn first_assignments<'a>(
+  statements: &'a [Statement],
+  found: &mut BTreeMap<Identifier, &'a Location>,
+) {
+  for statement in statements {
+    match statement {
+      Statement::Assignment { variable, value } => {
+        found.entry(*variable).or_insert(&value.location);
+      }
+      Statement::Return(_) | Statement::Call(_) | Statement::Break | Statement::Continue => {}
+      Statement::Repeat(repeat_statement) => first_assignments(&repeat_statement.block, found),
+      Statement::ForEach { body, .. } => first_assignments(body, found),
+      Statement::If(if_statement) => {
+        let mut if_statement = if_statement;
+        loop {
+          first_assignments(&if_statement.if_branch, found);
+          match &if_statement.else_branch {
+            ElseBranch::IfStatement(else_if) => if_statement = else_if,
+            ElseBranch::ElseStatement(else_block) => {
+              first_assignments(else_block, found);
+              break;
+            }
+            ElseBranch::None => break,
+          }
+        }
+      }
+    }
+  }
+}
+
+pub(crate) fn walk_statements(statements: &[Statement], visit: &mut impl FnMut(&Expression)) {
+  for statement in statements {
+    match statement {
+      Statement::Assignment { value, .. } => walk_expression(value, visit),
+      Statement::Return(expression) | Statement::Call(expression) => {
+        walk_expression(expression, visit)
+      }
+      Statement::Break | Statement::Continue => {}
+      Statement::Repeat(repeat_statement) => walk_statements(&repeat_statement.block, visit),
+      Statement::ForEach { iterable, body, .. } => {
+        walk_expression(iterable, visit);
+        walk_statements(body, visit);
+      }
+      Statement::If(if_statement) => {
+        let mut if_statement = if_statement;
+        loop {
+          walk_expression(&if_statement.condition, visit);
+          walk_statements(&if_statement.if_branch, visit);
+          match &if_statement.else_branch {
+            ElseBranch::IfStatement(else_if) => if_statement = else_if,
+            ElseBranch::ElseStatement(else_block) => {
+              walk_statements(else_block, visit);
+              break;
+            }
+            ElseBranch::None => break,
+          }
+        }
+      }
+    }
+  }
+}
+
+pub(crate) fn walk_expression(expression: &Expression, visit: &mut impl FnMut(&Expression)) {
+  visit(expression);
+  match &expression.op {
+    ExpressionOp::NumberLiteral(_)
+    | ExpressionOp::StringLiteral(_)
+    | ExpressionOp::Reference(_) => {}
+    ExpressionOp::TupleLiteral(expressions)
+    | ExpressionOp::ConstantTuple(_, expressions)
+    | ExpressionOp::FunctionCall(_, expressions) => {
+      for expression in expressions {
+        walk_expression(expression, visit);
+      }
+    }
+    ExpressionOp::Neg(value) | ExpressionOp::Invert(value) => walk_expression(value, visit),
+    ExpressionOp::Slice(tuple, start, end) => {
+      walk_expression(tuple, visit);
+      for bound in [start, end].into_iter().flatten() {
+        walk_expression(bound, visit);
+      }
+    }
+    ExpressionOp::Ternary(condition, if_true, if_false) => {
+      walk_expression(condition, visit);
+      walk_expression(if_true, visit);
+      walk_expression(if_false, visit);
+    }
+    ExpressionOp::Add(lhs, rhs)
+    | ExpressionOp::Mul(lhs, rhs)
+    | ExpressionOp::Sub(lhs, rhs)
+    | ExpressionOp::Div(lhs, rhs)
+    | ExpressionOp::FloorDiv(lhs, rhs)
+    | ExpressionOp::BinaryAnd(lhs, rhs)
+    | ExpressionOp::Xor(lhs, rhs)
+    | ExpressionOp::ShiftLeft(lhs, rhs)
+    | ExpressionOp::ShiftRight(lhs, rhs)
+    | ExpressionOp::BinaryOr(lhs, rhs)
+    | ExpressionOp::GreaterThan(lhs, rhs)
+    | ExpressionOp::LessThan(lhs, rhs)
+    | ExpressionOp::LessThanOrEqual(lhs, rhs)
+    | ExpressionOp::GreaterThanOrEqual(lhs, rhs)
+    | ExpressionOp::Equal(lhs, rhs)
+    | ExpressionOp::NotEqual(lhs, rhs)
+    | ExpressionOp::Index(lhs, rhs)
+    | ExpressionOp::Or(lhs, rhs)
+    | ExpressionOp::And(lhs, rhs)
+    | ExpressionOp::Modulo(lhs, rhs)
+    | ExpressionOp::Pow(lhs, rhs) => {
+      walk_expression(lhs, visit);
+      walk_expression(rhs, visit);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    parse_with, render_frame, BlendMode, CompiledProgram, ExecutionContext, FrameIdentifiers,
+    FrameParams, MissingChannelMode, NonFiniteMode, Value,
+  };
+
+  fn lint(source: &str) -> Vec<WarningType> {
+    let mut context = ExecutionContext::default();
+    let program = parse_with(&mut context, source).unwrap();
+    program
+      .lint(&context.export_scope_locations())
+      .into_iter()
+      .map(|warning| warning.warning)
+      .collect()
+  }
+
+  #[test]
+  fn constant_division_under_a_bitwise_operator_is_hinted() {
+    let warnings = lint("r = x & 255 / 256; g = 0; b = 0;");
+    assert!(matches!(
+      warnings[..],
+      [WarningType::TruncatedBitwiseOperand(255.0, 256.0)]
+    ));
+  }
+
+  #[test]
+  fn parenthesized_bitwise_division_is_not_hinted() {
+    assert!(lint("r = (x & 255) / 256; g = 0; b = 0;").is_empty());
+  }
+
+  fn referenced(source: &str) -> Vec<String> {
+    let mut context = ExecutionContext::default();
+    let program = parse_with(&mut context, source).unwrap();
+    program
+      .referenced_identifiers(&context.export_scope_locations())
+      .into_iter()
+      .map(|key| key.name)
+      .collect()
+  }
+
+  #[test]
+  fn referenced_identifiers_are_the_top_level_reads() {
+    assert_eq!(
+      referenced("r = x * foo; g = time; b = 0;"),
+      ["foo", "time", "x"]
+    );
+  }
+
+  #[test]
+  fn referenced_identifiers_skip_writes_and_function_locals() {
+    // `b` is only assigned and `v` only lives in scale()
+    assert_eq!(
+      referenced("function scale(v) { return v * k; } r = scale(y); g = r; b = 0;"),
+      ["r", "y"]
+    );
+    assert_eq!(referenced("r = 1; g = 2; b = 3;"), Vec::<String>::new());
+    // Constants aren't variables the host has to provide
+    assert_eq!(referenced("r = pi * e; g = tau; b = x;"), ["x"]);
+  }
+
+  #[test]
+  fn referenced_identifiers_look_inside_every_statement() {
+    assert_eq!(
+      referenced(
+        "if (a) { r = 0; } else if (c) { r = 1; } else { r = d; }
+        for v in t { g = v; }
+        repeat (i until 3) { b = f; }"
+      ),
+      ["a", "c", "d", "f", "t", "v"]
+    );
+  }
+
+  // The source of the hoisted statements and of the per-pixel ones
+  fn partition(source: &str) -> (String, String) {
+    let mut context = ExecutionContext::default();
+    let program = parse_with(&mut context, source).unwrap();
+    let varying = FrameIdentifiers::register(&mut context).varying();
+    let lut = context.export_scope_locations();
+    let (invariant, per_pixel) = program.partition_invariant(&varying);
+    (invariant.to_source(&lut), per_pixel.to_source(&lut))
+  }
+
+  #[test]
+  fn time_only_assignments_are_hoisted() {
+    assert_eq!(
+      partition("t = time * 2; r = x + t; g = 0; b = 0;"),
+      (
+        "t = time * 2;\ng = 0;\nb = 0;\n".into(),
+        "r = x + t;\n".into()
+      )
+    );
+  }
+
+  #[test]
+  fn nothing_after_an_early_return_is_hoisted() {
+    let (invariant, _) = partition("if (x > 1) { return 0; } t = time * 2; r = t; g = 0; b = 0;");
+    assert_eq!(invariant, "");
+  }
+
+  #[test]
+  fn variables_written_per_pixel_later_are_not_hoisted() {
+    assert_eq!(
+      partition("t = time; r = t; t = x; g = t; b = 0;"),
+      (
+        "b = 0;\n".into(),
+        "t = time;\nr = t;\nt = x;\ng = t;\n".into()
+      )
+    );
+  }
+
+  #[test]
+  fn variables_written_under_a_per_pixel_condition_are_not_hoisted() {
+    let (invariant, per_pixel) = partition("t = 0; if (x > 1) { t = time; } r = t; g = 0; b = 0;");
+    assert_eq!(invariant, "g = 0;\nb = 0;\n");
+    assert_eq!(per_pixel, "t = 0;\nif (x > 1) {\n  t = time;\n}\nr = t;\n");
+    // Fine when the condition is the same for the whole frame
+    let (_, per_pixel) = partition("t = 0; if (time > 1) { t = 5; } r = t; g = 0; b = 0;");
+    assert_eq!(per_pixel, "");
+  }
+
+  fn frame(identifiers: FrameIdentifiers, time: f32) -> FrameParams<'static> {
+    FrameParams {
+      width: 7,
+      height: 5,
+      time,
+      random: 0.0,
+      identifiers,
+      variables: &[],
+      invariant: None,
+      clear_color: [1, 2, 3, 4],
+      blend: BlendMode::Replace,
+      non_finite: NonFiniteMode::Error,
+      missing_channel: MissingChannelMode::Error,
+    }
+  }
+
+  #[test]
+  fn partitioned_renders_match_unpartitioned_ones() {
+    for source in [
+      "t = time * 3; s = sin(t); r = x * s + t; g = y & 255; b = t % 256;",
+      "function k(v, t) { return v * t; } c = k(2, time); r = c + x; g = c; b = u * 255;",
+      "if (x == 2) { return 0; } t = time; r = t; g = x; b = y;",
+      "acc = 0; for v in [1, 2, time] { acc += v; } r = acc + x; g = 0; b = 0;",
+      "t = 1; if (y > 1) { t = time; } r = t; g = 0; b = 0;",
+      "t = time; r = t; t = x; g = t; b = t * 2;",
+    ] {
+      for time in [0.0, 1.5, 3.0] {
+        let mut context = ExecutionContext::default();
+        let program = parse_with(&mut context, source).unwrap();
+        let identifiers = FrameIdentifiers::register(&mut context);
+        let mut whole = [0; 7 * 5 * 4];
+        render_frame(
+          &mut context,
+          &program,
+          &frame(identifiers, time),
+          &mut whole,
+        )
+        .unwrap();
+
+        let mut context = ExecutionContext::default();
+        let program = parse_with(&mut context, source).unwrap();
+        let mut compiled = CompiledProgram::new(context, program);
+        let mut partitioned = [0; 7 * 5 * 4];
+        let params = frame(compiled.identifiers(), time);
+        compiled.render(params, &mut partitioned).unwrap();
+        assert_eq!(whole, partitioned, "{source} at {time}");
+      }
+    }
+  }
+
+  fn unused(source: &str) -> Vec<String> {
+    lint(source)
+      .into_iter()
+      .filter_map(|warning| match warning {
+        WarningType::UnusedVariable(name) => Some(name),
+        _ => None,
+      })
+      .collect()
+  }
+
+  #[test]
+  fn variables_written_but_never_read_are_hinted() {
+    assert_eq!(unused("rr = x * 2; r = 0; g = 0; b = 0;"), ["rr"]);
+    // Only hinted once, however many times it's written
+    assert_eq!(
+      unused("t = 1; if (x) { t = 2; } for v in [1] { t = v; } r = 0; g = 0; b = 0;"),
+      ["t"]
+    );
+    assert_eq!(
+      unused("function f(n) { unused = n; return n; } r = f(1); g = 0; b = 0;"),
+      ["unused"]
+    );
+  }
+
+  #[test]
+  fn unused_variable_hints_point_at_the_first_value_written() {
+    let mut context = ExecutionContext::default();
+    let program = parse_with(&mut context, "r = 0; g = 0; b = 0;\nrr = 12;\nrr = 1;").unwrap();
+    let warnings = program.lint(&context.export_scope_locations());
+    let [warning] = &warnings[..] else {
+      panic!("{warnings:?}");
+    };
+    let location = &warning.location;
+    assert_eq!(
+      (
+        location.start_line,
+        location.start_column,
+        location.end_column
+      ),
+      (2, 6, 8)
+    );
+    assert_eq!(
+      warning.warning.to_string(),
+      "Hint: rr is assigned but never read"
+    );
+  }
+
+  #[test]
+  fn variables_that_are_read_are_not_hinted() {
+    assert!(unused("t = x; r = t; g = 0; b = 0;").is_empty());
+    // Read before it's written still counts, and so does a read in a function
+    assert!(unused("r = 0; for i in [1, 2] { r = r + k; k = i; } g = 0; b = 0;").is_empty());
+    assert!(unused("function f(n) { m = n; return m; } r = f(1); g = 0; b = 0;").is_empty());
+  }
+
+  #[test]
+  fn host_owned_variables_are_never_hinted() {
+    assert!(unused(
+      "r = 1; g = 2; b = 3; a = 4; x = 5; y = 6; u = 7; v = 8; aspect = 9; time = 10;"
+    )
+    .is_empty());
+    // Only at the top level, inside a function they're ordinary locals
+    assert_eq!(
+      unused("function f(n) { r = n; return n; } r = f(1); g = 0; b = 0;"),
+      ["r"]
+    );
+  }
+
+  #[test]
+  fn spans_cover_every_expression_in_source_order() {
+    let mut context = ExecutionContext::default();
+    context.register_function("label", 1, |_| Ok(Value::number(0.0)));
+    let program = parse_with(
+      &mut context,
+      "function f(v) { return v; }
+t = [1, x];
+r = f(t[0]) + sin(2);
+g = label(\"hi\"); b = 0;",
+    )
+    .unwrap();
+    let spans = program
+      .spans()
+      .into_iter()
+      .map(|(location, kind)| {
+        (
+          location.start_line,
+          location.start_column,
+          location.end_line,
+          location.end_column,
+          kind,
+        )
+      })
+      .collect::<Vec<_>>();
+    assert_eq!(
+      spans,
+      [
+        (1, 24, 1, 25, SpanKind::Variable),
+        (2, 5, 2, 11, SpanKind::Tuple),
+        (2, 6, 2, 7, SpanKind::Number),
+        (2, 9, 2, 10, SpanKind::Variable),
+        (3, 5, 3, 12, SpanKind::Function),
+        (3, 7, 3, 8, SpanKind::Variable),
+        // Indexing runs on to the closing bracket
+        (3, 8, 3, 11, SpanKind::Operator),
+        (3, 9, 3, 10, SpanKind::Number),
+        (3, 13, 3, 14, SpanKind::Operator),
+        (3, 15, 3, 21, SpanKind::Builtin),
+        (3, 19, 3, 20, SpanKind::Number),
+        (4, 5, 4, 16, SpanKind::HostFunction),
+        (4, 11, 4, 15, SpanKind::String),
+        (4, 22, 4, 23, SpanKind::Number),
+      ]
+    );
+  }
+}
@@ -1,34 +1,100 @@
 #![feature(try_trait_v2)]
+#![cfg_attr(feature = "simd", feature(portable_simd))]
+#![cfg_attr(not(feature = "std"), no_std)]
 
-use bimap::BiHashMap;
+#[cfg(not(any(feature = "std", feature = "alloc")))]
+compile_error!("anarchy_core needs either the std or the alloc feature");
+
+extern crate alloc;
+
+mod analysis;
+mod math;
+mod optimize;
+mod render;
+#[cfg(feature = "simd")]
+mod simd;
+mod source;
+mod typecheck;
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::rc::Rc;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+pub use analysis::{SpanKind, Warning, WarningType};
+use bimap::BiBTreeMap;
+use core::cell::RefCell;
+use core::convert::Infallible;
+use core::fmt;
+use core::iter::zip;
+use core::ops::{ControlFlow, FromResidual, RangeInclusive, Try};
+use core::sync::atomic::{AtomicUsize, Ordering};
 use lazy_static::lazy_static;
+use math::Float;
 pub use pest;
 use pest::iterators::{Pair, Pairs};
 use pest::pratt_parser::{Assoc, Op, PrattParser};
 use pest::Parser;
 use pest_derive::Parser;
-use std::collections::HashMap;
-use std::convert::Infallible;
-use std::fmt;
-use std::iter::zip;
-use std::ops::{ControlFlow, FromResidual, Try};
-use std::rc::Rc;
+#[cfg(feature = "rayon")]
+pub use render::render_frame_parallel;
+pub use render::{
+  color_channel, render_frame, render_frame_rows, BlendMode, CompiledProgram, FrameIdentifiers,
+  FrameParams, MissingChannelMode, NonFiniteMode,
+};
+#[cfg(feature = "simd")]
+pub use simd::render_frame_simd;
+use smallvec::{smallvec, SmallVec};
+#[cfg(feature = "std")]
 use std::sync::Mutex;
 
 #[derive(Parser)]
 #[grammar = "anarchy.pest"] // relative to src
 struct AnarchyParser;
 
+// Most tuples are colors or coordinates, which fit without a second allocation
+pub type Tuple = SmallVec<[Value; 4]>;
+// A tuple of numbers, see Expression::evaluate_vector
+type Vector = SmallVec<[f32; 4]>;
+// Evaluated arguments to a user-defined or host function. Calls happen per
+// pixel, so the usual handful of arguments shouldn't need an allocation
+type Arguments = SmallVec<[Value; 4]>;
+
 #[derive(Clone, Debug)]
 pub enum Value {
   Number(f32),
-  Tuple(Rc<Vec<Value>>),
+  Tuple(Rc<Tuple>),
+  // Only good for passing to host functions, nothing built in takes one
+  Str(Rc<str>),
 }
 
-#[derive(Clone, Debug)]
+// Which part of `tuple[index]` or `tuple[start:end]` was negative,
+// fractional or past the end of the tuple
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TupleAccess {
+  Index,
+  SliceStart,
+  SliceEnd,
+}
+
+impl fmt::Display for TupleAccess {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      TupleAccess::Index => write!(f, "Index"),
+      TupleAccess::SliceStart => write!(f, "Slice start"),
+      TupleAccess::SliceEnd => write!(f, "Slice end"),
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ValueType {
   Number,
   Tuple,
+  Str,
 }
 
 impl fmt::Display for ValueType {
@@ -50,6 +116,70 @@ impl fmt::Display for Value {
           .collect::<Vec<String>>()
           .join(", ")
       ),
+      Value::Str(string) => write!(f, "Str({string:?})"),
+    }
+  }
+}
+
+impl Value {
+  /// ```
+  /// use anarchy_core::Value;
+  /// assert_eq!(Value::number(0.5).as_number(), Some(0.5));
+  /// ```
+  pub fn number(number: f32) -> Value {
+    Value::Number(number)
+  }
+
+  /// ```
+  /// use anarchy_core::Value;
+  /// let color = Value::tuple(vec![Value::number(255.0), Value::number(0.0)]);
+  /// assert_eq!(color.as_tuple().map(|tuple| tuple.len()), Some(2));
+  /// ```
+  pub fn tuple(values: Vec<Value>) -> Value {
+    Value::Tuple(Rc::new(values.into_iter().collect()))
+  }
+
+  /// The number, or None for anything else
+  ///
+  /// ```
+  /// use anarchy_core::Value;
+  /// assert_eq!(Value::number(3.0).as_number(), Some(3.0));
+  /// assert_eq!(Value::tuple(vec![]).as_number(), None);
+  /// ```
+  pub fn as_number(&self) -> Option<f32> {
+    match self {
+      Value::Number(number) => Some(*number),
+      Value::Tuple(_) | Value::Str(_) => None,
+    }
+  }
+
+  /// The tuple's values, or None for anything else
+  ///
+  /// ```
+  /// use anarchy_core::Value;
+  /// let tuple = Value::tuple(vec![Value::number(1.0), Value::number(2.0)]);
+  /// let values = tuple.as_tuple().unwrap();
+  /// assert_eq!(values[1].as_number(), Some(2.0));
+  /// assert!(Value::number(1.0).as_tuple().is_none());
+  /// ```
+  pub fn as_tuple(&self) -> Option<&Rc<Tuple>> {
+    match self {
+      Value::Tuple(tuple) => Some(tuple),
+      Value::Number(_) | Value::Str(_) => None,
+    }
+  }
+
+  /// The string, or None for anything else
+  ///
+  /// ```
+  /// use anarchy_core::Value;
+  /// assert_eq!(Value::Str("hi".into()).as_str(), Some("hi"));
+  /// assert_eq!(Value::number(1.0).as_str(), None);
+  /// ```
+  pub fn as_str(&self) -> Option<&str> {
+    match self {
+      Value::Str(string) => Some(string),
+      Value::Number(_) | Value::Tuple(_) => None,
     }
   }
 }
@@ -73,6 +203,54 @@ impl fmt::Display for LanguageError {
   }
 }
 
+impl LanguageError {
+  // The error along with the lines of `source` it points at, underlined the
+  // way pest underlines parse errors. `source` must be the program's code
+  pub fn render(&self, source: &str) -> String {
+    let Some(location) = &self.location else {
+      return self.to_string();
+    };
+    let gutter = " ".repeat(location.end_line.to_string().len());
+    let mut rendered = vec![
+      self.to_string(),
+      format!(
+        "{gutter}--> {}:{}",
+        location.start_line, location.start_column
+      ),
+      format!("{gutter} |"),
+    ];
+    let lines = source
+      .lines()
+      .enumerate()
+      .skip(location.start_line - 1)
+      .take(location.end_line + 1 - location.start_line);
+    for (index, line) in lines {
+      let number = index + 1;
+      let start = if number == location.start_line {
+        location.start_column - 1
+      } else {
+        // Continuing from the line above, so skip the indentation
+        line.chars().take_while(|c| c.is_whitespace()).count()
+      };
+      let end = if number == location.end_line {
+        location.end_column - 1
+      } else {
+        line.chars().count()
+      };
+      // Keep tabs so the underline lines up however wide they are
+      let padding = line
+        .chars()
+        .take(start)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect::<String>();
+      let underline = "^".repeat(end.saturating_sub(start).max(1));
+      rendered.push(format!("{number:>width$} | {line}", width = gutter.len()));
+      rendered.push(format!("{gutter} | {padding}{underline}"));
+    }
+    rendered.join("\n")
+  }
+}
+
 impl fmt::Display for LanguageErrorType {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     match self {
@@ -84,14 +262,80 @@ impl fmt::Display for LanguageErrorType {
         f,
         "ReferenceError: Couldn't find identifier named {identifier}",
       ),
-      LanguageErrorType::Range(index, length) => write!(
+      LanguageErrorType::Range(access, index, _) if index.floor() != *index => {
+        write!(f, "RangeError: {access} {index} is not a whole number")
+      }
+      LanguageErrorType::Range(access, index, length) => write!(
         f,
-        "RangeError: Index {index} out of bounds for tuple of length {length}"
+        "RangeError: {access} {index} out of bounds for tuple of length {length}"
       ),
       LanguageErrorType::ArgumentCountMismatch(found, expected) => write!(
         f,
         "ArgumentCountMismatch: Function takes {expected} arguments, but you used: {found}"
       ),
+      LanguageErrorType::ImplicitOutputDisabled => write!(
+        f,
+        "SyntaxError: A trailing color expression needs implicit color output to be enabled"
+      ),
+      LanguageErrorType::ImplicitOutputShape(found) => write!(
+        f,
+        "OutputError: A trailing color expression needs 3 values, but you used: {found}"
+      ),
+      LanguageErrorType::StaticType(expected_type, found_type) => write!(
+        f,
+        "TypeError: Expected value of type {expected_type}, but this is always a {found_type}",
+      ),
+      LanguageErrorType::RecursionLimit(limit) => write!(
+        f,
+        "RecursionError: Function calls nested deeper than the limit of {limit}, counting the expressions around them"
+      ),
+      LanguageErrorType::AssertionFailed => write!(f, "AssertionError: Assertion failed"),
+      LanguageErrorType::LengthMismatch(lhs, rhs) => write!(
+        f,
+        "LengthError: Can't combine tuples of length {lhs} and {rhs} elementwise"
+      ),
+      LanguageErrorType::NonFiniteOutput(x, y, value) => write!(
+        f,
+        "OutputError: Pixel ({x}, {y}) has a color channel of {value}, which isn't a finite number"
+      ),
+      LanguageErrorType::UnsetChannel(x, y, channel) => write!(
+        f,
+        "OutputError: Pixel ({x}, {y}) finished without setting {channel}"
+      ),
+      LanguageErrorType::OutputBuffer(needed, found) => write!(
+        f,
+        "OutputError: The frame needs a buffer of {needed} bytes, but got one of {found}"
+      ),
+      LanguageErrorType::RangeStep(step) => {
+        write!(
+          f,
+          "RangeError: range() needs a positive step, but you used: {step}"
+        )
+      }
+      LanguageErrorType::RangeLength(limit) => write!(
+        f,
+        "RangeError: range() can't make tuples longer than {limit} elements"
+      ),
+      LanguageErrorType::EmptyIndex => {
+        write!(f, "RangeError: Can't pick an element out of an empty tuple")
+      }
+      LanguageErrorType::NormalizeZero => {
+        write!(
+          f,
+          "RangeError: Can't normalize a vector with a magnitude of 0"
+        )
+      }
+      LanguageErrorType::OutsideLoop(keyword) => {
+        write!(f, "SyntaxError: {keyword} can only be used inside a loop")
+      }
+      LanguageErrorType::ReservedName(name) => write!(
+        f,
+        "SyntaxError: {name} is a builtin function or constant, so it can't be used as a name"
+      ),
+      LanguageErrorType::DanglingElse => write!(
+        f,
+        "SyntaxError: else needs to be followed by a {{ block }} or another if"
+      ),
     }
   }
 }
@@ -135,11 +379,11 @@ impl From<bool> for Value {
   }
 }
 
-impl<'a, 'b> TryFrom<&'b TrackedValue<'a>> for Rc<Vec<Value>> {
+impl<'a, 'b> TryFrom<&'b TrackedValue<'a>> for Rc<Tuple> {
   type Error = LanguageError;
   fn try_from(
     TrackedValue(value, location): &'b TrackedValue<'a>,
-  ) -> Result<Rc<Vec<Value>>, LanguageError> {
+  ) -> Result<Rc<Tuple>, LanguageError> {
     match value {
       Value::Tuple(tuple) => Ok(Rc::clone(tuple)),
       value => Err(LanguageError {
@@ -150,8 +394,23 @@ impl<'a, 'b> TryFrom<&'b TrackedValue<'a>> for Rc<Vec<Value>> {
   }
 }
 
+// Copies the elements out, for code written before tuples were a Tuple
+impl<'a, 'b> TryFrom<&'b TrackedValue<'a>> for Rc<Vec<Value>> {
+  type Error = LanguageError;
+  fn try_from(tracked_value: &'b TrackedValue<'a>) -> Result<Rc<Vec<Value>>, LanguageError> {
+    let tuple = <Rc<Tuple>>::try_from(tracked_value)?;
+    Ok(Rc::new(tuple.to_vec()))
+  }
+}
+
 impl From<Rc<Vec<Value>>> for Value {
   fn from(tuple: Rc<Vec<Value>>) -> Value {
+    Value::tuple(Rc::unwrap_or_clone(tuple))
+  }
+}
+
+impl From<Rc<Tuple>> for Value {
+  fn from(tuple: Rc<Tuple>) -> Value {
     Value::Tuple(tuple)
   }
 }
@@ -166,8 +425,35 @@ pub struct LanguageError {
 pub enum LanguageErrorType {
   Type(ValueType, Value),
   Reference(String),
-  Range(usize, usize),
+  // How the tuple was being accessed, the index as written and the tuple's
+  // length
+  Range(TupleAccess, f32, usize),
   ArgumentCountMismatch(usize, usize),
+  ImplicitOutputDisabled,
+  ImplicitOutputShape(usize),
+  OutsideLoop(&'static str),
+  // `else` without a block or another if after it
+  DanglingElse,
+  // Assigning to the name of a builtin function or constant
+  ReservedName(String),
+  RecursionLimit(usize),
+  AssertionFailed,
+  // Elementwise arithmetic on tuples of different lengths
+  LengthMismatch(usize, usize),
+  NormalizeZero,
+  // index_wrap() or index_clamp() on a tuple with nothing in it
+  EmptyIndex,
+  RangeStep(f32),
+  // A color channel of pixel (x, y) came out NaN or infinite
+  NonFiniteOutput(usize, usize, f32),
+  // The pixel, and which of r, g or b it never assigned
+  UnsetChannel(usize, usize, char),
+  RangeLength(usize),
+  // The bytes a whole RGBA frame needs, and the bytes the buffer it was
+  // drawn into holds
+  OutputBuffer(usize, usize),
+  // Found by typecheck() without running the program
+  StaticType(ValueType, ValueType),
 }
 
 lazy_static! {
@@ -187,18 +473,21 @@ lazy_static! {
                 | Op::infix(Rule::shift_right, Assoc::Left)
                 | Op::infix(Rule::bor, Assoc::Left))
             .op(Op::infix(Rule::add, Assoc::Left) | Op::infix(Rule::sub, Assoc::Left))
-            .op(Op::infix(Rule::mul, Assoc::Left) | Op::infix(Rule::div, Assoc::Left) | Op::infix(Rule::modulo, Assoc::Left))
-            .op(Op::infix(Rule::pow, Assoc::Left))
+            .op(Op::infix(Rule::mul, Assoc::Left) | Op::infix(Rule::div, Assoc::Left) | Op::infix(Rule::floor_div, Assoc::Left) | Op::infix(Rule::modulo, Assoc::Left))
+            .op(Op::infix(Rule::pow, Assoc::Right))
             .op(Op::prefix(Rule::invert))
             .op(Op::prefix(Rule::neg))
-            .op(Op::postfix(Rule::index))
+            .op(Op::postfix(Rule::index) | Op::postfix(Rule::slice))
     };
 }
 
 #[derive(Debug, Clone)]
 struct Function {
-  // name: String,
+  name: String,
   arguments: Vec<Identifier>,
+  // Every variable in the function's scope, arguments included. These get
+  // fresh storage for each call
+  locals: Vec<Identifier>,
   contents: Vec<Statement>,
 }
 
@@ -212,6 +501,15 @@ struct FunctionPrototype {
 pub struct ParsedLanguage {
   top_level: Vec<Statement>,
   functions: Vec<Function>,
+  // Trailing `r, g, b` expression, see ParseOptions::implicit_color_output
+  output: Option<Expression>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ParseOptions {
+  // Allow the program to end with a bare `r, g, b` (or `[r, g, b]`)
+  // expression that supplies the color instead of assigning r/g/b
+  pub implicit_color_output: bool,
 }
 
 impl From<LanguageError> for ParseError {
@@ -220,9 +518,135 @@ impl From<LanguageError> for ParseError {
   }
 }
 
+// Parses `code` with the default options, registering its variables with
+// `execution_context`
+pub fn parse_with(
+  execution_context: &mut ExecutionContext,
+  code: &str,
+) -> Result<ParsedLanguage, ParseError> {
+  ParseOptions::default().parse(execution_context, code)
+}
+
+impl ParseOptions {
+  pub fn parse(
+    &self,
+    execution_context: &mut ExecutionContext,
+    code: &str,
+  ) -> Result<ParsedLanguage, ParseError> {
+    parse_program(execution_context, code, self, &mut ParseState::default())
+  }
+
+  // Like parse, but keeps going after errors that don't stop the rest of the
+  // program from making sense (unknown functions, argument count mismatches,
+  // ...) so they can all be reported at once. Syntax errors still stop
+  // parsing
+  pub fn parse_collecting(
+    &self,
+    execution_context: &mut ExecutionContext,
+    code: &str,
+  ) -> Result<ParsedLanguage, Vec<ParseError>> {
+    let mut state = ParseState {
+      errors: Some(RefCell::new(Vec::new())),
+      ..Default::default()
+    };
+    let result = parse_program(execution_context, code, self, &mut state);
+    let mut errors = state
+      .errors
+      .unwrap()
+      .into_inner()
+      .into_iter()
+      .map(ParseError::from)
+      .collect::<Vec<ParseError>>();
+    match result {
+      Ok(parsed_language) if errors.is_empty() => Ok(parsed_language),
+      Ok(_) => Err(errors),
+      Err(err) => {
+        errors.push(err);
+        Err(errors)
+      }
+    }
+  }
+}
+
+// Parses and evaluates a lone expression like `sin(x) * 2` against
+// `execution_context`, registering any variables it mentions. There's no
+// program around it, so user-defined functions aren't available
+pub fn eval_expression(
+  execution_context: &mut ExecutionContext,
+  code: &str,
+) -> Result<Value, ParseError> {
+  let expression = AnarchyParser::parse(Rule::single_expression, code)
+    .map_err(|err| ParseError::PestError(Box::new(err)))?
+    .next()
+    .unwrap()
+    .into_inner()
+    .next()
+    .unwrap();
+  let expression = parse_expression(
+    execution_context,
+    "".to_string(),
+    expression.into_inner(),
+    &ParseState::default(),
+  )?;
+  Ok(expression.evaluate(execution_context, &Vec::new())?)
+}
+
+#[cfg(feature = "std")]
+#[deprecated(note = "use parse_with, which borrows the context instead")]
 pub fn parse(
   execution_context: Rc<Mutex<ExecutionContext>>,
   code: &str,
+) -> Result<ParsedLanguage, ParseError> {
+  parse_with(&mut execution_context.lock().unwrap(), code)
+}
+
+#[cfg(feature = "std")]
+#[deprecated(note = "use ParseOptions::parse, which borrows the context instead")]
+pub fn parse_with_options(
+  execution_context: Rc<Mutex<ExecutionContext>>,
+  code: &str,
+  options: &ParseOptions,
+) -> Result<ParsedLanguage, ParseError> {
+  options.parse(&mut execution_context.lock().unwrap(), code)
+}
+
+#[cfg(feature = "std")]
+#[deprecated(note = "use ParseOptions::parse_collecting, which borrows the context instead")]
+pub fn parse_collecting(
+  execution_context: Rc<Mutex<ExecutionContext>>,
+  code: &str,
+  options: &ParseOptions,
+) -> Result<ParsedLanguage, Vec<ParseError>> {
+  options.parse_collecting(&mut execution_context.lock().unwrap(), code)
+}
+
+#[derive(Debug, Default)]
+struct ParseState {
+  functions: BTreeMap<String, FunctionPrototype>,
+  // Recoverable errors seen so far, if we're collecting them instead of
+  // failing on the first one
+  errors: Option<RefCell<Vec<LanguageError>>>,
+  // Constant tuples seen so far, by their contents, so repeats share an id
+  constant_tuples: RefCell<BTreeMap<String, usize>>,
+}
+
+impl ParseState {
+  fn recover(&self, error: LanguageError) -> Result<(), LanguageError> {
+    match &self.errors {
+      Some(errors) => {
+        errors.borrow_mut().push(error);
+        Ok(())
+      }
+      None => Err(error),
+    }
+  }
+}
+
+fn parse_program(
+  execution_context: &mut ExecutionContext,
+  code: &str,
+  options: &ParseOptions,
+  state: &mut ParseState,
 ) -> Result<ParsedLanguage, ParseError> {
   let mut program = AnarchyParser::parse(Rule::program, code)
     .map_err(|err| ParseError::PestError(Box::new(err)))?
@@ -231,52 +655,104 @@ pub fn parse(
     .into_inner();
   let function_definitions = program.next().unwrap().into_inner();
   let mut functions: Vec<Function> = Vec::new();
-  let mut functions_map = HashMap::new();
   for function_definition in function_definitions {
-    println!("Function Definition: {function_definition:?}");
     let mut function_definition = function_definition.into_inner();
-    let function_name = function_definition.next().unwrap().as_str().to_string();
+    let function_name = function_definition.next().unwrap();
+    check_name(&function_name, state)?;
+    let function_name = function_name.as_str().to_string();
     let arguments = function_definition
       .next()
       .unwrap()
       .into_inner()
       .map(|arg| {
-        execution_context.lock().unwrap().register(VariableKey {
+        check_name(&arg, state)?;
+        Ok(execution_context.register(VariableKey {
           name: arg.as_str().to_string(),
           scope: function_name.to_string(),
-        })
+        }))
       })
-      .collect::<Vec<Identifier>>();
-    let statement_block = function_definition.next().unwrap();
-    let contents = parse_statement_block(
-      execution_context.clone(),
-      function_name.clone(),
-      statement_block.into_inner(),
-      &functions_map,
-    )?;
-    functions_map.insert(
+      .collect::<Result<Vec<Identifier>, LanguageError>>()?;
+    // Registered before the body is parsed so the function can call itself
+    state.functions.insert(
       function_name.clone(),
       FunctionPrototype {
         identifier: functions.len(),
         argument_count: arguments.len(),
       },
     );
+    let statement_block = function_definition.next().unwrap();
+    let contents = parse_statement_block(
+      execution_context,
+      function_name.clone(),
+      statement_block.into_inner(),
+      state,
+      false,
+    )?;
+    let locals = execution_context.scope_identifiers(&function_name);
     functions.push(Function {
-      // name: function_name,
+      name: function_name,
       arguments,
+      locals,
       contents,
     });
   }
   let statement_block = program.next().unwrap();
+  let top_level = parse_statement_block(
+    execution_context,
+    "".to_string(),
+    statement_block.into_inner(),
+    state,
+    false,
+  )?;
+
+  let output = match program.next() {
+    Some(pair) if pair.as_rule() == Rule::output_expression => {
+      let location = Location::from(&pair);
+      if !options.implicit_color_output {
+        state.recover(LanguageError {
+          location: Some(location.clone()),
+          error: LanguageErrorType::ImplicitOutputDisabled,
+        })?;
+      }
+      let mut channels = pair
+        .into_inner()
+        .map(|expression| {
+          parse_expression(
+            execution_context,
+            "".to_string(),
+            expression.into_inner(),
+            state,
+          )
+        })
+        .collect::<Result<Vec<Expression>, LanguageError>>()?;
+      // A single `[r, g, b]` literal works as well as a bare `r, g, b`
+      if let [Expression {
+        op: ExpressionOp::TupleLiteral(tuple) | ExpressionOp::ConstantTuple(_, tuple),
+        ..
+      }] = &mut channels[..]
+      {
+        channels = core::mem::take(tuple);
+      }
+      if channels.len() != 3 {
+        state.recover(LanguageError {
+          location: Some(location),
+          error: LanguageErrorType::ImplicitOutputShape(channels.len()),
+        })?;
+        None
+      } else {
+        Some(Expression {
+          location,
+          op: ExpressionOp::TupleLiteral(channels),
+        })
+      }
+    }
+    _ => None,
+  };
 
   Ok(ParsedLanguage {
-    top_level: parse_statement_block(
-      execution_context,
-      "".to_string(),
-      statement_block.into_inner(),
-      &functions_map,
-    )?,
+    top_level,
     functions,
+    output,
   })
 }
 
@@ -293,9 +769,128 @@ pub fn execute(
   ParsedLanguage {
     top_level: pairs,
     functions,
+    ..
   }: &ParsedLanguage,
 ) -> ScopeFlow {
-  execute_statement_block(context, pairs, functions)
+  match execute_statement_block(context, pairs, functions) {
+    ScopeFlow::Break => escaped_loop("break").into(),
+    ScopeFlow::NextIteration => escaped_loop("continue").into(),
+    flow => flow,
+  }
+}
+
+// Evaluates the program's trailing color expression, if it has one. Hosts
+// call this after execute() and fall back to the r/g/b variables on None.
+pub fn execute_output(
+  context: &mut ExecutionContext,
+  ParsedLanguage {
+    output, functions, ..
+  }: &ParsedLanguage,
+) -> Result<Option<[f32; 3]>, LanguageError> {
+  let Some(Expression {
+    op: ExpressionOp::TupleLiteral(channels),
+    ..
+  }) = output
+  else {
+    return Ok(None);
+  };
+  let mut color = [0.0_f32; 3];
+  for (channel, expression) in zip(color.iter_mut(), channels) {
+    *channel = expression.evaluate_number(context, functions)?;
+  }
+  Ok(Some(color))
+}
+
+// Two programs run back to back, each in its own context so their
+// variables can't clash. The first program's color is handed to the
+// second as its r/g/b before it runs. Each program's color is whatever it
+// returns as [r, g, b], ends with, or assigns to r/g/b, in that order.
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+  first: ParsedLanguage,
+  first_context: ExecutionContext,
+  second: ParsedLanguage,
+  second_context: ExecutionContext,
+  // (first program's output, second program's input)
+  channels: [(Identifier, Identifier); 3],
+}
+
+impl ParsedLanguage {
+  pub fn then(
+    self,
+    mut context: ExecutionContext,
+    next: ParsedLanguage,
+    mut next_context: ExecutionContext,
+  ) -> Pipeline {
+    let channels = ["r", "g", "b"].map(|name| {
+      let key = VariableKey {
+        name: name.to_string(),
+        scope: "".to_string(),
+      };
+      (context.register(key.clone()), next_context.register(key))
+    });
+    Pipeline {
+      first: self,
+      first_context: context,
+      second: next,
+      second_context: next_context,
+      channels,
+    }
+  }
+}
+
+impl Pipeline {
+  pub fn reset(&mut self) {
+    self.first_context.reset();
+    self.second_context.reset();
+  }
+  // Sets a top-level variable (x, y, time...) in both programs
+  pub fn set_runtime(&mut self, identifier: &str, value: Value) {
+    self.first_context.set_runtime(identifier, value.clone());
+    self.second_context.set_runtime(identifier, value);
+  }
+  pub fn execute(&mut self) -> ScopeFlow {
+    let outputs = self.channels.map(|(output, _)| output);
+    let inputs = self.channels.map(|(_, input)| input);
+    run_stage(&mut self.first_context, &self.first, outputs)?;
+    for (output, input) in self.channels {
+      let value = self.first_context.unattributed_get(output)?;
+      self.second_context.set(input, value);
+    }
+    run_stage(&mut self.second_context, &self.second, inputs)?;
+    ScopeFlow::Continue
+  }
+  // The second program's context, which holds the final r/g/b
+  pub fn output(&mut self) -> &mut ExecutionContext {
+    &mut self.second_context
+  }
+}
+
+// Runs one program of a pipeline, leaving its color in `channels` (its
+// r/g/b) whether it returned the color, ended with it or assigned it
+fn run_stage(
+  context: &mut ExecutionContext,
+  program: &ParsedLanguage,
+  channels: [Identifier; 3],
+) -> Result<(), LanguageError> {
+  let color = match execute(context, program).into_result()? {
+    Some(Value::Tuple(values)) if values.len() == 3 => {
+      let mut color = [0.0; 3];
+      for (channel, value) in zip(&mut color, values.iter()) {
+        *channel = UntrackedValue(value.clone()).try_into()?;
+      }
+      Some(color)
+    }
+    // Anything else returned leaves r/g/b as they were assigned
+    Some(_) => None,
+    None => execute_output(context, program)?,
+  };
+  if let Some(color) = color {
+    for (identifier, channel) in zip(channels, color) {
+      context.set(identifier, Value::from(channel));
+    }
+  }
+  Ok(())
 }
 
 fn execute_statement_block(
@@ -322,6 +917,11 @@ impl Statement {
       Statement::Return(expression) => {
         return ScopeFlow::Return(expression.evaluate(context, functions)?);
       }
+      Statement::Call(expression) => {
+        expression.evaluate(context, functions)?;
+      }
+      Statement::Break => return ScopeFlow::Break,
+      Statement::Continue => return ScopeFlow::NextIteration,
       Statement::Repeat(RepeatStatement {
         variable,
         times,
@@ -329,7 +929,27 @@ impl Statement {
       }) => {
         for i in 0_u32..*times {
           context.set(*variable, (i as f32).into());
-          execute_statement_block(context, block, functions)?;
+          match execute_statement_block(context, block, functions) {
+            ScopeFlow::Break => break,
+            ScopeFlow::NextIteration => continue,
+            flow => flow?,
+          }
+        }
+      }
+      Statement::ForEach {
+        binding,
+        iterable,
+        body,
+      } => {
+        let tuple = TrackedValue(iterable.evaluate(context, functions)?, &iterable.location);
+        let elements = <Rc<Tuple>>::try_from(&tuple)?;
+        for element in elements.iter() {
+          context.set(*binding, element.clone());
+          match execute_statement_block(context, body, functions) {
+            ScopeFlow::Break => break,
+            ScopeFlow::NextIteration => continue,
+            flow => flow?,
+          }
         }
       }
     };
@@ -341,6 +961,10 @@ impl Statement {
 pub enum ScopeFlow {
   Error(LanguageError),
   Return(Value),
+  // Leave the nearest loop
+  Break,
+  // Skip to the nearest loop's next iteration
+  NextIteration,
   Continue,
 }
 
@@ -369,6 +993,29 @@ impl From<ScopeFlow> for Result<(), LanguageError> {
   }
 }
 
+impl ScopeFlow {
+  // The value a program or function returned, if any. A break or continue
+  // that got out of every loop becomes an OutsideLoop error
+  pub fn into_result(self) -> Result<Option<Value>, LanguageError> {
+    match self {
+      ScopeFlow::Error(err) => Err(err),
+      ScopeFlow::Return(value) => Ok(Some(value)),
+      ScopeFlow::Continue => Ok(None),
+      ScopeFlow::Break => Err(escaped_loop("break")),
+      ScopeFlow::NextIteration => Err(escaped_loop("continue")),
+    }
+  }
+}
+
+// Parsing rejects break and continue outside of loops, so this only happens
+// to programs put together some other way
+fn escaped_loop(keyword: &'static str) -> LanguageError {
+  LanguageError {
+    error: LanguageErrorType::OutsideLoop(keyword),
+    location: None,
+  }
+}
+
 impl From<LanguageError> for ScopeFlow {
   fn from(error: LanguageError) -> Self {
     ScopeFlow::Error(error)
@@ -393,10 +1040,7 @@ impl Try for ScopeFlow {
 
 impl IfStatement {
   fn execute(&self, context: &mut ExecutionContext, functions: &Vec<Function>) -> ScopeFlow {
-    let condition = f32::try_from(TrackedValue(
-      self.condition.evaluate(context, functions)?,
-      &self.condition.location,
-    ))?;
+    let condition = self.condition.evaluate_number(context, functions)?;
     if condition != 0.0 {
       execute_statement_block(context, &self.if_branch, functions)
     } else {
@@ -411,308 +1055,920 @@ impl IfStatement {
   }
 }
 
-#[derive(Debug, Clone)]
-enum FunctionIdentifier {
+// Builtins that take one number and give back a number
+#[derive(Debug, Clone, Copy)]
+enum MathFunction {
   Sin,
   Cos,
   Tan,
+  Asin,
+  Acos,
+  Atan,
   Abs,
   Sqrt,
+  Floor,
+  Ceil,
+  Round,
+}
+
+impl MathFunction {
+  fn apply(self, value: f32) -> f32 {
+    match self {
+      MathFunction::Sin => Float::sin(value),
+      MathFunction::Cos => Float::cos(value),
+      MathFunction::Tan => Float::tan(value),
+      MathFunction::Asin => Float::asin(value),
+      MathFunction::Acos => Float::acos(value),
+      MathFunction::Atan => Float::atan(value),
+      MathFunction::Abs => value.abs(),
+      MathFunction::Sqrt => value.sqrt(),
+      MathFunction::Floor => value.floor(),
+      MathFunction::Ceil => value.ceil(),
+      MathFunction::Round => value.round(),
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+enum FunctionIdentifier {
+  Math(MathFunction),
+  // Natural log, or log(value, base)
   Log,
-  Acos,
-  Asin,
-  Atan,
+  Atan2,
+  // Remainder that's never negative, unlike `%` which takes the sign of the
+  // dividend, so `mod(-1, width)` wraps around to `width - 1`
+  Mod,
+  Min,
+  Max,
+  Clamp,
+  Step,
+  Smoothstep,
+  // Hue in degrees, saturation and value from 0 to 1, to an [r, g, b] tuple
+  // from 0 to 255
+  Hsv,
   Len,
+  // range(end), range(start, end) or range(start, end, step), counting up
+  Range,
+  Sum,
+  Product,
+  Reverse,
+  // `index_wrap(tuple, i)` wraps `i` around the length, so -1 is the last
+  // element, and `index_clamp(tuple, i)` sticks to the first or last one.
+  // Neither is ever out of bounds, only an empty tuple has nothing to pick
+  IndexWrap,
+  IndexClamp,
+  Dot,
+  Magnitude,
+  // Errors on a zero vector rather than dividing by 0
+  Normalize,
+  // Also spelled lerp. Works elementwise like + and *
+  Mix,
+  Quantize,
+  Dither,
+  Random,
+  // Smooth 2D value noise in [0, 1), with a new value at every integer point
+  Noise,
+  // Hands its argument to the print sink, then returns it
+  Print,
+  // Errors if its first argument is 0. The second, a message, is ignored
+  Assert,
+  // `map(function, tuple)` calls a one-argument user-defined function on each
+  // element. The function is named in the source but isn't an argument here
+  Map(Identifier),
+  // `reduce(function, tuple, initial)`, where the two-argument function is
+  // called with the result so far and each element in turn
+  Reduce(Identifier),
   UserDefined(Identifier),
+  Host(Identifier),
 }
 
-impl Expression {
-  fn evaluate(
-    &self,
-    context: &mut ExecutionContext,
-    functions: &Vec<Function>,
-  ) -> Result<Value, LanguageError> {
-    Ok(match &self.op {
-      ExpressionOp::Reference(identifier) => context.get(*identifier, &self.location)?,
-      ExpressionOp::FunctionCall(function, arguments) => match function {
-        FunctionIdentifier::Len => {
-          let tracked_value = TrackedValue(
-            arguments[0].evaluate(context, functions)?,
-            &arguments[0].location,
-          );
-          let value: Rc<Vec<Value>> = <Rc<Vec<Value>>>::try_from(&tracked_value)?;
-          Value::from(value.len() as f32)
-        }
-        FunctionIdentifier::UserDefined(identifier) => {
-          let function = &functions[*identifier];
-          for (argument_id, arg_expression) in zip(function.arguments.iter(), arguments.iter()) {
-            let arg_value = arg_expression.evaluate(context, functions)?;
-            context.set(*argument_id, arg_value);
-          }
-          match execute_statement_block(context, &function.contents, functions) {
-            ScopeFlow::Continue => Ok(Value::Number(0.0_f32)),
-            ScopeFlow::Return(value) => Ok(value),
-            ScopeFlow::Error(err) => Err(err),
-          }
-          .unwrap_or(Value::Number(0.0_f32))
-        }
-        function => {
-          let value = f32::try_from(TrackedValue(
-            arguments[0].evaluate(context, functions)?,
-            &arguments[0].location,
-          ))?;
-          Value::from(match function {
-            FunctionIdentifier::Sin => value.sin(),
-            FunctionIdentifier::Cos => value.cos(),
-            FunctionIdentifier::Tan => value.tan(),
-            FunctionIdentifier::Asin => value.asin(),
-            FunctionIdentifier::Acos => value.acos(),
-            FunctionIdentifier::Atan => value.atan(),
-            FunctionIdentifier::Abs => value.abs(),
-            FunctionIdentifier::Sqrt => value.sqrt(),
-            FunctionIdentifier::Log => value.log(2.0),
-            FunctionIdentifier::Len => unreachable!(),
-            FunctionIdentifier::UserDefined(_) => unreachable!(),
-          })
+impl FunctionIdentifier {
+  // Range of argument counts a builtin accepts
+  fn argument_count(&self) -> RangeInclusive<usize> {
+    match self {
+      FunctionIdentifier::Min | FunctionIdentifier::Max | FunctionIdentifier::Random => {
+        1..=usize::MAX
+      }
+      FunctionIdentifier::Log | FunctionIdentifier::Assert => 1..=2,
+      FunctionIdentifier::Range => 1..=3,
+      FunctionIdentifier::Atan2
+      | FunctionIdentifier::Mod
+      | FunctionIdentifier::Dot
+      | FunctionIdentifier::IndexWrap
+      | FunctionIdentifier::IndexClamp
+      | FunctionIdentifier::Quantize
+      | FunctionIdentifier::Step
+      | FunctionIdentifier::Noise => 2..=2,
+      FunctionIdentifier::Clamp
+      | FunctionIdentifier::Mix
+      | FunctionIdentifier::Smoothstep
+      | FunctionIdentifier::Hsv => 3..=3,
+      FunctionIdentifier::Dither => 4..=4,
+      FunctionIdentifier::UserDefined(_) | FunctionIdentifier::Host(_) => unreachable!(),
+      _ => 1..=1,
+    }
+  }
+}
+
+// Longest tuple range() will make, so a typo can't eat all the memory
+const MAX_RANGE_LENGTH: usize = 1 << 20;
+
+// Ordered dither thresholds, indexed by [y % 4][x % 4]
+const BAYER_MATRIX: [[f32; 4]; 4] = [
+  [0.0, 8.0, 2.0, 10.0],
+  [12.0, 4.0, 14.0, 6.0],
+  [3.0, 11.0, 1.0, 9.0],
+  [15.0, 7.0, 13.0, 5.0],
+];
+
+// Hermite interpolation from 0 at `low` to 1 at `high`
+fn smoothstep(low: f32, high: f32, value: f32) -> f32 {
+  // Would divide by 0, so make it a hard edge like step()
+  if low == high {
+    return if value >= low { 1.0 } else { 0.0 };
+  }
+  let t = ((value - low) / (high - low)).clamp(0.0, 1.0);
+  t * t * (3.0 - 2.0 * t)
+}
+
+fn hsv(hue: f32, saturation: f32, value: f32) -> [f32; 3] {
+  // Which sixth of the color wheel the hue is in, wrapping around
+  let sector = hue.rem_euclid(360.0) / 60.0;
+  let chroma = value * saturation;
+  let second = chroma * (1.0 - (sector % 2.0 - 1.0).abs());
+  let [red, green, blue] = match sector as u32 {
+    0 => [chroma, second, 0.0],
+    1 => [second, chroma, 0.0],
+    2 => [0.0, chroma, second],
+    3 => [0.0, second, chroma],
+    4 => [second, 0.0, chroma],
+    _ => [chroma, 0.0, second],
+  };
+  let lightest = value - chroma;
+  [red, green, blue].map(|channel| (channel + lightest) * 255.0)
+}
+
+fn magnitude(vector: &[f32]) -> f32 {
+  vector
+    .iter()
+    .fold(0.0, |sum, value| sum + value * value)
+    .sqrt()
+}
+
+// Snaps a 0..255 value to the nearest of `levels` evenly spaced values
+fn quantize(value: f32, levels: f32) -> f32 {
+  let step = 255.0 / (levels.floor().max(2.0) - 1.0);
+  ((value / step).round() * step).clamp(0.0, 255.0)
+}
+
+fn dither(value: f32, x: f32, y: f32, levels: f32) -> f32 {
+  let step = 255.0 / (levels.floor().max(2.0) - 1.0);
+  let threshold =
+    BAYER_MATRIX[(y as i64).rem_euclid(4) as usize][(x as i64).rem_euclid(4) as usize];
+  // Shift by -0.5..0.5 of a band before snapping
+  quantize(value + ((threshold + 0.5) / 16.0 - 0.5) * step, levels)
+}
+
+// lowbias32 from https://nullprogram.com/blog/2018/07/31/
+fn hash(mut value: u32) -> u32 {
+  value ^= value >> 16;
+  value = value.wrapping_mul(0x7feb352d);
+  value ^= value >> 15;
+  value = value.wrapping_mul(0x846ca68b);
+  value ^ (value >> 16)
+}
+
+// Uniform in [0, 1) and identical everywhere for the same seeds, since it
+// only looks at their bit patterns
+fn random(seeds: &[f32]) -> f32 {
+  let hashed = seeds.iter().fold(0x9e3779b9, |hashed, seed| {
+    // -0 and 0 should give the same value
+    hash(hashed ^ (seed + 0.0).to_bits())
+  });
+  (hashed >> 8) as f32 / (1 << 24) as f32
+}
+
+// Blends random() values picked for the corners of the surrounding grid
+// cell. Only uses operations that round the same way on every platform
+fn noise(x: f32, y: f32) -> f32 {
+  let (left, top) = (x.floor(), y.floor());
+  let [top_left, top_right, bottom_left, bottom_right] =
+    [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0), (1.0, 1.0)]
+      .map(|(right, down)| random(&[left + right, top + down]));
+  // Eases in and out so the cell edges don't show
+  let fade = |t: f32| t * t * (3.0 - 2.0 * t);
+  let (tx, ty) = (fade(x - left), fade(y - top));
+  let upper = top_left + (top_right - top_left) * tx;
+  let lower = bottom_left + (bottom_right - bottom_left) * tx;
+  // Rounding could otherwise land on 1 when every corner is just under it
+  (upper + (lower - upper) * ty).min(1.0 - f32::EPSILON / 2.0)
+}
+
+// Numbers and strings compare by value, tuples element by element. Tuples of
+// different lengths are unequal, but values of different types can't be
+// compared at all
+fn values_equal(lhs: &Value, rhs: &Value, location: &Location) -> Result<bool, LanguageError> {
+  match (lhs, rhs) {
+    (Value::Number(lhs), Value::Number(rhs)) => Ok(lhs == rhs),
+    (Value::Tuple(lhs), Value::Tuple(rhs)) => {
+      if lhs.len() != rhs.len() {
+        return Ok(false);
+      }
+      for (lhs, rhs) in zip(lhs.iter(), rhs.iter()) {
+        if !values_equal(lhs, rhs, location)? {
+          return Ok(false);
         }
-      },
-      ExpressionOp::NumberLiteral(number) => (*number).into(),
-      ExpressionOp::TupleLiteral(expressions) => Value::Tuple(Rc::new(
-        expressions
-          .iter()
+      }
+      Ok(true)
+    }
+    (Value::Str(lhs), Value::Str(rhs)) => Ok(lhs == rhs),
+    (Value::Number(_), rhs) => Err(LanguageError {
+      error: LanguageErrorType::Type(ValueType::Number, rhs.clone()),
+      location: Some(location.clone()),
+    }),
+    (Value::Tuple(_), rhs) => Err(LanguageError {
+      error: LanguageErrorType::Type(ValueType::Tuple, rhs.clone()),
+      location: Some(location.clone()),
+    }),
+    (Value::Str(_), rhs) => Err(LanguageError {
+      error: LanguageErrorType::Type(ValueType::Str, rhs.clone()),
+      location: Some(location.clone()),
+    }),
+  }
+}
+
+// Bitwise operators see the integer part of a number as 32-bit two's
+// complement, so -1 is all ones and `-1 & 255` is 255. Numbers outside the
+// i64 range saturate before they're truncated to 32 bits, and NaN is 0. The
+// result is always read back as unsigned
+#[inline]
+fn bits(number: f32) -> u32 {
+  number as i64 as u32
+}
+
+fn tuple_values<'a>(value: &'a Value, location: &Location) -> Result<&'a Tuple, LanguageError> {
+  match value {
+    Value::Tuple(values) => Ok(values),
+    value => Err(LanguageError {
+      error: LanguageErrorType::Type(ValueType::Tuple, value.clone()),
+      location: Some(location.clone()),
+    }),
+  }
+}
+
+// Turns an index or slice bound into a position in a tuple of `length`
+// values, rejecting negative and fractional ones rather than letting the cast
+// round them into range. A slice bound may sit one past the last value.
+fn tuple_position(
+  position: f32,
+  access: TupleAccess,
+  length: usize,
+  location: &Location,
+) -> Result<usize, LanguageError> {
+  let end = match access {
+    TupleAccess::Index => length,
+    TupleAccess::SliceStart | TupleAccess::SliceEnd => length + 1,
+  };
+  if position >= 0.0 && position.floor() == position && (position as usize) < end {
+    Ok(position as usize)
+  } else {
+    Err(LanguageError {
+      error: LanguageErrorType::Range(access, position, length),
+      location: Some(location.clone()),
+    })
+  }
+}
+
+fn index_tuple(values: &[Value], index: f32, location: &Location) -> Result<Value, LanguageError> {
+  let index = tuple_position(index, TupleAccess::Index, values.len(), location)?;
+  Ok(values[index].clone())
+}
+
+// Applies `op` to each pair of numbers, treating tuples like vectors: two
+// tuples are combined element by element, and a number is paired with every
+// element of a tuple
+fn elementwise(
+  lhs: &Value,
+  rhs: &Value,
+  op: impl Fn(f32, f32) -> f32 + Copy,
+  location: &Location,
+) -> Result<Value, LanguageError> {
+  Ok(match (lhs, rhs) {
+    (Value::Number(lhs), Value::Number(rhs)) => Value::Number(op(*lhs, *rhs)),
+    (Value::Tuple(lhs), Value::Tuple(rhs)) => {
+      if lhs.len() != rhs.len() {
+        return Err(LanguageError {
+          error: LanguageErrorType::LengthMismatch(lhs.len(), rhs.len()),
+          location: Some(location.clone()),
+        });
+      }
+      Value::Tuple(Rc::new(
+        zip(lhs.iter(), rhs.iter())
+          .map(|(lhs, rhs)| elementwise(lhs, rhs, op, location))
+          .collect::<Result<Tuple, LanguageError>>()?,
+      ))
+    }
+    (Value::Tuple(lhs), rhs) => Value::Tuple(Rc::new(
+      lhs
+        .iter()
+        .map(|lhs| elementwise(lhs, rhs, op, location))
+        .collect::<Result<Tuple, LanguageError>>()?,
+    )),
+    (lhs, Value::Tuple(rhs)) => Value::Tuple(Rc::new(
+      rhs
+        .iter()
+        .map(|rhs| elementwise(lhs, rhs, op, location))
+        .collect::<Result<Tuple, LanguageError>>()?,
+    )),
+    // No string arithmetic
+    (string @ Value::Str(_), _) | (_, string @ Value::Str(_)) => {
+      return Err(LanguageError {
+        error: LanguageErrorType::Type(ValueType::Number, string.clone()),
+        location: Some(location.clone()),
+      })
+    }
+  })
+}
+
+impl Expression {
+  // Same as evaluate, but numbers are read straight out of the scope
+  // instead of cloning the Value first
+  #[inline]
+  fn evaluate_number(
+    &self,
+    context: &mut ExecutionContext,
+    functions: &Vec<Function>,
+  ) -> Result<f32, LanguageError> {
+    match &self.op {
+      ExpressionOp::NumberLiteral(number) => {
+        context.count_evaluation();
+        Ok(*number)
+      }
+      ExpressionOp::Reference(identifier) => {
+        context.count_evaluation();
+        match context.get(*identifier, &self.location)? {
+          Value::Number(number) => Ok(*number),
+          value => Err(LanguageError {
+            error: LanguageErrorType::Type(ValueType::Number, value.clone()),
+            location: Some(self.location.clone()),
+          }),
+        }
+      }
+      _ => f32::try_from(TrackedValue(
+        self.evaluate(context, functions)?,
+        &self.location,
+      )),
+    }
+  }
+
+  // Runs the user-defined function `identifier` with `values` as its arguments
+  fn call(
+    &self,
+    identifier: Identifier,
+    values: Arguments,
+    context: &mut ExecutionContext,
+    functions: &Vec<Function>,
+  ) -> Result<Value, LanguageError> {
+    let function = &functions[identifier];
+    context.push_frame(&function.locals, &self.location)?;
+    for (argument_id, value) in zip(function.arguments.iter(), values) {
+      context.set(*argument_id, value);
+    }
+    let flow = execute_statement_block(context, &function.contents, functions);
+    context.pop_frame(&function.locals);
+    Ok(flow.into_result()?.unwrap_or(Value::Number(0.0_f32)))
+  }
+
+  // Evaluates to a tuple of numbers, for builtins that treat tuples as vectors
+  fn evaluate_vector(
+    &self,
+    context: &mut ExecutionContext,
+    functions: &Vec<Function>,
+  ) -> Result<Vector, LanguageError> {
+    self.evaluate_borrowed(context, functions, |value| {
+      tuple_values(value, &self.location)?
+        .iter()
+        .map(|value| match value {
+          Value::Number(number) => Ok(*number),
+          value => Err(LanguageError {
+            error: LanguageErrorType::Type(ValueType::Number, value.clone()),
+            location: Some(self.location.clone()),
+          }),
+        })
+        .collect()
+    })
+  }
+
+  // Hands `consume` the value, borrowing it straight out of the scope when
+  // this is a variable instead of cloning it first
+  #[inline]
+  fn evaluate_borrowed<T>(
+    &self,
+    context: &mut ExecutionContext,
+    functions: &Vec<Function>,
+    consume: impl FnOnce(&Value) -> Result<T, LanguageError>,
+  ) -> Result<T, LanguageError> {
+    match &self.op {
+      ExpressionOp::Reference(identifier) => {
+        context.count_evaluation();
+        consume(context.get(*identifier, &self.location)?)
+      }
+      _ => consume(&self.evaluate(context, functions)?),
+    }
+  }
+
+  // Same as evaluate, but skips the call for the common leaves
+  #[inline(always)]
+  fn evaluate_operand(
+    &self,
+    context: &mut ExecutionContext,
+    functions: &Vec<Function>,
+  ) -> Result<Value, LanguageError> {
+    match &self.op {
+      ExpressionOp::NumberLiteral(number) => {
+        context.count_evaluation();
+        Ok(Value::Number(*number))
+      }
+      ExpressionOp::Reference(identifier) => {
+        context.count_evaluation();
+        Ok(context.get(*identifier, &self.location)?.clone())
+      }
+      _ => self.evaluate(context, functions),
+    }
+  }
+
+  // +, -, * and / work on tuples too, see elementwise. Forcing these inline
+  // keeps plain number arithmetic about as fast as it was before tuples
+  // were allowed
+  #[inline(always)]
+  fn evaluate_arithmetic(
+    &self,
+    lhs: &Expression,
+    rhs: &Expression,
+    op: impl Fn(f32, f32) -> f32 + Copy,
+    context: &mut ExecutionContext,
+    functions: &Vec<Function>,
+  ) -> Result<Value, LanguageError> {
+    let lhs = lhs.evaluate_operand(context, functions)?;
+    let rhs = rhs.evaluate_operand(context, functions)?;
+    if let (Value::Number(lhs), Value::Number(rhs)) = (&lhs, &rhs) {
+      return Ok(Value::Number(op(*lhs, *rhs)));
+    }
+    elementwise(&lhs, &rhs, op, &self.location)
+  }
+
+  // Runs a call to a builtin, host or user-defined function. Kept out of
+  // evaluate so its locals don't bloat the stack frame of every expression
+  #[inline(never)]
+  fn evaluate_call(
+    &self,
+    function: &FunctionIdentifier,
+    arguments: &[Expression],
+    context: &mut ExecutionContext,
+    functions: &Vec<Function>,
+  ) -> Result<Value, LanguageError> {
+    Ok(match function {
+      FunctionIdentifier::Len => arguments[0].evaluate_borrowed(context, functions, |value| {
+        Ok(Value::from(
+          tuple_values(value, &arguments[0].location)?.len() as f32,
+        ))
+      })?,
+      FunctionIdentifier::Range => {
+        let mut bounds = [0.0, 0.0, 1.0];
+        // A lone argument is the end
+        let first = if arguments.len() == 1 { 1 } else { 0 };
+        for (bound, argument) in zip(&mut bounds[first..], arguments) {
+          *bound = argument.evaluate_number(context, functions)?;
+        }
+        let [start, end, step] = bounds;
+        if !(step > 0.0 && step.is_finite()) {
+          return Err(LanguageError {
+            error: LanguageErrorType::RangeStep(step),
+            location: Some(arguments[2].location.clone()),
+          });
+        }
+        // NaN bounds make an empty tuple
+        let length = ((end - start) / step).ceil().max(0.0);
+        if length > MAX_RANGE_LENGTH as f32 {
+          return Err(LanguageError {
+            error: LanguageErrorType::RangeLength(MAX_RANGE_LENGTH),
+            location: Some(self.location.clone()),
+          });
+        }
+        Value::Tuple(Rc::new(
+          (0..length as usize)
+            .map(|index| Value::Number(start + index as f32 * step))
+            .collect(),
+        ))
+      }
+      FunctionIdentifier::Sum => {
+        let values = arguments[0].evaluate_vector(context, functions)?;
+        Value::from(values.iter().fold(0.0, |sum, value| sum + value))
+      }
+      FunctionIdentifier::Product => {
+        let values = arguments[0].evaluate_vector(context, functions)?;
+        Value::from(values.iter().fold(1.0, |product, value| product * value))
+      }
+      FunctionIdentifier::Reverse => {
+        let tuple = <Rc<Tuple>>::try_from(&TrackedValue(
+          arguments[0].evaluate(context, functions)?,
+          &arguments[0].location,
+        ))?;
+        Value::Tuple(Rc::new(tuple.iter().rev().cloned().collect()))
+      }
+      FunctionIdentifier::IndexWrap | FunctionIdentifier::IndexClamp => {
+        // Truncated toward zero rather than rejected like a fractional
+        // `tuple[i]`, and kept signed so it can wrap
+        let index = arguments[1].evaluate_number(context, functions)? as i64;
+        arguments[0].evaluate_borrowed(context, functions, |value| {
+          let values = tuple_values(value, &arguments[0].location)?;
+          let Some(last) = values.len().checked_sub(1) else {
+            return Err(LanguageError {
+              error: LanguageErrorType::EmptyIndex,
+              location: Some(arguments[0].location.clone()),
+            });
+          };
+          let index = match function {
+            FunctionIdentifier::IndexWrap => index.rem_euclid(values.len() as i64) as usize,
+            _ => index.clamp(0, last as i64) as usize,
+          };
+          Ok(values[index].clone())
+        })?
+      }
+      FunctionIdentifier::Dot => {
+        let lhs = arguments[0].evaluate_vector(context, functions)?;
+        let rhs = arguments[1].evaluate_vector(context, functions)?;
+        if lhs.len() != rhs.len() {
+          return Err(LanguageError {
+            error: LanguageErrorType::LengthMismatch(lhs.len(), rhs.len()),
+            location: Some(self.location.clone()),
+          });
+        }
+        // Folding from 0.0 makes the dot product of two empty tuples +0.
+        // Iterator::sum() would start at -0 and return that instead
+        Value::from(zip(lhs, rhs).fold(0.0, |sum, (lhs, rhs)| sum + lhs * rhs))
+      }
+      FunctionIdentifier::Magnitude => {
+        let vector = arguments[0].evaluate_vector(context, functions)?;
+        Value::from(magnitude(&vector))
+      }
+      FunctionIdentifier::Normalize => {
+        let vector = arguments[0].evaluate_vector(context, functions)?;
+        let magnitude = magnitude(&vector);
+        if magnitude == 0.0 {
+          return Err(LanguageError {
+            error: LanguageErrorType::NormalizeZero,
+            location: Some(arguments[0].location.clone()),
+          });
+        }
+        Value::Tuple(Rc::new(
+          vector
+            .into_iter()
+            .map(|value| Value::Number(value / magnitude))
+            .collect(),
+        ))
+      }
+      // a + (b - a) * t, with no clamping of t
+      FunctionIdentifier::Mix => {
+        let a = arguments[0].evaluate(context, functions)?;
+        let b = arguments[1].evaluate(context, functions)?;
+        let t = arguments[2].evaluate(context, functions)?;
+        let difference = elementwise(&a, &b, |a, b| b - a, &self.location)?;
+        let offset = elementwise(
+          &difference,
+          &t,
+          |difference, t| difference * t,
+          &self.location,
+        )?;
+        elementwise(&a, &offset, |a, offset| a + offset, &self.location)?
+      }
+      FunctionIdentifier::Min | FunctionIdentifier::Max => {
+        let mut result = arguments[0].evaluate_number(context, functions)?;
+        for argument in &arguments[1..] {
+          let value = argument.evaluate_number(context, functions)?;
+          result = match function {
+            FunctionIdentifier::Min => result.min(value),
+            _ => result.max(value),
+          };
+        }
+        Value::from(result)
+      }
+      FunctionIdentifier::Log => {
+        let value = arguments[0].evaluate_number(context, functions)?;
+        Value::from(match arguments.get(1) {
+          Some(base) => Float::log(value, base.evaluate_number(context, functions)?),
+          None => Float::ln(value),
+        })
+      }
+      FunctionIdentifier::Atan2 => {
+        let y = arguments[0].evaluate_number(context, functions)?;
+        let x = arguments[1].evaluate_number(context, functions)?;
+        Value::from(Float::atan2(y, x))
+      }
+      FunctionIdentifier::Mod => {
+        let lhs = arguments[0].evaluate_number(context, functions)?;
+        let rhs = arguments[1].evaluate_number(context, functions)?;
+        Value::from(lhs.rem_euclid(rhs))
+      }
+      FunctionIdentifier::Clamp => {
+        let value = arguments[0].evaluate_number(context, functions)?;
+        let lo = arguments[1].evaluate_number(context, functions)?;
+        let hi = arguments[2].evaluate_number(context, functions)?;
+        Value::from(value.max(lo).min(hi))
+      }
+      FunctionIdentifier::Step => {
+        let edge = arguments[0].evaluate_number(context, functions)?;
+        let value = arguments[1].evaluate_number(context, functions)?;
+        Value::from(value >= edge)
+      }
+      FunctionIdentifier::Smoothstep => {
+        let low = arguments[0].evaluate_number(context, functions)?;
+        let high = arguments[1].evaluate_number(context, functions)?;
+        let value = arguments[2].evaluate_number(context, functions)?;
+        Value::from(smoothstep(low, high, value))
+      }
+      FunctionIdentifier::Hsv => {
+        let hue = arguments[0].evaluate_number(context, functions)?;
+        let saturation = arguments[1].evaluate_number(context, functions)?;
+        let value = arguments[2].evaluate_number(context, functions)?;
+        Value::Tuple(Rc::new(
+          hsv(hue, saturation, value)
+            .into_iter()
+            .map(Value::Number)
+            .collect(),
+        ))
+      }
+      FunctionIdentifier::Quantize => Value::from(quantize(
+        arguments[0].evaluate_number(context, functions)?,
+        arguments[1].evaluate_number(context, functions)?,
+      )),
+      FunctionIdentifier::Dither => {
+        let mut values = [0.0_f32; 4];
+        for (value, argument) in zip(values.iter_mut(), arguments.iter()) {
+          *value = argument.evaluate_number(context, functions)?;
+        }
+        let [value, x, y, levels] = values;
+        Value::from(dither(value, x, y, levels))
+      }
+      FunctionIdentifier::Random => {
+        let seeds = arguments
+          .iter()
+          .map(|argument| argument.evaluate_number(context, functions))
+          .collect::<Result<Vec<f32>, LanguageError>>()?;
+        Value::from(random(&seeds))
+      }
+      FunctionIdentifier::Noise => {
+        let x = arguments[0].evaluate_number(context, functions)?;
+        let y = arguments[1].evaluate_number(context, functions)?;
+        Value::from(noise(x, y))
+      }
+      FunctionIdentifier::Print => {
+        let value = arguments[0].evaluate(context, functions)?;
+        if let Some(sink) = &context.scope_locations.print_sink {
+          (sink.0)(&value.to_string());
+        }
+        value
+      }
+      FunctionIdentifier::Assert => {
+        let condition = arguments[0].evaluate_number(context, functions)?;
+        if condition == 0.0 {
+          return Err(LanguageError {
+            error: LanguageErrorType::AssertionFailed,
+            location: Some(arguments[0].location.clone()),
+          });
+        }
+        Value::from(condition)
+      }
+      FunctionIdentifier::Host(identifier) => {
+        let values = arguments
+          .iter()
+          .map(|argument| argument.evaluate(context, functions))
+          .collect::<Result<Arguments, LanguageError>>()?;
+        let host_function = &context.scope_locations.host_functions[*identifier];
+        (host_function.function)(&values).map_err(|mut err| {
+          err.location.get_or_insert_with(|| self.location.clone());
+          err
+        })?
+      }
+      FunctionIdentifier::Map(identifier) => {
+        let tuple = <Rc<Tuple>>::try_from(&TrackedValue(
+          arguments[0].evaluate(context, functions)?,
+          &arguments[0].location,
+        ))?;
+        Value::Tuple(Rc::new(
+          tuple
+            .iter()
+            .map(|value| self.call(*identifier, smallvec![value.clone()], context, functions))
+            .collect::<Result<Tuple, LanguageError>>()?,
+        ))
+      }
+      FunctionIdentifier::Reduce(identifier) => {
+        let tuple = <Rc<Tuple>>::try_from(&TrackedValue(
+          arguments[0].evaluate(context, functions)?,
+          &arguments[0].location,
+        ))?;
+        let mut accumulator = arguments[1].evaluate(context, functions)?;
+        for value in tuple.iter() {
+          accumulator = self.call(
+            *identifier,
+            smallvec![accumulator, value.clone()],
+            context,
+            functions,
+          )?;
+        }
+        accumulator
+      }
+      FunctionIdentifier::UserDefined(identifier) => {
+        // Evaluate every argument before binding any, since a recursive call
+        // may read the locals it's about to replace
+        let values = arguments
+          .iter()
+          .map(|argument| argument.evaluate(context, functions))
+          .collect::<Result<Arguments, LanguageError>>()?;
+        self.call(*identifier, values, context, functions)?
+      }
+      FunctionIdentifier::Math(function) => {
+        Value::from(function.apply(arguments[0].evaluate_number(context, functions)?))
+      }
+    })
+  }
+
+  fn evaluate(
+    &self,
+    context: &mut ExecutionContext,
+    functions: &Vec<Function>,
+  ) -> Result<Value, LanguageError> {
+    context.count_evaluation();
+    let stack = match self.op {
+      ExpressionOp::FunctionCall(..) => STACK_PER_FUNCTION_CALL,
+      _ => STACK_PER_EXPRESSION,
+    };
+    context.claim_stack(stack, &self.location)?;
+    let value = self.evaluate_op(context, functions);
+    context.stack_used -= stack;
+    value
+  }
+
+  fn evaluate_op(
+    &self,
+    context: &mut ExecutionContext,
+    functions: &Vec<Function>,
+  ) -> Result<Value, LanguageError> {
+    Ok(match &self.op {
+      ExpressionOp::Reference(identifier) => context.get(*identifier, &self.location)?.clone(),
+      ExpressionOp::FunctionCall(function, arguments) => {
+        self.evaluate_call(function, arguments, context, functions)?
+      }
+      ExpressionOp::NumberLiteral(number) => (*number).into(),
+      ExpressionOp::StringLiteral(string) => Value::Str(Rc::from(&**string)),
+      ExpressionOp::TupleLiteral(expressions) => Value::Tuple(Rc::new(
+        expressions
+          .iter()
           .map(|expression| expression.evaluate(context, functions))
-          .collect::<Result<Vec<Value>, _>>()?,
+          .collect::<Result<Tuple, _>>()?,
       )),
+      ExpressionOp::ConstantTuple(constant, expressions) => {
+        if let Some(tuple) = context.constant_tuples.get(constant) {
+          return Ok(Value::Tuple(Rc::clone(tuple)));
+        }
+        let tuple = Rc::new(
+          expressions
+            .iter()
+            .map(|expression| expression.evaluate(context, functions))
+            .collect::<Result<Tuple, _>>()?,
+        );
+        context.constant_tuples.insert(*constant, Rc::clone(&tuple));
+        Value::Tuple(tuple)
+      }
       ExpressionOp::Index(tuple, index) => {
-        let index_num = f32::try_from(TrackedValue(
-          index.evaluate(context, functions)?,
-          &index.location,
-        ))? as usize;
-        let tuple = <Rc<Vec<Value>>>::try_from(&TrackedValue(
+        let index_num = index.evaluate_number(context, functions)?;
+        tuple.evaluate_borrowed(context, functions, |value| {
+          index_tuple(
+            tuple_values(value, &tuple.location)?,
+            index_num,
+            &index.location,
+          )
+        })?
+      }
+      ExpressionOp::Slice(tuple, start, end) => {
+        let values = <Rc<Tuple>>::try_from(&TrackedValue(
           tuple.evaluate(context, functions)?,
           &tuple.location,
         ))?;
-        tuple
-          .get(index_num)
-          .ok_or_else(|| LanguageError {
-            error: LanguageErrorType::Range(index_num, tuple.len()),
-            location: Some(index.location.clone()),
-          })?
-          .clone()
-      }
-      ExpressionOp::Pow(lhs, rhs) => Value::from(
-        f32::try_from(TrackedValue(
-          lhs.evaluate(context, functions)?,
-          &lhs.location,
-        ))?
-        .powf(f32::try_from(TrackedValue(
-          rhs.evaluate(context, functions)?,
-          &rhs.location,
-        ))?),
-      ),
+        let mut bound = |bound: &Option<Box<Expression>>, access, default| match bound {
+          Some(bound) => tuple_position(
+            bound.evaluate_number(context, functions)?,
+            access,
+            values.len(),
+            &bound.location,
+          ),
+          None => Ok(default),
+        };
+        let start = bound(start, TupleAccess::SliceStart, 0)?;
+        let end = bound(end, TupleAccess::SliceEnd, values.len())?;
+        // A backwards range is just empty
+        Value::Tuple(Rc::new(
+          values[start..end.max(start)].iter().cloned().collect(),
+        ))
+      }
+      ExpressionOp::Pow(lhs, rhs) => Value::from(Float::powf(
+        lhs.evaluate_number(context, functions)?,
+        rhs.evaluate_number(context, functions)?,
+      )),
       ExpressionOp::Modulo(lhs, rhs) => Value::from(
-        f32::try_from(TrackedValue(
-          lhs.evaluate(context, functions)?,
-          &lhs.location,
-        ))?
-          % f32::try_from(TrackedValue(
-            rhs.evaluate(context, functions)?,
-            &rhs.location,
-          ))?,
-      ),
-      ExpressionOp::Add(lhs, rhs) => Value::from(
-        f32::try_from(TrackedValue(
-          lhs.evaluate(context, functions)?,
-          &lhs.location,
-        ))?
-          + f32::try_from(TrackedValue(
-            rhs.evaluate(context, functions)?,
-            &rhs.location,
-          ))?,
+        lhs.evaluate_number(context, functions)? % rhs.evaluate_number(context, functions)?,
       ),
-      ExpressionOp::Sub(lhs, rhs) => Value::from(
-        f32::try_from(TrackedValue(
-          lhs.evaluate(context, functions)?,
-          &lhs.location,
-        ))?
-          - f32::try_from(TrackedValue(
-            rhs.evaluate(context, functions)?,
-            &rhs.location,
-          ))?,
-      ),
-      ExpressionOp::Mul(lhs, rhs) => Value::from(
-        f32::try_from(TrackedValue(
-          lhs.evaluate(context, functions)?,
-          &lhs.location,
-        ))?
-          * f32::try_from(TrackedValue(
-            rhs.evaluate(context, functions)?,
-            &rhs.location,
-          ))?,
-      ),
-      ExpressionOp::Div(lhs, rhs) => Value::from(
-        f32::try_from(TrackedValue(
-          lhs.evaluate(context, functions)?,
-          &lhs.location,
-        ))?
-          / f32::try_from(TrackedValue(
-            rhs.evaluate(context, functions)?,
-            &rhs.location,
-          ))?,
+      ExpressionOp::Add(lhs, rhs) => {
+        self.evaluate_arithmetic(lhs, rhs, |lhs, rhs| lhs + rhs, context, functions)?
+      }
+      ExpressionOp::Sub(lhs, rhs) => {
+        self.evaluate_arithmetic(lhs, rhs, |lhs, rhs| lhs - rhs, context, functions)?
+      }
+      ExpressionOp::Mul(lhs, rhs) => {
+        self.evaluate_arithmetic(lhs, rhs, |lhs, rhs| lhs * rhs, context, functions)?
+      }
+      ExpressionOp::Div(lhs, rhs) => {
+        self.evaluate_arithmetic(lhs, rhs, |lhs, rhs| lhs / rhs, context, functions)?
+      }
+      ExpressionOp::FloorDiv(lhs, rhs) => Value::from(
+        (lhs.evaluate_number(context, functions)? / rhs.evaluate_number(context, functions)?)
+          .floor(),
       ),
       ExpressionOp::BinaryAnd(lhs, rhs) => Value::from(
-        (f32::try_from(TrackedValue(
-          lhs.evaluate(context, functions)?,
-          &lhs.location,
-        ))? as u32
-          & f32::try_from(TrackedValue(
-            rhs.evaluate(context, functions)?,
-            &rhs.location,
-          ))? as u32) as f32,
+        (bits(lhs.evaluate_number(context, functions)?)
+          & bits(rhs.evaluate_number(context, functions)?)) as f32,
       ),
       ExpressionOp::Xor(lhs, rhs) => Value::from(
-        (f32::try_from(TrackedValue(
-          lhs.evaluate(context, functions)?,
-          &lhs.location,
-        ))? as u32
-          ^ f32::try_from(TrackedValue(
-            rhs.evaluate(context, functions)?,
-            &rhs.location,
-          ))? as u32) as f32,
+        (bits(lhs.evaluate_number(context, functions)?)
+          ^ bits(rhs.evaluate_number(context, functions)?)) as f32,
       ),
+      // Shifting by 32 or more pushes every bit out, rather than panicking in
+      // debug builds and wrapping around in release. Negative amounts (and
+      // NaN) saturate to a shift of 0
       ExpressionOp::ShiftLeft(lhs, rhs) => Value::from(
-        ((f32::try_from(TrackedValue(
-          lhs.evaluate(context, functions)?,
-          &lhs.location,
-        ))? as u32)
-          << (f32::try_from(TrackedValue(
-            rhs.evaluate(context, functions)?,
-            &rhs.location,
-          ))? as u32)) as f32,
+        bits(lhs.evaluate_number(context, functions)?)
+          .checked_shl(rhs.evaluate_number(context, functions)? as u32)
+          .unwrap_or(0) as f32,
       ),
       ExpressionOp::ShiftRight(lhs, rhs) => Value::from(
-        ((f32::try_from(TrackedValue(
-          lhs.evaluate(context, functions)?,
-          &lhs.location,
-        ))? as u32)
-          >> (f32::try_from(TrackedValue(
-            rhs.evaluate(context, functions)?,
-            &rhs.location,
-          ))? as u32)) as f32,
+        bits(lhs.evaluate_number(context, functions)?)
+          .checked_shr(rhs.evaluate_number(context, functions)? as u32)
+          .unwrap_or(0) as f32,
       ),
       ExpressionOp::BinaryOr(lhs, rhs) => Value::from(
-        (f32::try_from(TrackedValue(
-          lhs.evaluate(context, functions)?,
-          &lhs.location,
-        ))? as u32
-          | f32::try_from(TrackedValue(
-            rhs.evaluate(context, functions)?,
-            &rhs.location,
-          ))? as u32) as f32,
+        (bits(lhs.evaluate_number(context, functions)?)
+          | bits(rhs.evaluate_number(context, functions)?)) as f32,
       ),
       ExpressionOp::GreaterThan(lhs, rhs) => Value::from(
-        f32::try_from(TrackedValue(
-          lhs.evaluate(context, functions)?,
-          &lhs.location,
-        ))?
-          > f32::try_from(TrackedValue(
-            rhs.evaluate(context, functions)?,
-            &rhs.location,
-          ))?,
+        lhs.evaluate_number(context, functions)? > rhs.evaluate_number(context, functions)?,
       ),
       ExpressionOp::LessThan(lhs, rhs) => Value::from(
-        f32::try_from(TrackedValue(
-          lhs.evaluate(context, functions)?,
-          &lhs.location,
-        ))?
-          < f32::try_from(TrackedValue(
-            rhs.evaluate(context, functions)?,
-            &rhs.location,
-          ))?,
+        lhs.evaluate_number(context, functions)? < rhs.evaluate_number(context, functions)?,
       ),
       ExpressionOp::GreaterThanOrEqual(lhs, rhs) => Value::from(
-        f32::try_from(TrackedValue(
-          lhs.evaluate(context, functions)?,
-          &lhs.location,
-        ))?
-          >= f32::try_from(TrackedValue(
-            rhs.evaluate(context, functions)?,
-            &rhs.location,
-          ))?,
+        lhs.evaluate_number(context, functions)? >= rhs.evaluate_number(context, functions)?,
       ),
       ExpressionOp::LessThanOrEqual(lhs, rhs) => Value::from(
-        f32::try_from(TrackedValue(
-          lhs.evaluate(context, functions)?,
-          &lhs.location,
-        ))?
-          <= f32::try_from(TrackedValue(
-            rhs.evaluate(context, functions)?,
-            &rhs.location,
-          ))?,
-      ),
-      ExpressionOp::Equal(lhs, rhs) => Value::from(
-        f32::try_from(TrackedValue(
-          lhs.evaluate(context, functions)?,
-          &lhs.location,
-        ))?
-          == f32::try_from(TrackedValue(
-            rhs.evaluate(context, functions)?,
-            &rhs.location,
-          ))?,
-      ),
-      ExpressionOp::NotEqual(lhs, rhs) => Value::from(
-        f32::try_from(TrackedValue(
-          lhs.evaluate(context, functions)?,
-          &lhs.location,
-        ))?
-          != f32::try_from(TrackedValue(
-            rhs.evaluate(context, functions)?,
-            &rhs.location,
-          ))?,
+        lhs.evaluate_number(context, functions)? <= rhs.evaluate_number(context, functions)?,
       ),
-      ExpressionOp::Neg(value) => Value::from(-f32::try_from(TrackedValue(
-        value.evaluate(context, functions)?,
-        &value.location,
-      ))?),
-      ExpressionOp::Invert(value) => Value::from(
-        if f32::try_from(TrackedValue(
-          value.evaluate(context, functions)?,
-          &value.location,
-        ))?
-          == 0.0
-        {
+      ExpressionOp::Equal(lhs, rhs) => Value::from(values_equal(
+        &lhs.evaluate(context, functions)?,
+        &rhs.evaluate(context, functions)?,
+        &rhs.location,
+      )?),
+      ExpressionOp::NotEqual(lhs, rhs) => Value::from(!values_equal(
+        &lhs.evaluate(context, functions)?,
+        &rhs.evaluate(context, functions)?,
+        &rhs.location,
+      )?),
+      ExpressionOp::Neg(value) => Value::from(-value.evaluate_number(context, functions)?),
+      ExpressionOp::Invert(value) => {
+        Value::from(if value.evaluate_number(context, functions)? == 0.0 {
           1.0
         } else {
           0.0
-        },
-      ),
-      ExpressionOp::And(lhs, rhs) => Value::from(
-        if f32::try_from(TrackedValue(
-          lhs.evaluate(context, functions)?,
-          &lhs.location,
-        ))?
-          != 0.0
-        {
-          f32::try_from(TrackedValue(
-            rhs.evaluate(context, functions)?,
-            &rhs.location,
-          ))?
+        })
+      }
+      ExpressionOp::Ternary(condition, if_true, if_false) => {
+        if condition.evaluate_number(context, functions)? != 0.0 {
+          if_true.evaluate(context, functions)?
+        } else {
+          if_false.evaluate(context, functions)?
+        }
+      }
+      ExpressionOp::And(lhs, rhs) => {
+        Value::from(if lhs.evaluate_number(context, functions)? != 0.0 {
+          rhs.evaluate_number(context, functions)?
         } else {
           0.0
-        },
-      ),
+        })
+      }
       ExpressionOp::Or(lhs, rhs) => {
-        let lhs = f32::try_from(TrackedValue(
-          lhs.evaluate(context, functions)?,
-          &lhs.location,
-        ))?;
+        let lhs = lhs.evaluate_number(context, functions)?;
         Value::from(if lhs != 0.0 {
           lhs
         } else {
-          f32::try_from(TrackedValue(
-            rhs.evaluate(context, functions)?,
-            &rhs.location,
-          ))?
+          rhs.evaluate_number(context, functions)?
         })
       }
     })
@@ -720,25 +1976,27 @@ impl Expression {
 }
 
 fn parse_statement_block(
-  execution_context: Rc<Mutex<ExecutionContext>>,
+  execution_context: &mut ExecutionContext,
   scope: String,
   pairs: Pairs<Rule>,
-  functions: &HashMap<String, FunctionPrototype>,
+  state: &ParseState,
+  in_loop: bool,
 ) -> Result<Vec<Statement>, LanguageError> {
   pairs
     .filter(|pair| pair.as_rule() == Rule::statement)
     .map(|pair| {
       parse_statement(
-        execution_context.clone(),
+        execution_context,
         scope.clone(),
         pair.into_inner().next().unwrap(),
-        functions,
+        state,
+        in_loop,
       )
     })
     .collect::<Result<Vec<Statement>, LanguageError>>()
 }
 
-#[derive(Debug, Clone, PartialEq, Hash, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct VariableKey {
   // variable name
   pub name: String,
@@ -752,25 +2010,118 @@ impl fmt::Display for VariableKey {
   }
 }
 
+type HostFunctionImpl = dyn Fn(&[Value]) -> Result<Value, LanguageError> + Send + Sync;
+
+// A function provided by the embedder, see ExecutionContext::register_function
+#[derive(Clone)]
+struct HostFunction {
+  arity: usize,
+  function: Arc<HostFunctionImpl>,
+}
+impl fmt::Debug for HostFunction {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("HostFunction")
+      .field("arity", &self.arity)
+      .finish_non_exhaustive()
+  }
+}
+
+// Where print() sends its output, see ExecutionContext::set_print_sink
+#[derive(Clone)]
+struct PrintSink(Arc<dyn Fn(&str) + Send + Sync>);
+impl fmt::Debug for PrintSink {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("PrintSink").finish_non_exhaustive()
+  }
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct ExecutionContextLUT {
-  scope_locations: BiHashMap<VariableKey, usize>,
+  scope_locations: BiBTreeMap<VariableKey, usize>,
+  host_functions: Vec<HostFunction>,
+  host_function_names: BTreeMap<String, usize>,
+  print_sink: Option<PrintSink>,
 }
 
-#[derive(Debug, Clone, Default)]
+const DEFAULT_MAX_CALL_DEPTH: usize = 128;
+// Native stack a call and each level of expression nesting can take, a
+// little over the most measured on x86_64. Function calls, builtins
+// included, are the biggest expressions at about 2.7KiB in release and 50KiB
+// in debug. Debug builds give every local its own stack slot, so they need
+// far more
+const STACK_PER_FRAME: usize = if cfg!(debug_assertions) {
+  32 << 10
+} else {
+  3 << 10
+};
+const STACK_PER_EXPRESSION: usize = if cfg!(debug_assertions) {
+  28 << 10
+} else {
+  3 << 9
+};
+const STACK_PER_FUNCTION_CALL: usize = if cfg!(debug_assertions) {
+  54 << 10
+} else {
+  3 << 10
+};
+// What the call depth limit counts in: one call nested an expression deep,
+// like the `n * fact(n - 1)` of a factorial
+const STACK_PER_CALL: usize = STACK_PER_FRAME + STACK_PER_FUNCTION_CALL + STACK_PER_EXPRESSION;
+// Left for the host and the program's top level
+const STACK_HEADROOM: usize = 512 << 10;
+// Stack a thread needs to run programs that recurse as deep as the default
+// limit allows. Run them on threads at least this big, or lower the limit
+// with ExecutionContext::fit_call_depth_to_stack. That's about 1.5MiB in
+// release builds, so the usual 2MiB spawned thread is enough there
+pub const STACK_SIZE: usize = DEFAULT_MAX_CALL_DEPTH * STACK_PER_CALL + STACK_HEADROOM;
+
+#[derive(Debug, Clone)]
 pub struct ExecutionContext {
   scope_locations: ExecutionContextLUT,
   scope: Vec<Option<Value>>,
+  // Generation each slot was last written in. Slots left over from older
+  // generations read as whatever the checkpoint holds, so resetting doesn't
+  // have to touch every slot
+  generations: Vec<u64>,
+  generation: u64,
+  checkpoint: Vec<Option<Value>>,
+  // Callers' values of slots shadowed by the active calls
+  saved_slots: Vec<Option<Value>>,
+  // Estimated native stack taken by the calls and expressions being run
+  stack_used: usize,
+  max_call_depth: usize,
+  // ExpressionOp::ConstantTuple values built so far, by id
+  constant_tuples: BTreeMap<usize, Rc<Tuple>>,
+  #[cfg(feature = "profile")]
+  profile: Profile,
+}
+
+// How much work a context has done since the last take_profile()
+#[cfg(feature = "profile")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Profile {
+  // Expression nodes evaluated, literals and variable reads included
+  pub evaluations: u64,
+  // Pixels rendered by render_frame and friends
+  pub pixels: u64,
+}
+
+#[cfg(feature = "profile")]
+impl Profile {
+  pub fn evaluations_per_pixel(&self) -> f64 {
+    self.evaluations as f64 / self.pixels.max(1) as f64
+  }
+}
+
+impl Default for ExecutionContext {
+  fn default() -> Self {
+    Self::new_with_scope_locations(ExecutionContextLUT::default())
+  }
 }
 impl fmt::Display for ExecutionContext {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     write!(f, "{{")?;
-    let mut scope_iter = self
-      .scope_locations
-      .scope_locations
-      .iter()
-      .filter_map(|(key, index)| Some((key, self.scope[*index].clone()?)))
-      .peekable();
+    let mut scope_iter = self.variables().peekable();
     while let Some((key, value)) = scope_iter.next() {
       write!(f, "{key} = {value}")?;
       if scope_iter.peek().is_some() {
@@ -783,34 +2134,178 @@ impl fmt::Display for ExecutionContext {
 impl ExecutionContext {
   pub fn new_with_scope_locations(scope_locations: ExecutionContextLUT) -> Self {
     let length = scope_locations.scope_locations.len();
-    let mut scope = Vec::with_capacity(length);
-    scope.resize(length, None);
     Self {
       scope_locations,
-      scope,
+      scope: vec![None; length],
+      generations: vec![0; length],
+      generation: 1,
+      checkpoint: Vec::new(),
+      saved_slots: Vec::new(),
+      stack_used: 0,
+      max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+      constant_tuples: BTreeMap::new(),
+      #[cfg(feature = "profile")]
+      profile: Profile::default(),
+    }
+  }
+  // Returns the counts gathered so far and starts over from zero. Only covers
+  // this context, so render_frame_parallel's per-thread contexts aren't in it
+  #[cfg(feature = "profile")]
+  pub fn take_profile(&mut self) -> Profile {
+    core::mem::take(&mut self.profile)
+  }
+  // Compiled out unless the profile feature is on
+  #[inline(always)]
+  fn count_evaluation(&mut self) {
+    #[cfg(feature = "profile")]
+    {
+      self.profile.evaluations += 1;
+    }
+  }
+  #[inline(always)]
+  pub(crate) fn count_pixel(&mut self) {
+    #[cfg(feature = "profile")]
+    {
+      self.profile.pixels += 1;
+    }
+  }
+  pub fn set_max_call_depth(&mut self, max_call_depth: usize) {
+    self.max_call_depth = max_call_depth;
+  }
+  // Lowers the call depth limit far enough that programs can't overflow a
+  // stack of `stack_size` bytes, for hosts that can't pick their stack size
+  pub fn fit_call_depth_to_stack(&mut self, stack_size: usize) {
+    let depth = stack_size.saturating_sub(STACK_HEADROOM) / STACK_PER_CALL;
+    self.max_call_depth = self.max_call_depth.min(depth);
+  }
+  // Counts `bytes` more native stack as taken, or errors if that goes past
+  // what the call depth limit allows. Expressions take stack too, so a call
+  // nested deep inside one uses up more of the limit than a bare call
+  fn claim_stack(&mut self, bytes: usize, location: &Location) -> Result<(), LanguageError> {
+    if self.stack_used + bytes > self.max_call_depth.saturating_mul(STACK_PER_CALL) {
+      return Err(LanguageError {
+        error: LanguageErrorType::RecursionLimit(self.max_call_depth),
+        location: Some(location.clone()),
+      });
+    }
+    self.stack_used += bytes;
+    Ok(())
+  }
+  // Stashes the given slots so a call can use them without clobbering its caller
+  fn push_frame(&mut self, slots: &[Identifier], location: &Location) -> Result<(), LanguageError> {
+    self.claim_stack(STACK_PER_FRAME, location)?;
+    for slot in slots {
+      let saved = self.take(*slot);
+      self.saved_slots.push(saved);
+    }
+    Ok(())
+  }
+  fn pop_frame(&mut self, slots: &[Identifier]) {
+    for slot in slots.iter().rev() {
+      let saved = self.saved_slots.pop().unwrap();
+      self.put(*slot, saved);
     }
+    self.stack_used -= STACK_PER_FRAME;
   }
   pub fn export_scope_locations(&self) -> ExecutionContextLUT {
     self.scope_locations.clone()
   }
+  // Makes `function` callable from programs parsed with this context as
+  // `name(...)` with exactly `arity` arguments. Builtins and functions the
+  // program defines itself take precedence
+  pub fn register_function(
+    &mut self,
+    name: &str,
+    arity: usize,
+    function: impl Fn(&[Value]) -> Result<Value, LanguageError> + Send + Sync + 'static,
+  ) {
+    let host_function = HostFunction {
+      arity,
+      function: Arc::new(function),
+    };
+    let lut = &mut self.scope_locations;
+    match lut.host_function_names.get(name) {
+      Some(index) => lut.host_functions[*index] = host_function,
+      None => {
+        lut
+          .host_function_names
+          .insert(name.to_string(), lut.host_functions.len());
+        lut.host_functions.push(host_function);
+      }
+    }
+  }
+  // Receives everything programs print(), formatted like Value's Display.
+  // print() runs per pixel, so the sink is the place to throttle it. Without
+  // a sink it does nothing but return its argument
+  pub fn set_print_sink(&mut self, sink: impl Fn(&str) + Send + Sync + 'static) {
+    self.scope_locations.print_sink = Some(PrintSink(Arc::new(sink)));
+  }
+  pub fn clear_print_sink(&mut self) {
+    self.scope_locations.print_sink = None;
+  }
+  fn host_function(&self, name: &str) -> Option<(Identifier, usize)> {
+    let index = *self.scope_locations.host_function_names.get(name)?;
+    Some((index, self.scope_locations.host_functions[index].arity))
+  }
+  fn scope_identifiers(&self, scope: &str) -> Vec<Identifier> {
+    let mut identifiers = self
+      .scope_locations
+      .scope_locations
+      .iter()
+      .filter(|(key, _)| key.scope == scope)
+      .map(|(_, identifier)| *identifier)
+      .collect::<Vec<Identifier>>();
+    identifiers.sort_unstable();
+    identifiers
+  }
+  fn lookup(&self, key: &VariableKey) -> Option<Identifier> {
+    self
+      .scope_locations
+      .scope_locations
+      .get_by_left(key)
+      .copied()
+  }
   pub fn register(&mut self, key: VariableKey) -> Identifier {
     match self.scope_locations.scope_locations.get_by_left(&key) {
       Some(index) => *index,
       None => {
         let index = self.scope.len();
         self.scope.push(None);
+        self.generations.push(0);
         self.scope_locations.scope_locations.insert(key, index);
         index
       }
     }
   }
-  #[inline(always)]
+  #[inline]
+  fn slot(&self, identifier: Identifier) -> Option<&Value> {
+    if self.generations[identifier] == self.generation {
+      self.scope[identifier].as_ref()
+    } else {
+      self.checkpoint.get(identifier)?.as_ref()
+    }
+  }
+  #[inline]
+  fn put(&mut self, identifier: Identifier, value: Option<Value>) {
+    self.scope[identifier] = value;
+    self.generations[identifier] = self.generation;
+  }
+  // Empties the slot, returning what it held
+  fn take(&mut self, identifier: Identifier) -> Option<Value> {
+    let value = self.scope[identifier].take();
+    if core::mem::replace(&mut self.generations[identifier], self.generation) == self.generation {
+      value
+    } else {
+      self.checkpoint.get(identifier).cloned().flatten()
+    }
+  }
+  #[inline]
   fn inner_get(
     &self,
     identifier: Identifier,
     location: Option<&Location>,
-  ) -> Result<Value, LanguageError> {
-    self.scope[identifier].clone().ok_or_else(|| LanguageError {
+  ) -> Result<&Value, LanguageError> {
+    self.slot(identifier).ok_or_else(|| LanguageError {
       error: LanguageErrorType::Reference(
         self
           .scope_locations
@@ -822,18 +2317,42 @@ impl ExecutionContext {
       location: location.cloned(),
     })
   }
-  #[inline(always)]
-  fn get(&self, identifier: Identifier, location: &Location) -> Result<Value, LanguageError> {
+  #[inline]
+  fn get(&self, identifier: Identifier, location: &Location) -> Result<&Value, LanguageError> {
     self.inner_get(identifier, Some(location))
   }
   pub fn unattributed_get(&mut self, identifier: Identifier) -> Result<Value, LanguageError> {
+    self.get_ref(identifier).cloned()
+  }
+  // Like unattributed_get, but borrows the value instead of cloning it
+  pub fn get_ref(&self, identifier: Identifier) -> Result<&Value, LanguageError> {
     self.inner_get(identifier, None)
   }
-  #[inline(always)]
+  // For optional outputs the program may never assign
+  pub fn unattributed_get_or(&self, identifier: Identifier, default: Value) -> Value {
+    self.slot(identifier).cloned().unwrap_or(default)
+  }
+  #[inline]
   pub fn set(&mut self, identifier: Identifier, value: Value) {
-    self.scope[identifier] = Some(value);
+    self.put(identifier, Some(value));
   }
-  #[inline(always)]
+  // Reads a top-level variable by name, None if it isn't set
+  pub fn get_runtime(&self, identifier: &str) -> Option<&Value> {
+    let index = self.lookup(&VariableKey {
+      name: identifier.to_string(),
+      scope: "".to_string(),
+    })?;
+    self.slot(index)
+  }
+  // Every variable that's currently set
+  pub fn variables(&self) -> impl Iterator<Item = (&VariableKey, &Value)> {
+    self
+      .scope_locations
+      .scope_locations
+      .iter()
+      .filter_map(|(key, index)| Some((key, self.slot(*index)?)))
+  }
+  #[inline]
   pub fn set_runtime(&mut self, identifier: &str, value: Value) {
     let index = self.register(VariableKey {
       name: identifier.to_string(),
@@ -841,13 +2360,44 @@ impl ExecutionContext {
     });
     self.set(index, value);
   }
-  #[inline(always)]
+  #[inline]
   pub fn reset(&mut self) {
-    // Reset all values to None
-    self.scope.fill(None);
+    self.checkpoint.clear();
+    self.rewind();
+  }
+  // Makes the variables set so far what rewind() goes back to
+  pub(crate) fn save_checkpoint(&mut self) {
+    self.checkpoint = (0..self.scope.len())
+      .map(|identifier| self.slot(identifier).cloned())
+      .collect();
+    self.rewind();
+  }
+  // Forgets everything set since the last checkpoint
+  #[inline]
+  pub(crate) fn rewind(&mut self) {
+    self.generation += 1;
+  }
+  // Captures every variable's current value, to go back to with restore()
+  pub fn snapshot(&self) -> Snapshot {
+    Snapshot(
+      (0..self.scope.len())
+        .map(|identifier| self.slot(identifier).cloned())
+        .collect(),
+    )
+  }
+  // Puts every variable back the way it was when `snapshot` was taken from
+  // this context. Variables registered since then end up unset
+  pub fn restore(&mut self, Snapshot(values): &Snapshot) {
+    for identifier in 0..self.scope.len() {
+      self.put(identifier, values.get(identifier).cloned().flatten());
+    }
   }
 }
 
+// The values of all of a context's variables, see ExecutionContext::snapshot
+#[derive(Debug, Clone)]
+pub struct Snapshot(Vec<Option<Value>>);
+
 type Identifier = usize;
 #[derive(Debug, Clone)]
 enum ElseBranch {
@@ -887,6 +2437,7 @@ enum ExpressionOp {
   Mul(Box<Expression>, Box<Expression>),
   Sub(Box<Expression>, Box<Expression>),
   Div(Box<Expression>, Box<Expression>),
+  FloorDiv(Box<Expression>, Box<Expression>),
   BinaryAnd(Box<Expression>, Box<Expression>),
   Xor(Box<Expression>, Box<Expression>),
   ShiftLeft(Box<Expression>, Box<Expression>),
@@ -899,16 +2450,32 @@ enum ExpressionOp {
   Equal(Box<Expression>, Box<Expression>),
   NotEqual(Box<Expression>, Box<Expression>),
   NumberLiteral(f32),
+  StringLiteral(Box<str>),
   TupleLiteral(Vec<Expression>),
+  // A tuple literal made only of literals. It's built once per context and
+  // shared after that, rather than allocated every time it's evaluated. The
+  // id is the same for every copy of the same tuple
+  ConstantTuple(usize, Vec<Expression>),
   Reference(Identifier),
   Index(Box<Expression>, Box<Expression>),
   Neg(Box<Expression>),
   Invert(Box<Expression>),
+  // `||` and `&&` short-circuit and give back one of their operands rather
+  // than 1 or 0, so `x || 7` is x unless x is 0. `||` gives the left side
+  // if it's truthy (nonzero), otherwise the right. `&&` gives the right side
+  // if the left is truthy, otherwise 0. Both sides have to be numbers
   Or(Box<Expression>, Box<Expression>),
   And(Box<Expression>, Box<Expression>),
   FunctionCall(FunctionIdentifier, Vec<Expression>),
   Modulo(Box<Expression>, Box<Expression>),
   Pow(Box<Expression>, Box<Expression>),
+  Ternary(Box<Expression>, Box<Expression>, Box<Expression>),
+  // tuple[start:end], either bound may be omitted
+  Slice(
+    Box<Expression>,
+    Option<Box<Expression>>,
+    Option<Box<Expression>>,
+  ),
 }
 #[derive(Debug, Clone)]
 struct IfStatement {
@@ -924,7 +2491,16 @@ enum Statement {
   },
   If(IfStatement),
   Return(Expression),
+  // A function call whose result is thrown away
+  Call(Expression),
   Repeat(RepeatStatement),
+  Break,
+  Continue,
+  ForEach {
+    binding: Identifier,
+    iterable: Expression,
+    body: Vec<Statement>,
+  },
 }
 
 pub type PestError = pest::error::Error<Rule>;
@@ -944,46 +2520,219 @@ impl fmt::Display for ParseError {
   }
 }
 
+impl ParseError {
+  // Pest errors already quote the source, so only language errors need it
+  // added
+  pub fn render(&self, source: &str) -> String {
+    match self {
+      Self::PestError(error) => format!("PestError: {error}"),
+      Self::LanguageError(error) => error.render(source),
+    }
+  }
+}
+
+// The first argument of map and reduce names a function instead of being an
+// expression, so they get parsed separately. None after a recoverable error
+fn parse_higher_order(
+  execution_context: &mut ExecutionContext,
+  scope: String,
+  name: &str,
+  arguments_pairs: Pair<Rule>,
+  argument_pairs_location: Location,
+  state: &ParseState,
+) -> Result<Option<ExpressionOp>, LanguageError> {
+  // Arguments it takes, counting the function, and the function's own arity
+  let (argument_count, arity) = match name {
+    "map" => (2, 1),
+    _ => (3, 2),
+  };
+  let arguments = arguments_pairs.into_inner().collect::<Vec<Pair<Rule>>>();
+  if arguments.len() != argument_count {
+    state.recover(LanguageError {
+      location: Some(argument_pairs_location),
+      error: LanguageErrorType::ArgumentCountMismatch(arguments.len(), argument_count),
+    })?;
+    return Ok(None);
+  }
+  let function = arguments[0].as_str().trim();
+  let Some(prototype) = state.functions.get(function) else {
+    state.recover(LanguageError {
+      location: Some(Location::from(&arguments[0])),
+      error: LanguageErrorType::Reference(function.to_string()),
+    })?;
+    return Ok(None);
+  };
+  if prototype.argument_count != arity {
+    state.recover(LanguageError {
+      location: Some(Location::from(&arguments[0])),
+      error: LanguageErrorType::ArgumentCountMismatch(arity, prototype.argument_count),
+    })?;
+    return Ok(None);
+  }
+  let op = match name {
+    "map" => FunctionIdentifier::Map(prototype.identifier),
+    _ => FunctionIdentifier::Reduce(prototype.identifier),
+  };
+  let arguments = arguments[1..]
+    .iter()
+    .map(|argument| {
+      parse_expression(
+        execution_context,
+        scope.clone(),
+        argument.clone().into_inner(),
+        state,
+      )
+    })
+    .collect::<Result<Vec<Expression>, LanguageError>>()?;
+  Ok(Some(ExpressionOp::FunctionCall(op, arguments)))
+}
+
+// The builtin called `name`, apart from map and reduce which need to know
+// about user-defined functions
+fn builtin_function(name: &str) -> Option<FunctionIdentifier> {
+  Some(match name {
+    "sin" => FunctionIdentifier::Math(MathFunction::Sin),
+    "cos" => FunctionIdentifier::Math(MathFunction::Cos),
+    "tan" => FunctionIdentifier::Math(MathFunction::Tan),
+    "asin" => FunctionIdentifier::Math(MathFunction::Asin),
+    "acos" => FunctionIdentifier::Math(MathFunction::Acos),
+    "atan" => FunctionIdentifier::Math(MathFunction::Atan),
+    "atan2" => FunctionIdentifier::Atan2,
+    "abs" => FunctionIdentifier::Math(MathFunction::Abs),
+    "sqrt" => FunctionIdentifier::Math(MathFunction::Sqrt),
+    "log" => FunctionIdentifier::Log,
+    "floor" => FunctionIdentifier::Math(MathFunction::Floor),
+    "ceil" => FunctionIdentifier::Math(MathFunction::Ceil),
+    "round" => FunctionIdentifier::Math(MathFunction::Round),
+    "mod" => FunctionIdentifier::Mod,
+    "min" => FunctionIdentifier::Min,
+    "max" => FunctionIdentifier::Max,
+    "clamp" => FunctionIdentifier::Clamp,
+    "step" => FunctionIdentifier::Step,
+    "smoothstep" => FunctionIdentifier::Smoothstep,
+    "hsv" => FunctionIdentifier::Hsv,
+    "len" => FunctionIdentifier::Len,
+    "range" => FunctionIdentifier::Range,
+    "sum" => FunctionIdentifier::Sum,
+    "product" => FunctionIdentifier::Product,
+    "reverse" => FunctionIdentifier::Reverse,
+    "index_wrap" => FunctionIdentifier::IndexWrap,
+    "index_clamp" => FunctionIdentifier::IndexClamp,
+    "dot" => FunctionIdentifier::Dot,
+    "magnitude" => FunctionIdentifier::Magnitude,
+    "normalize" => FunctionIdentifier::Normalize,
+    "mix" | "lerp" => FunctionIdentifier::Mix,
+    "quantize" => FunctionIdentifier::Quantize,
+    "dither" => FunctionIdentifier::Dither,
+    "random" => FunctionIdentifier::Random,
+    "noise" => FunctionIdentifier::Noise,
+    "print" => FunctionIdentifier::Print,
+    "assert" => FunctionIdentifier::Assert,
+    _ => return None,
+  })
+}
+
+// Constants, which a variable of the same name takes precedence over
+fn constant(name: &str) -> Option<f32> {
+  match name {
+    "pi" => Some(core::f32::consts::PI),
+    "e" => Some(core::f32::consts::E),
+    "tau" => Some(core::f32::consts::TAU),
+    _ => None,
+  }
+}
+
+// Ids are unique across every program, so a context that runs more than one
+// can't mix up their tuples
+static NEXT_CONSTANT_TUPLE: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) fn next_constant_tuple() -> usize {
+  NEXT_CONSTANT_TUPLE.fetch_add(1, Ordering::Relaxed)
+}
+
+// Spells out a tuple literal's contents, or None if they aren't all literals
+fn constant_key(values: &[Expression]) -> Option<String> {
+  let mut key = String::new();
+  for value in values {
+    match &value.op {
+      ExpressionOp::NumberLiteral(number) => key.push_str(&format!("{},", number.to_bits())),
+      ExpressionOp::StringLiteral(string) => key.push_str(&format!("{string:?},")),
+      ExpressionOp::ConstantTuple(constant, _) => key.push_str(&format!("#{constant},")),
+      _ => return None,
+    }
+  }
+  Some(key)
+}
+
 fn parse_expression(
-  execution_context: Rc<Mutex<ExecutionContext>>,
+  execution_context: &mut ExecutionContext,
   scope: String,
   pairs: Pairs<Rule>,
-  functions: &HashMap<String, FunctionPrototype>,
+  state: &ParseState,
 ) -> Result<Expression, LanguageError> {
-  let execution_context = &execution_context;
-  PRATT_PARSER
+  // Shared between the Pratt parser's closures
+  let execution_context = RefCell::new(execution_context);
+  let ternary = pairs.clone().find(|pair| pair.as_rule() == Rule::ternary);
+  let condition = PRATT_PARSER
     .map_primary(|primary| {
-      let execution_context = execution_context.clone();
       let location = Location::from(&primary);
       let op = match primary.as_rule() {
         Rule::number_literal => {
           ExpressionOp::NumberLiteral(primary.as_str().parse::<f32>().unwrap())
         }
-        Rule::tuple_literal => ExpressionOp::TupleLiteral(
-          primary
+        Rule::string_literal => {
+          let quoted = primary.as_str();
+          ExpressionOp::StringLiteral(
+            quoted[1..quoted.len() - 1]
+              .replace("\\\"", "\"")
+              .replace("\\\\", "\\")
+              .into(),
+          )
+        }
+        Rule::tuple_literal => {
+          let values = primary
             .into_inner()
             .map(|entry| {
               parse_expression(
-                execution_context.clone(),
+                &mut execution_context.borrow_mut(),
                 scope.clone(),
                 entry.into_inner(),
-                functions,
+                state,
               )
             })
-            .collect::<Result<Vec<Expression>, LanguageError>>()?,
-        ),
+            .collect::<Result<Vec<Expression>, LanguageError>>()?;
+          match constant_key(&values) {
+            Some(key) => {
+              let constant = *state
+                .constant_tuples
+                .borrow_mut()
+                .entry(key)
+                .or_insert_with(next_constant_tuple);
+              ExpressionOp::ConstantTuple(constant, values)
+            }
+            None => ExpressionOp::TupleLiteral(values),
+          }
+        }
         Rule::identifier => {
-          ExpressionOp::Reference(execution_context.lock().unwrap().register(VariableKey {
+          let mut execution_context = execution_context.borrow_mut();
+          let key = VariableKey {
             name: primary.as_str().to_string(),
             scope: scope.clone(),
-          }))
+          };
+          // Constants, unless the program already has a variable by that name
+          match constant(&key.name) {
+            Some(number) if execution_context.lookup(&key).is_none() => {
+              ExpressionOp::NumberLiteral(number)
+            }
+            _ => ExpressionOp::Reference(execution_context.register(key)),
+          }
         }
         Rule::expr => {
           parse_expression(
-            execution_context,
+            &mut execution_context.borrow_mut(),
             scope.clone(),
             primary.into_inner(),
-            functions,
+            state,
           )?
           .op
         }
@@ -992,45 +2741,94 @@ fn parse_expression(
           let op_identifier = pairs.next().unwrap();
           let arguments_pairs = pairs.next().unwrap();
           let argument_pairs_location = Location::from(&arguments_pairs);
+          if matches!(op_identifier.as_str(), "map" | "reduce") {
+            let op = parse_higher_order(
+              &mut execution_context.borrow_mut(),
+              scope.clone(),
+              op_identifier.as_str(),
+              arguments_pairs,
+              argument_pairs_location,
+              state,
+            )?;
+            // Stand-in so the rest of the program still gets checked
+            let op = op.unwrap_or(ExpressionOp::NumberLiteral(0.0));
+            return Ok(Expression { op, location });
+          }
           let arguments = arguments_pairs
             .into_inner()
             .map(|expression| {
               parse_expression(
-                execution_context.clone(),
+                &mut execution_context.borrow_mut(),
                 scope.clone(),
                 expression.into_inner(),
-                functions,
+                state,
               )
             })
             .collect::<Result<Vec<Expression>, LanguageError>>()?;
-          let op = match op_identifier.as_str() {
-            "sin" => FunctionIdentifier::Sin,
-            "cos" => FunctionIdentifier::Cos,
-            "tan" => FunctionIdentifier::Tan,
-            "asin" => FunctionIdentifier::Asin,
-            "acos" => FunctionIdentifier::Acos,
-            "atan" => FunctionIdentifier::Atan,
-            "abs" => FunctionIdentifier::Abs,
-            "sqrt" => FunctionIdentifier::Sqrt,
-            "log" => FunctionIdentifier::Log,
-            "len" => FunctionIdentifier::Len,
-            name => {
-              let function = functions.get(name).ok_or_else(|| LanguageError {
-                location: Some(Location::from(&op_identifier)),
-                error: LanguageErrorType::Reference(name.to_string()),
-              })?;
-              if function.argument_count != arguments.len() {
-                return Err(LanguageError {
-                  location: Some(argument_pairs_location),
-                  error: LanguageErrorType::ArgumentCountMismatch(
-                    arguments.len(),
-                    function.argument_count,
-                  ),
-                });
+          let op = match builtin_function(op_identifier.as_str()) {
+            Some(op) => op,
+            None => {
+              let name = op_identifier.as_str();
+              let user_defined = state.functions.get(name).map(|function| {
+                (
+                  FunctionIdentifier::UserDefined(function.identifier),
+                  function.argument_count,
+                )
+              });
+              let host = || {
+                let (identifier, arity) = execution_context.borrow().host_function(name)?;
+                Some((FunctionIdentifier::Host(identifier), arity))
+              };
+              match user_defined.or_else(host) {
+                Some((op, argument_count)) => {
+                  if argument_count != arguments.len() {
+                    state.recover(LanguageError {
+                      location: Some(argument_pairs_location.clone()),
+                      error: LanguageErrorType::ArgumentCountMismatch(
+                        arguments.len(),
+                        argument_count,
+                      ),
+                    })?;
+                  }
+                  op
+                }
+                None => {
+                  state.recover(LanguageError {
+                    location: Some(Location::from(&op_identifier)),
+                    error: LanguageErrorType::Reference(name.to_string()),
+                  })?;
+                  // Stand-in so the rest of the program still gets checked
+                  return Ok(Expression {
+                    op: ExpressionOp::NumberLiteral(0.0),
+                    location,
+                  });
+                }
               }
-              FunctionIdentifier::UserDefined(function.identifier)
             }
           };
+          if !matches!(
+            op,
+            FunctionIdentifier::UserDefined(_) | FunctionIdentifier::Host(_)
+          ) {
+            let expected = op.argument_count();
+            if !expected.contains(&arguments.len()) {
+              state.recover(LanguageError {
+                location: Some(argument_pairs_location),
+                error: LanguageErrorType::ArgumentCountMismatch(
+                  arguments.len(),
+                  if arguments.len() < *expected.start() {
+                    *expected.start()
+                  } else {
+                    *expected.end()
+                  },
+                ),
+              })?;
+              return Ok(Expression {
+                op: ExpressionOp::NumberLiteral(0.0),
+                location,
+              });
+            }
+          }
           ExpressionOp::FunctionCall(op, arguments)
         }
         _ => unreachable!(),
@@ -1052,13 +2850,31 @@ fn parse_expression(
       let op = match op.as_rule() {
         Rule::index => {
           let index: Expression = parse_expression(
-            execution_context.clone(),
+            &mut execution_context.borrow_mut(),
             scope.clone(),
             op.into_inner(),
-            functions,
+            state,
           )?;
           ExpressionOp::Index(Box::new(lhs?), Box::new(index))
         }
+        Rule::slice => {
+          let mut start = None;
+          let mut end = None;
+          for bound in op.into_inner() {
+            let rule = bound.as_rule();
+            let expression = Box::new(parse_expression(
+              &mut execution_context.borrow_mut(),
+              scope.clone(),
+              bound.into_inner().next().unwrap().into_inner(),
+              state,
+            )?);
+            match rule {
+              Rule::slice_start => start = Some(expression),
+              _ => end = Some(expression),
+            }
+          }
+          ExpressionOp::Slice(Box::new(lhs?), start, end)
+        }
         // Rule::fac => (1..(lhs?.try_into()? as i32) + 1).product(),
         _ => unreachable!(),
       };
@@ -1073,6 +2889,7 @@ fn parse_expression(
         Rule::sub => ExpressionOp::Sub(lhs, rhs),
         Rule::mul => ExpressionOp::Mul(lhs, rhs),
         Rule::div => ExpressionOp::Div(lhs, rhs),
+        Rule::floor_div => ExpressionOp::FloorDiv(lhs, rhs),
         Rule::xor => ExpressionOp::Xor(lhs, rhs),
         Rule::bor => ExpressionOp::BinaryOr(lhs, rhs),
         Rule::band => ExpressionOp::BinaryAnd(lhs, rhs),
@@ -1092,25 +2909,88 @@ fn parse_expression(
       };
       Ok(Expression { location, op })
     })
-    .parse(pairs)
+    .parse(pairs.filter(|pair| pair.as_rule() != Rule::ternary))?;
+
+  let Some(ternary) = ternary else {
+    return Ok(condition);
+  };
+  let location = Location::from(&ternary);
+  let mut branches = ternary.into_inner().map(|branch| {
+    parse_expression(
+      &mut execution_context.borrow_mut(),
+      scope.clone(),
+      branch.into_inner(),
+      state,
+    )
+  });
+  let if_true = branches.next().unwrap()?;
+  let if_false = branches.next().unwrap()?;
+  Ok(Expression {
+    location,
+    op: ExpressionOp::Ternary(Box::new(condition), Box::new(if_true), Box::new(if_false)),
+  })
+}
+
+// Builtin functions and constants can't be assigned to, looped over or
+// defined, nor can they name a function's arguments
+fn check_name(name: &Pair<'_, Rule>, state: &ParseState) -> Result<(), LanguageError> {
+  let name_str = name.as_str();
+  if builtin_function(name_str).is_some()
+    || matches!(name_str, "map" | "reduce")
+    || constant(name_str).is_some()
+  {
+    state.recover(LanguageError {
+      location: Some(Location::from(name)),
+      error: LanguageErrorType::ReservedName(name_str.to_string()),
+    })?;
+  }
+  Ok(())
 }
 
 fn parse_statement(
-  execution_context: Rc<Mutex<ExecutionContext>>,
+  execution_context: &mut ExecutionContext,
   scope: String,
   pair: Pair<'_, Rule>,
-  functions: &HashMap<String, FunctionPrototype>,
+  state: &ParseState,
+  in_loop: bool,
 ) -> Result<Statement, LanguageError> {
   // println!("Reading a rule {:?}", pair.as_rule());
   Ok(match pair.as_rule() {
     Rule::assignment_statement => {
       let mut pairs = pair.into_inner();
-      let identifier = execution_context.lock().unwrap().register(VariableKey {
-        name: pairs.next().unwrap().as_str().to_string(),
+      let variable = pairs.next().unwrap();
+      check_name(&variable, state)?;
+      let identifier = execution_context.register(VariableKey {
+        name: variable.as_str().to_string(),
         scope: scope.clone(),
       });
+      let operator = pairs.next().unwrap();
       let expression = pairs.next().unwrap();
-      let value = parse_expression(execution_context, scope, expression.into_inner(), functions)?;
+      let value = parse_expression(execution_context, scope, expression.into_inner(), state)?;
+      // `x += y` is sugar for `x = x + y`
+      let value = match operator.as_rule() {
+        Rule::assign => value,
+        rule => {
+          let lhs = Box::new(Expression {
+            location: Location::from(&variable),
+            op: ExpressionOp::Reference(identifier),
+          });
+          let rhs = Box::new(value);
+          Expression {
+            location: Location::from(&operator),
+            op: match rule {
+              Rule::add_assign => ExpressionOp::Add(lhs, rhs),
+              Rule::sub_assign => ExpressionOp::Sub(lhs, rhs),
+              Rule::mul_assign => ExpressionOp::Mul(lhs, rhs),
+              Rule::div_assign => ExpressionOp::Div(lhs, rhs),
+              Rule::band_assign => ExpressionOp::BinaryAnd(lhs, rhs),
+              Rule::bor_assign => ExpressionOp::BinaryOr(lhs, rhs),
+              Rule::xor_assign => ExpressionOp::Xor(lhs, rhs),
+              _ => unreachable!(),
+            },
+          }
+        }
+      };
       Statement::Assignment {
         variable: identifier,
         value,
@@ -1120,13 +3000,60 @@ fn parse_statement(
       execution_context,
       scope,
       pair,
-      functions,
+      state,
+      in_loop,
     )?),
     Rule::repeat_statement => Statement::Repeat(parse_repeat_statement(
       execution_context,
       scope,
       pair,
-      functions,
+      state,
+    )?),
+    Rule::foreach_statement => {
+      let mut pairs = pair.into_inner();
+      let binding = pairs.next().unwrap();
+      check_name(&binding, state)?;
+      let binding = execution_context.register(VariableKey {
+        name: binding.as_str().to_string(),
+        scope: scope.clone(),
+      });
+      let iterable = parse_expression(
+        execution_context,
+        scope.clone(),
+        pairs.next().unwrap().into_inner(),
+        state,
+      )?;
+      let body = parse_statement_block(
+        execution_context,
+        scope,
+        pairs.next().unwrap().into_inner(),
+        state,
+        true,
+      )?;
+      Statement::ForEach {
+        binding,
+        iterable,
+        body,
+      }
+    }
+    Rule::break_statement | Rule::continue_statement => {
+      let (statement, keyword) = match pair.as_rule() {
+        Rule::break_statement => (Statement::Break, "break"),
+        _ => (Statement::Continue, "continue"),
+      };
+      if !in_loop {
+        state.recover(LanguageError {
+          location: Some(Location::from(&pair)),
+          error: LanguageErrorType::OutsideLoop(keyword),
+        })?;
+      }
+      statement
+    }
+    Rule::call_statement => Statement::Call(parse_expression(
+      execution_context,
+      scope,
+      pair.into_inner(),
+      state,
     )?),
     Rule::return_statement => {
       let mut pairs = pair.into_inner();
@@ -1135,7 +3062,7 @@ fn parse_statement(
         execution_context,
         scope,
         expression.into_inner(),
-        functions,
+        state,
       )?)
     }
     _ => unreachable!(),
@@ -1143,15 +3070,16 @@ fn parse_statement(
 }
 
 fn parse_repeat_statement(
-  execution_context: Rc<Mutex<ExecutionContext>>,
+  execution_context: &mut ExecutionContext,
   scope: String,
   pair: Pair<'_, Rule>,
-  functions: &HashMap<String, FunctionPrototype>,
+  state: &ParseState,
 ) -> Result<RepeatStatement, LanguageError> {
   let mut pairs = pair.into_inner();
-  let variable = pairs.next().unwrap().as_str();
-  let variable = execution_context.lock().unwrap().register(VariableKey {
-    name: variable.to_string(),
+  let variable = pairs.next().unwrap();
+  check_name(&variable, state)?;
+  let variable = execution_context.register(VariableKey {
+    name: variable.as_str().to_string(),
     scope: scope.clone(),
   });
   let times = pairs.next().unwrap().as_str().parse::<f32>().unwrap() as u32;
@@ -1163,7 +3091,8 @@ fn parse_repeat_statement(
       execution_context,
       scope,
       pairs.next().unwrap().into_inner(),
-      functions,
+      state,
+      true,
     )?,
   })
 }
@@ -1176,52 +3105,1643 @@ struct RepeatStatement {
 }
 
 fn parse_if_statement(
-  execution_context: Rc<Mutex<ExecutionContext>>,
+  execution_context: &mut ExecutionContext,
   scope: String,
   pair: Pair<'_, Rule>,
-  functions: &HashMap<String, FunctionPrototype>,
+  state: &ParseState,
+  in_loop: bool,
 ) -> Result<IfStatement, LanguageError> {
   let mut pairs = pair.into_inner();
   let mut if_statement_if = pairs.next().unwrap().into_inner();
   let condition = if_statement_if.next().unwrap().into_inner();
   let if_block = parse_statement_block(
-    execution_context.clone(),
+    execution_context,
     scope.clone(),
     if_statement_if.next().unwrap().into_inner(),
-    functions,
+    state,
+    in_loop,
   )?;
   // println!("Condition: {condition}");
-  let condition = parse_expression(
-    execution_context.clone(),
-    scope.clone(),
-    condition,
-    functions,
-  )?;
+  let condition = parse_expression(execution_context, scope.clone(), condition, state)?;
   Ok(IfStatement {
     condition,
     if_branch: if_block,
     else_branch: match pairs.next() {
       Some(if_statement_else) => {
         let mut if_statement_else = if_statement_else.into_inner();
-        let next_pair = if_statement_else.peek().unwrap();
-        match next_pair.as_rule() {
+        let else_keyword = if_statement_else.next().unwrap();
+        match if_statement_else.next() {
           // else if ...
-          Rule::if_statement => ElseBranch::IfStatement(Box::new(parse_if_statement(
-            execution_context.clone(),
-            scope,
-            if_statement_else.next().unwrap(),
-            functions,
-          )?)),
+          Some(pair) if pair.as_rule() == Rule::if_statement => ElseBranch::IfStatement(Box::new(
+            parse_if_statement(execution_context, scope, pair, state, in_loop)?,
+          )),
           // plain old else
-          _ => ElseBranch::ElseStatement(parse_statement_block(
+          Some(pair) => ElseBranch::ElseStatement(parse_statement_block(
             execution_context,
             scope,
-            if_statement_else.next().unwrap().into_inner(),
-            functions,
+            pair.into_inner(),
+            state,
+            in_loop,
           )?),
+          None => {
+            state.recover(LanguageError {
+              location: Some(Location::from(&else_keyword)),
+              error: LanguageErrorType::DanglingElse,
+            })?;
+            ElseBranch::None
+          }
         }
       }
       None => ElseBranch::None,
     },
   })
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn eval(source: &str) -> Value {
+    eval_expression(&mut ExecutionContext::default(), source).unwrap()
+  }
+
+  fn number(source: &str) -> f32 {
+    eval(source).as_number().unwrap()
+  }
+
+  fn eval_error(source: &str) -> LanguageErrorType {
+    match eval_expression(&mut ExecutionContext::default(), source) {
+      Err(ParseError::LanguageError(err)) => err.error,
+      Err(err) => panic!("{err}"),
+      Ok(value) => panic!("{source} evaluated to {value}"),
+    }
+  }
+
+  fn run(source: &str) -> ExecutionContext {
+    let mut context = ExecutionContext::default();
+    let program = parse_with(&mut context, source).unwrap();
+    Result::from(execute(&mut context, &program)).unwrap();
+    context
+  }
+
+  // What a top-level return in `source` returned
+  fn returned(source: &str) -> Option<f32> {
+    let mut context = ExecutionContext::default();
+    let program = parse_with(&mut context, source).unwrap();
+    execute(&mut context, &program)
+      .into_result()
+      .unwrap()
+      .map(|value| value.as_number().unwrap())
+  }
+
+  fn variable(context: &ExecutionContext, name: &str) -> f32 {
+    context.get_runtime(name).unwrap().as_number().unwrap()
+  }
+
+  fn color(context: &ExecutionContext) -> [f32; 3] {
+    ["r", "g", "b"].map(|name| variable(context, name))
+  }
+
+  #[test]
+  fn pipeline_feeds_a_returned_color_into_a_trailing_expression() {
+    let mut brightness_context = ExecutionContext::default();
+    let brightness = parse_with(
+      &mut brightness_context,
+      "c = x * 2; return [c, c + 10, c + 20]; r = 0; g = 0; b = 0;",
+    )
+    .unwrap();
+    let mut invert_context = ExecutionContext::default();
+    let invert = ParseOptions {
+      implicit_color_output: true,
+    }
+    .parse(&mut invert_context, "255 - r, 255 - g, 255 - b")
+    .unwrap();
+    let mut pipeline = brightness.then(brightness_context, invert, invert_context);
+    pipeline.set_runtime("x", Value::number(50.0));
+    Result::from(pipeline.execute()).unwrap();
+    assert_eq!(color(pipeline.output()), [155.0, 145.0, 135.0]);
+  }
+
+  #[test]
+  fn pipeline_passes_assigned_channels_through() {
+    let mut brightness_context = ExecutionContext::default();
+    let brightness = parse_with(&mut brightness_context, "r = x * 2; g = x; b = 0;").unwrap();
+    let mut invert_context = ExecutionContext::default();
+    let invert = parse_with(
+      &mut invert_context,
+      "r = 255 - r; g = 255 - g; b = 255 - b;",
+    )
+    .unwrap();
+    let mut pipeline = brightness.then(brightness_context, invert, invert_context);
+    pipeline.set_runtime("x", Value::number(50.0));
+    Result::from(pipeline.execute()).unwrap();
+    assert_eq!(color(pipeline.output()), [155.0, 205.0, 255.0]);
+  }
+
+  #[test]
+  fn quantize_snaps_to_the_nearest_band() {
+    // Bands at 0, 85, 170 and 255
+    assert_eq!(number("quantize(200, 4)"), 170.0);
+    assert_eq!(number("quantize(230, 4)"), 255.0);
+  }
+
+  #[test]
+  fn dither_depends_on_the_coordinates() {
+    assert_eq!(number("dither(128, 0, 0, 2)"), 0.0);
+    assert_eq!(number("dither(128, 1, 0, 2)"), 255.0);
+  }
+
+  #[test]
+  fn reading_tuple_elements_leaves_the_tuple_alone() {
+    let context = run("c = [10, 20, 30]; r = c[0] + c[1] * 2; g = c[2] - r; b = len(c);");
+    assert_eq!(color(&context), [50.0, -20.0, 3.0]);
+    assert_eq!(
+      context.get_runtime("c").unwrap().to_string(),
+      eval("[10, 20, 30]").to_string()
+    );
+  }
+
+  #[test]
+  fn for_each_visits_every_element() {
+    let context = run("s = 0; n = 0; for v in [1, 2, 3] { s += v; n += 1; }");
+    assert_eq!(variable(&context, "s"), 6.0);
+    assert_eq!(variable(&context, "n"), 3.0);
+  }
+
+  #[test]
+  fn return_inside_for_each_leaves_the_program() {
+    assert_eq!(
+      returned("for v in [1, 5, 9] { if (v > 4) { return v; } } return 0;"),
+      Some(5.0)
+    );
+  }
+
+  #[test]
+  fn break_and_continue_only_affect_the_inner_loop() {
+    let context =
+      run("n = 0; repeat (i until 3) { repeat (j until 5) { if (j == 2) { break; } n += 1; } }");
+    assert_eq!(variable(&context, "n"), 6.0);
+    let context = run(
+      "n = 0; for i in [1, 2, 3] { for j in [1, 2, 3, 4] { if (j == 2) { continue; } n += 1; } }",
+    );
+    assert_eq!(variable(&context, "n"), 9.0);
+  }
+
+  #[test]
+  fn break_outside_a_loop_is_an_error() {
+    let mut context = ExecutionContext::default();
+    for source in ["break;", "function f() { continue; } r = f();"] {
+      assert!(matches!(
+        parse_with(&mut context, source),
+        Err(ParseError::LanguageError(LanguageError {
+          error: LanguageErrorType::OutsideLoop(_),
+          ..
+        }))
+      ));
+    }
+    assert!(matches!(
+      ScopeFlow::Break.into_result(),
+      Err(LanguageError {
+        error: LanguageErrorType::OutsideLoop("break"),
+        ..
+      })
+    ));
+  }
+
+  #[test]
+  fn floor_ceil_and_round() {
+    assert_eq!(number("floor(2.7)"), 2.0);
+    assert_eq!(number("ceil(2.1)"), 3.0);
+    assert_eq!(number("round(2.5)"), 3.0);
+    assert_eq!(number("floor(-2.5)"), -3.0);
+    assert!(matches!(
+      eval_error("floor([1, 2])"),
+      LanguageErrorType::Type(ValueType::Number, _)
+    ));
+  }
+
+  #[test]
+  fn min_and_max_take_any_number_of_arguments() {
+    assert_eq!(number("min(3, 1, 2)"), 1.0);
+    assert_eq!(number("max(3, 1, 2)"), 3.0);
+    assert_eq!(number("max(4)"), 4.0);
+    assert!(matches!(
+      eval_error("min()"),
+      LanguageErrorType::ArgumentCountMismatch(0, 1)
+    ));
+    assert!(matches!(
+      eval_error("max(1, [2])"),
+      LanguageErrorType::Type(ValueType::Number, _)
+    ));
+  }
+
+  #[test]
+  fn clamp_takes_exactly_three_arguments() {
+    assert_eq!(number("clamp(300, 0, 255)"), 255.0);
+    assert_eq!(number("clamp(-1, 0, 255)"), 0.0);
+    assert_eq!(number("clamp(7, 0, 255)"), 7.0);
+    assert!(matches!(
+      eval_error("clamp(1, 2)"),
+      LanguageErrorType::ArgumentCountMismatch(2, 3)
+    ));
+  }
+
+  #[test]
+  fn atan2_covers_every_quadrant() {
+    assert_eq!(number("atan2(1, 0)"), core::f32::consts::FRAC_PI_2);
+    assert_eq!(number("atan2(0, -1)"), core::f32::consts::PI);
+    assert_eq!(number("atan2(-1, 0)"), -core::f32::consts::FRAC_PI_2);
+    assert!(matches!(
+      eval_error("atan2(1)"),
+      LanguageErrorType::ArgumentCountMismatch(1, 2)
+    ));
+  }
+
+  #[test]
+  fn log_is_natural_unless_given_a_base() {
+    assert!((number("log(e)") - 1.0).abs() < 1e-6);
+    assert_eq!(number("log(1)"), 0.0);
+    assert_eq!(number("log(8, 2)"), 3.0);
+    assert!((number("log(1000, 10)") - 3.0).abs() < 1e-6);
+  }
+
+  #[test]
+  fn pi_e_and_tau_are_constants() {
+    let context = run("r = sin(pi); g = tau / pi; b = log(e);");
+    let [r, g, b] = color(&context);
+    assert!(r.abs() < 1e-6);
+    assert_eq!(g, 2.0);
+    assert!((b - 1.0).abs() < 1e-6);
+  }
+
+  #[test]
+  fn host_variable_shadows_a_constant() {
+    let mut context = ExecutionContext::default();
+    context.set_runtime("pi", Value::number(3.0));
+    assert_eq!(
+      eval_expression(&mut context, "pi * 2").unwrap().as_number(),
+      Some(6.0)
+    );
+  }
+
+  #[test]
+  fn ternary_nests_to_the_right_and_skips_the_other_branch() {
+    assert_eq!(number("1 ? 2 : 0 ? 3 : 4"), 2.0);
+    assert_eq!(number("0 ? 2 : 1 ? 3 : 4"), 3.0);
+    assert_eq!(number("0 ? 2 : 0 ? 3 : 4"), 4.0);
+    assert_eq!(number("1 ? 2 : [1][5]"), 2.0);
+    assert_eq!(number("0 ? [1][5] : 3"), 3.0);
+  }
+
+  #[test]
+  fn comments_are_skipped() {
+    let commented = run(
+      "// Leading comment\nr = 1; # hash comment\n/* block\ncomment */ g = 2; // trailing\nb = /* inline */ 3;",
+    );
+    let stripped = run("r = 1; g = 2; b = 3;");
+    assert_eq!(color(&commented), color(&stripped));
+  }
+
+  #[test]
+  fn power_is_right_associative() {
+    assert_eq!(number("2 ** 3 ** 2"), 512.0);
+    assert_eq!(number("(2 ** 3) ** 2"), 64.0);
+    // ^ stays xor
+    assert_eq!(number("6 ^ 3"), 5.0);
+  }
+
+  #[test]
+  fn comparisons_point_the_right_way() {
+    for (source, expected) in [
+      ("1 < 2", 1.0),
+      ("2 < 1", 0.0),
+      ("2 > 1", 1.0),
+      ("1 > 2", 0.0),
+      ("2 <= 2", 1.0),
+      ("3 <= 2", 0.0),
+      ("2 >= 2", 1.0),
+      ("1 >= 2", 0.0),
+      ("1 != 2", 1.0),
+      ("2 != 2", 0.0),
+    ] {
+      assert_eq!(number(source), expected, "{source}");
+    }
+  }
+
+  #[test]
+  fn floor_division_rounds_down() {
+    assert_eq!(number("7 ~/ 2"), 3.0);
+    assert_eq!(number("-7 ~/ 2"), -4.0);
+    assert_eq!(number("1 ~/ 0"), f32::INFINITY);
+    assert!(number("0 ~/ 0").is_nan());
+  }
+
+  #[test]
+  fn recursion_computes_factorials() {
+    let context =
+      run("function fact(n) { if (n <= 1) { return 1; } return n * fact(n - 1); } r = fact(10);");
+    assert_eq!(variable(&context, "r"), 3628800.0);
+  }
+
+  // Still has work to do after each call returns, so none of it can be
+  // turned into a loop
+  const RUNAWAY: [&str; 2] = [
+    "function f(n) { return f(n + 1) + 0; } r = f(0);",
+    "function f(n) { return ((((f(n + 1) + 0) * 1) + 0) * 1); } r = f(0);",
+  ];
+
+  // A recursive call `nesting` levels deep in `shape`, which has a {} for
+  // where the call goes
+  fn nested_recursion(shape: &str, nesting: usize) -> String {
+    let mut call = String::from("f(n - 1)");
+    for _ in 0..nesting {
+      call = shape.replace("{}", &call);
+    }
+    format!("function f(n) {{ if (n < 1) {{ return 0; }} return {call}; }} r = f(100000);")
+  }
+
+  fn recursion_error(context: &mut ExecutionContext, source: &str) -> LanguageErrorType {
+    let program = parse_with(context, source).unwrap();
+    execute(context, &program).into_result().unwrap_err().error
+  }
+
+  #[test]
+  fn runaway_recursion_is_an_error_instead_of_a_stack_overflow() {
+    let thread = std::thread::Builder::new().stack_size(STACK_SIZE);
+    // LanguageError can't leave the thread, so just send back the limit hit
+    let limits = thread.spawn(|| {
+      RUNAWAY.map(
+        |source| match recursion_error(&mut ExecutionContext::default(), source) {
+          LanguageErrorType::RecursionLimit(limit) => Some(limit),
+          _ => None,
+        },
+      )
+    });
+    let limits = limits.unwrap().join().unwrap();
+    assert_eq!(limits, [Some(DEFAULT_MAX_CALL_DEPTH); 2]);
+  }
+
+  #[test]
+  fn recursion_inside_deeply_nested_expressions_is_an_error_too() {
+    let thread = std::thread::Builder::new().stack_size(STACK_SIZE);
+    let limits = thread.spawn(|| {
+      let mut limits = Vec::new();
+      for shape in ["(1 + {})", "sin({})", "sum([{}, 1])", "map(g, [{}])[0]"] {
+        for nesting in [1, 16, 18, 64, 200] {
+          let source =
+            "function g(v) { return v; }".to_string() + &nested_recursion(shape, nesting);
+          limits.push(
+            match recursion_error(&mut ExecutionContext::default(), &source) {
+              LanguageErrorType::RecursionLimit(limit) => Some(limit),
+              _ => None,
+            },
+          );
+        }
+      }
+      limits
+    });
+    let limits = limits.unwrap().join().unwrap();
+    assert_eq!(limits, [Some(DEFAULT_MAX_CALL_DEPTH); 20]);
+  }
+
+  #[test]
+  fn shallow_recursion_inside_nested_expressions_still_runs() {
+    let thread = std::thread::Builder::new().stack_size(STACK_SIZE);
+    let source = nested_recursion("(1 + {})", 16).replace("100000", "20");
+    let r = thread.spawn(move || variable(&run(&source), "r"));
+    assert_eq!(r.unwrap().join().unwrap(), 320.0);
+  }
+
+  #[test]
+  fn call_depth_fits_a_smaller_stack() {
+    // The test harness's threads have 2MiB
+    for source in RUNAWAY {
+      let mut context = ExecutionContext::default();
+      context.fit_call_depth_to_stack(2 << 20);
+      assert!(matches!(
+        recursion_error(&mut context, source),
+        LanguageErrorType::RecursionLimit(_)
+      ));
+    }
+  }
+
+  fn located_error(source: &str) -> LanguageError {
+    match eval_expression(&mut ExecutionContext::default(), source) {
+      Err(ParseError::LanguageError(err)) => err,
+      Err(err) => panic!("{err}"),
+      Ok(value) => panic!("{source} evaluated to {value}"),
+    }
+  }
+
+  fn columns(err: &LanguageError) -> (usize, usize) {
+    let location = err.location.as_ref().unwrap();
+    (location.start_column, location.end_column)
+  }
+
+  #[test]
+  fn slices_copy_the_range_between_their_bounds() {
+    assert_eq!(
+      eval("[1, 2, 3, 4][1:3]").to_string(),
+      eval("[2, 3]").to_string()
+    );
+    assert_eq!(
+      eval("[1, 2, 3][:2]").to_string(),
+      eval("[1, 2]").to_string()
+    );
+    assert_eq!(
+      eval("[1, 2, 3][1:]").to_string(),
+      eval("[2, 3]").to_string()
+    );
+    assert_eq!(
+      eval("[1, 2, 3][:]").to_string(),
+      eval("[1, 2, 3]").to_string()
+    );
+    assert_eq!(eval("[1, 2, 3][3:]").as_tuple().unwrap().len(), 0);
+  }
+
+  #[test]
+  fn reversed_slice_bounds_are_empty() {
+    assert_eq!(eval("[1, 2, 3][2:1]").as_tuple().unwrap().len(), 0);
+    assert_eq!(eval("[1, 2, 3][3:0]").as_tuple().unwrap().len(), 0);
+  }
+
+  #[test]
+  fn index_out_of_bounds() {
+    let err = located_error("[1, 2, 3][3]");
+    assert!(matches!(
+      err.error,
+      LanguageErrorType::Range(TupleAccess::Index, 3.0, 3)
+    ));
+    assert_eq!(columns(&err), (11, 12));
+    assert_eq!(
+      err.error.to_string(),
+      "RangeError: Index 3 out of bounds for tuple of length 3"
+    );
+  }
+
+  #[test]
+  fn negative_index_is_a_range_error() {
+    let err = located_error("[1, 2, 3][0 - 1]");
+    assert!(matches!(
+      err.error,
+      LanguageErrorType::Range(TupleAccess::Index, -1.0, 3)
+    ));
+    assert_eq!(
+      err.error.to_string(),
+      "RangeError: Index -1 out of bounds for tuple of length 3"
+    );
+  }
+
+  #[test]
+  fn fractional_index_is_a_range_error() {
+    let err = located_error("[1, 2, 3][1.5]");
+    assert!(matches!(
+      err.error,
+      LanguageErrorType::Range(TupleAccess::Index, 1.5, 3)
+    ));
+    assert_eq!(
+      err.error.to_string(),
+      "RangeError: Index 1.5 is not a whole number"
+    );
+  }
+
+  #[test]
+  fn slice_start_out_of_bounds() {
+    let err = located_error("[1, 2, 3][4:]");
+    assert!(matches!(
+      err.error,
+      LanguageErrorType::Range(TupleAccess::SliceStart, 4.0, 3)
+    ));
+    assert_eq!(columns(&err), (11, 12));
+    assert_eq!(
+      err.error.to_string(),
+      "RangeError: Slice start 4 out of bounds for tuple of length 3"
+    );
+  }
+
+  #[test]
+  fn negative_slice_start_is_a_range_error() {
+    let err = located_error("[1, 2, 3][0 - 2:2]");
+    assert!(matches!(
+      err.error,
+      LanguageErrorType::Range(TupleAccess::SliceStart, -2.0, 3)
+    ));
+    assert_eq!(
+      err.error.to_string(),
+      "RangeError: Slice start -2 out of bounds for tuple of length 3"
+    );
+  }
+
+  #[test]
+  fn fractional_slice_start_is_a_range_error() {
+    let err = located_error("[1, 2, 3][0.5:2]");
+    assert!(matches!(
+      err.error,
+      LanguageErrorType::Range(TupleAccess::SliceStart, 0.5, 3)
+    ));
+    assert_eq!(
+      err.error.to_string(),
+      "RangeError: Slice start 0.5 is not a whole number"
+    );
+  }
+
+  #[test]
+  fn slice_end_out_of_bounds() {
+    let err = located_error("[1, 2, 3][1:5]");
+    assert!(matches!(
+      err.error,
+      LanguageErrorType::Range(TupleAccess::SliceEnd, 5.0, 3)
+    ));
+    assert_eq!(columns(&err), (13, 14));
+    assert_eq!(
+      err.error.to_string(),
+      "RangeError: Slice end 5 out of bounds for tuple of length 3"
+    );
+  }
+
+  #[test]
+  fn negative_slice_end_is_a_range_error() {
+    let err = located_error("[1, 2, 3][:0 - 1]");
+    assert!(matches!(
+      err.error,
+      LanguageErrorType::Range(TupleAccess::SliceEnd, -1.0, 3)
+    ));
+    assert_eq!(
+      err.error.to_string(),
+      "RangeError: Slice end -1 out of bounds for tuple of length 3"
+    );
+  }
+
+  #[test]
+  fn fractional_slice_end_is_a_range_error() {
+    let err = located_error("[1, 2, 3][:2.5]");
+    assert!(matches!(
+      err.error,
+      LanguageErrorType::Range(TupleAccess::SliceEnd, 2.5, 3)
+    ));
+    assert_eq!(
+      err.error.to_string(),
+      "RangeError: Slice end 2.5 is not a whole number"
+    );
+  }
+
+  #[test]
+  fn or_returns_the_first_true_operand() {
+    assert_eq!(number("3 || 0"), 3.0);
+    assert_eq!(number("3 || 4"), 3.0);
+    assert_eq!(number("0 || 5"), 5.0);
+    assert_eq!(number("0 || 0"), 0.0);
+    assert_eq!(number("0 - 2 || 5"), -2.0);
+  }
+
+  #[test]
+  fn and_returns_the_second_operand_when_the_first_is_true() {
+    assert_eq!(number("2 && 7"), 7.0);
+    assert_eq!(number("2 && 0"), 0.0);
+    assert_eq!(number("0 && 7"), 0.0);
+    assert_eq!(number("0 && 0"), 0.0);
+  }
+
+  #[test]
+  fn and_or_skip_the_right_hand_side_when_the_left_decides() {
+    assert_eq!(number("1 || assert(0)"), 1.0);
+    assert_eq!(number("0 && assert(0)"), 0.0);
+    assert!(matches!(
+      eval_error("0 || assert(0)"),
+      LanguageErrorType::AssertionFailed
+    ));
+    assert!(matches!(
+      eval_error("1 && assert(0)"),
+      LanguageErrorType::AssertionFailed
+    ));
+  }
+
+  #[test]
+  fn and_or_reject_tuples() {
+    for source in ["[1, 2] && 1", "[1, 2] || 1", "1 && [1, 2]", "0 || [1, 2]"] {
+      let err = located_error(source);
+      assert!(
+        matches!(
+          err.error,
+          LanguageErrorType::Type(ValueType::Number, Value::Tuple(_))
+        ),
+        "{source}"
+      );
+      let tuple_start = source.find('[').unwrap() + 1;
+      assert_eq!(columns(&err), (tuple_start, tuple_start + 6), "{source}");
+    }
+  }
+
+  #[test]
+  fn rewind_goes_back_to_the_checkpoint_and_reset_clears_it() {
+    let mut context = ExecutionContext::default();
+    context.set_runtime("a", Value::number(1.0));
+    context.save_checkpoint();
+    context.set_runtime("a", Value::number(2.0));
+    context.set_runtime("b", Value::number(3.0));
+    assert_eq!(variable(&context, "b"), 3.0);
+    context.rewind();
+    assert_eq!(variable(&context, "a"), 1.0);
+    assert!(context.get_runtime("b").is_none());
+    context.reset();
+    assert!(context.get_runtime("a").is_none());
+  }
+
+  fn numbers(source: &str) -> Vec<f32> {
+    eval(source)
+      .as_tuple()
+      .unwrap()
+      .iter()
+      .map(|value| value.as_number().unwrap())
+      .collect()
+  }
+
+  #[test]
+  fn arithmetic_on_two_tuples_is_elementwise() {
+    assert_eq!(numbers("[1, 2] + [3, 4]"), [4.0, 6.0]);
+    assert_eq!(numbers("[5, 5] - [1, 2]"), [4.0, 3.0]);
+    assert_eq!(numbers("[1, 2] * [3, 4]"), [3.0, 8.0]);
+    assert_eq!(numbers("[6, 8] / [2, 4]"), [3.0, 2.0]);
+  }
+
+  #[test]
+  fn arithmetic_with_a_number_broadcasts_it() {
+    assert_eq!(numbers("[1, 2] * 2"), [2.0, 4.0]);
+    assert_eq!(numbers("2 * [1, 2]"), [2.0, 4.0]);
+    assert_eq!(numbers("10 - [1, 2]"), [9.0, 8.0]);
+    assert_eq!(numbers("[1, 2] - 10"), [-9.0, -8.0]);
+    assert_eq!(numbers("[2, 4] / 2"), [1.0, 2.0]);
+    assert_eq!(
+      eval("[[1, 2], 3] + 1").to_string(),
+      eval("[[2, 3], 4]").to_string()
+    );
+  }
+
+  #[test]
+  fn tuples_of_different_lengths_do_not_combine() {
+    let err = located_error("[1, 2] + [1, 2, 3]");
+    assert!(matches!(err.error, LanguageErrorType::LengthMismatch(2, 3)));
+    // Points at the operator
+    assert_eq!(columns(&err), (8, 9));
+    assert!(matches!(
+      eval_error("[1, [2, 3]] * [1, [2]]"),
+      LanguageErrorType::LengthMismatch(2, 1)
+    ));
+  }
+
+  #[test]
+  fn strings_are_not_arithmetic() {
+    assert!(matches!(
+      eval_error("\"a\" + 1"),
+      LanguageErrorType::Type(ValueType::Number, Value::Str(_))
+    ));
+    assert!(matches!(
+      eval_error("[1, 2] * \"a\""),
+      LanguageErrorType::Type(ValueType::Number, Value::Str(_))
+    ));
+  }
+
+  #[test]
+  fn dot_sums_elementwise_products() {
+    assert_eq!(number("dot([1, 2], [3, 4])"), 11.0);
+    assert_eq!(number("dot([1, 2, 3], [4, 5, 6])"), 32.0);
+    assert_eq!(number("dot([1, 0], [0, 1])"), 0.0);
+    assert_eq!(number("dot([], [])").to_bits(), 0.0f32.to_bits());
+  }
+
+  #[test]
+  fn dot_needs_two_tuples_of_numbers_the_same_length() {
+    let err = located_error("dot([1, 2], [1, 2, 3])");
+    assert!(matches!(err.error, LanguageErrorType::LengthMismatch(2, 3)));
+    assert_eq!(columns(&err), (1, 23));
+    let err = located_error("dot([1, [2]], [1, 2])");
+    assert!(matches!(
+      err.error,
+      LanguageErrorType::Type(ValueType::Number, Value::Tuple(_))
+    ));
+    assert_eq!(columns(&err), (5, 13));
+    assert!(matches!(
+      eval_error("dot(1, [1])"),
+      LanguageErrorType::Type(ValueType::Tuple, Value::Number(_))
+    ));
+  }
+
+  #[test]
+  fn one_number_builtins_apply_their_function() {
+    for (name, function) in [
+      ("sin", f32::sin as fn(f32) -> f32),
+      ("cos", f32::cos),
+      ("tan", f32::tan),
+      ("asin", f32::asin),
+      ("acos", f32::acos),
+      ("atan", f32::atan),
+      ("abs", f32::abs),
+      ("sqrt", f32::sqrt),
+      ("floor", f32::floor),
+      ("ceil", f32::ceil),
+      ("round", f32::round),
+    ] {
+      for value in [-0.75, 0.3, 0.5] {
+        let expected = function(value);
+        let found = number(&format!("{name}({value})"));
+        assert!(
+          found == expected || (found.is_nan() && expected.is_nan()),
+          "{name}({value}) was {found}, not {expected}"
+        );
+      }
+    }
+  }
+
+  #[test]
+  fn magnitude_of_a_3_4_5_triangle() {
+    assert_eq!(number("magnitude([3, 4])"), 5.0);
+    assert_eq!(number("magnitude([0 - 3, 4])"), 5.0);
+    assert_eq!(number("magnitude([2, 3, 6])"), 7.0);
+    assert_eq!(number("magnitude([])"), 0.0);
+  }
+
+  #[test]
+  fn normalize_scales_to_length_one() {
+    assert_eq!(numbers("normalize([3, 4])"), [0.6, 0.8]);
+    assert_eq!(numbers("normalize([0, 0, 2])"), [0.0, 0.0, 1.0]);
+    assert!((number("magnitude(normalize([1, 2, 3]))") - 1.0).abs() < 1e-6);
+  }
+
+  #[test]
+  fn normalize_and_magnitude_errors() {
+    let err = located_error("normalize([0, 0])");
+    assert!(matches!(err.error, LanguageErrorType::NormalizeZero));
+    assert_eq!(columns(&err), (11, 17));
+    assert!(matches!(
+      eval_error("magnitude(5)"),
+      LanguageErrorType::Type(ValueType::Tuple, Value::Number(_))
+    ));
+    assert!(matches!(
+      eval_error("normalize(5)"),
+      LanguageErrorType::Type(ValueType::Tuple, Value::Number(_))
+    ));
+  }
+
+  #[test]
+  fn mix_interpolates_numbers_without_clamping() {
+    assert_eq!(number("mix(0, 10, 0.5)"), 5.0);
+    assert_eq!(number("lerp(0, 10, 0.5)"), 5.0);
+    assert_eq!(number("mix(10, 20, 0)"), 10.0);
+    assert_eq!(number("mix(10, 20, 1)"), 20.0);
+    assert_eq!(number("mix(0, 10, 2)"), 20.0);
+    assert_eq!(number("mix(0, 10, 0 - 1)"), -10.0);
+  }
+
+  #[test]
+  fn mix_blends_colors_per_channel() {
+    assert_eq!(
+      numbers("mix([255, 0, 100], [0, 255, 100], 0.2)"),
+      [204.0, 51.0, 100.0]
+    );
+    // A tuple of weights mixes each channel by its own amount
+    assert_eq!(
+      numbers("mix([0, 0, 0], [100, 200, 40], [0.5, 0.25, 1])"),
+      [50.0, 50.0, 40.0]
+    );
+  }
+
+  #[test]
+  fn mix_takes_exactly_three_arguments_of_matching_lengths() {
+    assert!(matches!(
+      parse_with(&mut ExecutionContext::default(), "r = mix(1, 2);").unwrap_err(),
+      ParseError::LanguageError(LanguageError {
+        error: LanguageErrorType::ArgumentCountMismatch(2, 3),
+        ..
+      })
+    ));
+    assert!(matches!(
+      eval_error("mix([1, 2], [1, 2, 3], 0.5)"),
+      LanguageErrorType::LengthMismatch(2, 3)
+    ));
+  }
+
+  #[test]
+  fn step_is_one_from_the_edge_up() {
+    assert_eq!(number("step(0.5, 0.4)"), 0.0);
+    assert_eq!(number("step(0.5, 0.5)"), 1.0);
+    assert_eq!(number("step(0.5, 0.6)"), 1.0);
+  }
+
+  #[test]
+  fn smoothstep_eases_between_its_edges() {
+    assert_eq!(number("smoothstep(0, 10, 0 - 5)"), 0.0);
+    assert_eq!(number("smoothstep(0, 10, 0)"), 0.0);
+    assert_eq!(number("smoothstep(0, 10, 5)"), 0.5);
+    assert_eq!(number("smoothstep(0, 10, 10)"), 1.0);
+    assert_eq!(number("smoothstep(0, 10, 15)"), 1.0);
+    // 3t² - 2t³ at t = 0.25
+    assert_eq!(number("smoothstep(0, 4, 1)"), 0.15625);
+    // Equal edges make a hard edge instead of dividing by zero
+    assert_eq!(number("smoothstep(2, 2, 1)"), 0.0);
+    assert_eq!(number("smoothstep(2, 2, 2)"), 1.0);
+  }
+
+  #[test]
+  fn step_and_smoothstep_argument_counts() {
+    for (source, found, expected) in [
+      ("r = step(1);", 1, 2),
+      ("r = step(1, 2, 3);", 3, 2),
+      ("r = smoothstep(1, 2);", 2, 3),
+    ] {
+      match parse_with(&mut ExecutionContext::default(), source) {
+        Err(ParseError::LanguageError(LanguageError {
+          error: LanguageErrorType::ArgumentCountMismatch(actual_found, actual_expected),
+          ..
+        })) => assert_eq!(
+          (actual_found, actual_expected),
+          (found, expected),
+          "{source}"
+        ),
+        _ => panic!("{source} should have the wrong number of arguments"),
+      }
+    }
+  }
+
+  #[test]
+  fn hsv_primaries_and_grays() {
+    assert_eq!(numbers("hsv(0, 1, 1)"), [255.0, 0.0, 0.0]);
+    assert_eq!(numbers("hsv(120, 1, 1)"), [0.0, 255.0, 0.0]);
+    assert_eq!(numbers("hsv(240, 1, 1)"), [0.0, 0.0, 255.0]);
+    assert_eq!(numbers("hsv(60, 1, 1)"), [255.0, 255.0, 0.0]);
+    assert_eq!(numbers("hsv(300, 1, 1)"), [255.0, 0.0, 255.0]);
+    // No saturation is gray whatever the hue
+    assert_eq!(numbers("hsv(200, 0, 0.5)"), [127.5, 127.5, 127.5]);
+    assert_eq!(numbers("hsv(0, 0, 0)"), [0.0, 0.0, 0.0]);
+  }
+
+  #[test]
+  fn hsv_hue_is_in_degrees_and_wraps() {
+    assert_eq!(numbers("hsv(360, 1, 1)"), [255.0, 0.0, 0.0]);
+    assert_eq!(numbers("hsv(480, 1, 1)"), [0.0, 255.0, 0.0]);
+    assert_eq!(numbers("hsv(0 - 120, 1, 1)"), [0.0, 0.0, 255.0]);
+    assert_eq!(numbers("hsv(30, 1, 1)"), [255.0, 127.5, 0.0]);
+  }
+
+  #[test]
+  fn hsv_sets_the_color_through_indexing() {
+    let context = run("c = hsv(240, 1, 0.5); r = c[0]; g = c[1]; b = c[2];");
+    assert_eq!(color(&context), [0.0, 0.0, 127.5]);
+  }
+
+  // noise() promises the same values everywhere, so pin a few exactly
+  #[test]
+  fn noise_is_pinned() {
+    for (source, bits) in [
+      ("noise(0, 0)", 0x3f2e6f80),
+      ("noise(3, 7)", 0x3f798b6d),
+      ("noise(0.5, 0.5)", 0x3f394ec0),
+      ("noise(12.25, 0 - 3.75)", 0x3f000a89),
+    ] {
+      assert_eq!(number(source).to_bits(), bits, "{source}");
+    }
+  }
+
+  #[test]
+  fn noise_matches_random_on_the_grid() {
+    assert_eq!(number("noise(3, 7)"), number("random(3, 7)"));
+    assert_eq!(number("noise(0 - 2, 5)"), number("random(0 - 2, 5)"));
+  }
+
+  #[test]
+  fn noise_is_continuous_and_in_range() {
+    // Corners are less than 1 apart and the fade is at most 1.5 steep, so a
+    // step of 0.01 along both axes moves it by at most 0.03
+    let step = 0.01;
+    for row in 0..200 {
+      let y = row as f32 * 0.037 - 3.0;
+      for column in 0..400 {
+        let x = column as f32 * step - 2.0;
+        let value = noise(x, y);
+        let next = noise(x + step, y + step);
+        assert!((0.0..1.0).contains(&value), "noise({x}, {y}) = {value}");
+        assert!(
+          (next - value).abs() <= 0.03,
+          "noise jumped from {value} to {next} after ({x}, {y})"
+        );
+      }
+    }
+  }
+
+  fn parse_error(source: &str) -> LanguageErrorType {
+    match parse_with(&mut ExecutionContext::default(), source) {
+      Err(ParseError::LanguageError(err)) => err.error,
+      Err(err) => panic!("{err}"),
+      Ok(_) => panic!("{source} parsed"),
+    }
+  }
+
+  fn tuple_variable(context: &ExecutionContext, name: &str) -> Vec<f32> {
+    context
+      .get_runtime(name)
+      .unwrap()
+      .as_tuple()
+      .unwrap()
+      .iter()
+      .map(|value| value.as_number().unwrap())
+      .collect()
+  }
+
+  #[test]
+  fn map_doubles_every_element() {
+    let context = run(
+      "function double(v) { return v * 2; }
+      t = map(double, [1, 2, 3]);
+      empty = map(double, []);",
+    );
+    assert_eq!(tuple_variable(&context, "t"), [2.0, 4.0, 6.0]);
+    assert_eq!(tuple_variable(&context, "empty"), []);
+  }
+
+  #[test]
+  fn map_leaves_its_tuple_alone() {
+    let context = run(
+      "function square(v) { return v * v; }
+      t = [1, 2, 3];
+      squares = map(square, t);",
+    );
+    assert_eq!(tuple_variable(&context, "t"), [1.0, 2.0, 3.0]);
+    assert_eq!(tuple_variable(&context, "squares"), [1.0, 4.0, 9.0]);
+  }
+
+  #[test]
+  fn map_needs_a_one_argument_user_function() {
+    assert!(matches!(
+      parse_error("function add(a, b) { return a + b; } t = map(add, [1]);"),
+      LanguageErrorType::ArgumentCountMismatch(1, 2)
+    ));
+    assert!(matches!(
+      parse_error("t = map(missing, [1]);"),
+      LanguageErrorType::Reference(name) if name == "missing"
+    ));
+    assert!(matches!(
+      parse_error("t = map(sin, [1]);"),
+      LanguageErrorType::Reference(name) if name == "sin"
+    ));
+    assert!(matches!(
+      parse_error("function f(v) { return v; } t = map(f);"),
+      LanguageErrorType::ArgumentCountMismatch(1, 2)
+    ));
+  }
+
+  #[test]
+  fn reduce_folds_sums_and_products() {
+    let context = run(
+      "function add(a, b) { return a + b; }
+      function multiply(a, b) { return a * b; }
+      s = reduce(add, [1, 2, 3, 4], 0);
+      p = reduce(multiply, [1, 2, 3, 4], 1);
+      empty = reduce(add, [], 7);",
+    );
+    assert_eq!(variable(&context, "s"), 10.0);
+    assert_eq!(variable(&context, "p"), 24.0);
+    assert_eq!(variable(&context, "empty"), 7.0);
+  }
+
+  #[test]
+  fn reduce_passes_the_accumulator_first() {
+    // ((0 * 10 + 1) * 10 + 2) * 10 + 3
+    let context = run(
+      "function digits(total, digit) { return total * 10 + digit; }
+      n = reduce(digits, [1, 2, 3], 0);",
+    );
+    assert_eq!(variable(&context, "n"), 123.0);
+  }
+
+  #[test]
+  fn reduce_needs_a_two_argument_user_function() {
+    assert!(matches!(
+      parse_error("function f(v) { return v; } t = reduce(f, [1], 0);"),
+      LanguageErrorType::ArgumentCountMismatch(2, 1)
+    ));
+    assert!(matches!(
+      parse_error("function add(a, b) { return a + b; } t = reduce(add, [1]);"),
+      LanguageErrorType::ArgumentCountMismatch(2, 3)
+    ));
+  }
+
+  #[test]
+  fn range_with_one_two_and_three_arguments() {
+    assert_eq!(numbers("range(4)"), [0.0, 1.0, 2.0, 3.0]);
+    assert_eq!(numbers("range(2, 5)"), [2.0, 3.0, 4.0]);
+    assert_eq!(numbers("range(0, 1, 0.25)"), [0.0, 0.25, 0.5, 0.75]);
+    assert_eq!(numbers("range(0, 10, 4)"), [0.0, 4.0, 8.0]);
+  }
+
+  #[test]
+  fn range_is_empty_when_the_end_is_not_past_the_start() {
+    assert_eq!(numbers("range(0)"), []);
+    assert_eq!(numbers("range(0 - 3)"), []);
+    assert_eq!(numbers("range(5, 2)"), []);
+    assert_eq!(numbers("range(0, 0 / 0)"), []);
+  }
+
+  #[test]
+  fn range_rejects_bad_steps_and_huge_lengths() {
+    let err = located_error("range(0, 10, 0)");
+    assert!(matches!(err.error, LanguageErrorType::RangeStep(0.0)));
+    assert_eq!(columns(&err), (14, 15));
+    for source in ["range(0, 10, 0 - 1)", "range(0, 10, 1 / 0)"] {
+      assert!(
+        matches!(eval_error(source), LanguageErrorType::RangeStep(_)),
+        "{source}"
+      );
+    }
+    assert!(matches!(
+      eval_error("range(1 / 0)"),
+      LanguageErrorType::RangeLength(MAX_RANGE_LENGTH)
+    ));
+    assert!(matches!(
+      eval_error("range(0, 1, 0.0000001)"),
+      LanguageErrorType::RangeLength(MAX_RANGE_LENGTH)
+    ));
+  }
+
+  #[test]
+  fn sum_and_product_fold_a_tuple() {
+    assert_eq!(number("sum([1, 2, 3, 4])"), 10.0);
+    assert_eq!(number("product([1, 2, 3, 4])"), 24.0);
+    assert_eq!(number("sum(range(101))"), 5050.0);
+    assert_eq!(number("product([2, 0.5, 0 - 3])"), -3.0);
+  }
+
+  #[test]
+  fn sum_and_product_of_nothing_are_their_identities() {
+    assert_eq!(number("sum([])"), 0.0);
+    assert_eq!(number("product([])"), 1.0);
+  }
+
+  #[test]
+  fn sum_and_product_need_a_tuple_of_numbers() {
+    let err = located_error("sum([1, [2, 3]])");
+    assert!(matches!(
+      err.error,
+      LanguageErrorType::Type(ValueType::Number, Value::Tuple(_))
+    ));
+    assert_eq!(columns(&err), (5, 16));
+    assert!(matches!(
+      eval_error("product(4)"),
+      LanguageErrorType::Type(ValueType::Tuple, Value::Number(_))
+    ));
+  }
+
+  #[test]
+  fn reverse_flips_the_order() {
+    assert_eq!(numbers("reverse([1, 2, 3])"), [3.0, 2.0, 1.0]);
+    assert_eq!(numbers("reverse([7])"), [7.0]);
+    assert_eq!(numbers("reverse([])"), []);
+    assert_eq!(
+      eval("reverse([[1, 2], 3])").to_string(),
+      eval("[3, [1, 2]]").to_string()
+    );
+  }
+
+  #[test]
+  fn reversing_twice_round_trips_and_leaves_the_input_alone() {
+    let context = run("t = [1, 2, 3, 4]; back = reverse(reverse(t)); flipped = reverse(t);");
+    assert_eq!(tuple_variable(&context, "back"), [1.0, 2.0, 3.0, 4.0]);
+    assert_eq!(tuple_variable(&context, "t"), [1.0, 2.0, 3.0, 4.0]);
+    assert_eq!(tuple_variable(&context, "flipped"), [4.0, 3.0, 2.0, 1.0]);
+  }
+
+  #[test]
+  fn reverse_needs_a_tuple() {
+    let err = located_error("reverse(5)");
+    assert!(matches!(
+      err.error,
+      LanguageErrorType::Type(ValueType::Tuple, Value::Number(_))
+    ));
+    assert_eq!(columns(&err), (9, 10));
+  }
+
+  #[test]
+  fn eval_expression_does_arithmetic_and_builtins() {
+    assert_eq!(number("1 + 2 * 3"), 7.0);
+    assert_eq!(number("(1 + 2) * 3"), 9.0);
+    assert_eq!(number("max(1, 5, 3)"), 5.0);
+    assert_eq!(number("sqrt(16) + abs(0 - 2)"), 6.0);
+  }
+
+  #[test]
+  fn eval_expression_reads_the_context() {
+    let mut context = ExecutionContext::default();
+    context.set_runtime("x", Value::number(4.0));
+    let value = eval_expression(&mut context, "x * x").unwrap();
+    assert_eq!(value.as_number(), Some(16.0));
+  }
+
+  #[test]
+  fn eval_expression_errors() {
+    assert!(matches!(
+      eval_error("missing + 1"),
+      LanguageErrorType::Reference(name) if name == "::missing"
+    ));
+    assert!(matches!(
+      eval_error("double(2)"),
+      LanguageErrorType::Reference(name) if name == "double"
+    ));
+    assert!(matches!(
+      eval_expression(&mut ExecutionContext::default(), "1 +"),
+      Err(ParseError::PestError(_))
+    ));
+    assert!(matches!(
+      eval_expression(&mut ExecutionContext::default(), "r = 1;"),
+      Err(ParseError::PestError(_))
+    ));
+  }
+
+  #[test]
+  fn restore_undoes_changes_since_a_snapshot() {
+    let mut context = ExecutionContext::default();
+    let program = parse_with(&mut context, "a = 1; t = [1, 2];").unwrap();
+    Result::from(execute(&mut context, &program)).unwrap();
+    let snapshot = context.snapshot();
+    let program = parse_with(&mut context, "a = a + 10; t = [3]; fresh = 5;").unwrap();
+    Result::from(execute(&mut context, &program)).unwrap();
+    assert_eq!(variable(&context, "a"), 11.0);
+    context.restore(&snapshot);
+    assert_eq!(variable(&context, "a"), 1.0);
+    assert_eq!(tuple_variable(&context, "t"), [1.0, 2.0]);
+    // Registered after the snapshot, so it goes back to unset
+    assert!(context.get_runtime("fresh").is_none());
+  }
+
+  #[test]
+  fn a_snapshot_can_be_restored_more_than_once() {
+    let mut context = ExecutionContext::default();
+    context.set_runtime("a", Value::number(1.0));
+    let snapshot = context.snapshot();
+    for value in [2.0, 3.0] {
+      context.set_runtime("a", Value::number(value));
+      context.restore(&snapshot);
+      assert_eq!(variable(&context, "a"), 1.0);
+    }
+  }
+
+  #[test]
+  fn string_literals_reach_host_functions_intact() {
+    let mut context = ExecutionContext::default();
+    context.register_function("label_length", 2, |args| {
+      assert_eq!(args[0].as_str(), Some("hi \"there\""));
+      Ok(Value::number(
+        args[0].as_str().unwrap().len() as f32 + args[1].as_number().unwrap(),
+      ))
+    });
+    let program = parse_with(&mut context, "n = label_length(\"hi \\\"there\\\"\", 1);").unwrap();
+    Result::from(execute(&mut context, &program)).unwrap();
+    assert_eq!(variable(&context, "n"), 11.0);
+  }
+
+  #[test]
+  fn strings_can_be_stored_and_passed_along() {
+    let mut context = ExecutionContext::default();
+    context.register_function("echo", 1, |args| Ok(args[0].clone()));
+    let program = parse_with(&mut context, "s = \"label\"; t = echo(s);").unwrap();
+    Result::from(execute(&mut context, &program)).unwrap();
+    assert_eq!(context.get_runtime("t").unwrap().as_str(), Some("label"));
+  }
+
+  #[test]
+  fn index_wrap_wraps_around_the_length() {
+    let picks = |index: &str| number(&format!("index_wrap([10, 20, 30], {index})"));
+    assert_eq!(picks("0"), 10.0);
+    assert_eq!(picks("2"), 30.0);
+    assert_eq!(picks("0 - 1"), 30.0);
+    assert_eq!(picks("0 - 4"), 30.0);
+    assert_eq!(picks("3"), 10.0);
+    assert_eq!(picks("1000001"), 30.0);
+    assert_eq!(picks("0 - 1000000"), 30.0);
+    // Truncated toward zero
+    assert_eq!(picks("1.9"), 20.0);
+  }
+
+  #[test]
+  fn index_clamp_sticks_to_the_ends() {
+    let picks = |index: &str| number(&format!("index_clamp([10, 20, 30], {index})"));
+    assert_eq!(picks("0"), 10.0);
+    assert_eq!(picks("1"), 20.0);
+    assert_eq!(picks("0 - 1"), 10.0);
+    assert_eq!(picks("0 - 1000000"), 10.0);
+    assert_eq!(picks("3"), 30.0);
+    assert_eq!(picks("1000000"), 30.0);
+  }
+
+  #[test]
+  fn index_wrap_and_clamp_only_fail_on_an_empty_tuple() {
+    for function in ["index_wrap", "index_clamp"] {
+      for index in ["0", "5", "0 - 5"] {
+        assert!(matches!(
+          eval_error(&format!("{function}([], {index})")),
+          LanguageErrorType::EmptyIndex
+        ));
+      }
+      assert_eq!(
+        eval(&format!("{function}([[1, 2]], 7)")).to_string(),
+        eval("[1, 2]").to_string()
+      );
+    }
+  }
+
+  fn tuple_rc(context: &ExecutionContext, name: &str) -> Rc<Tuple> {
+    match context.get_runtime(name) {
+      Some(Value::Tuple(tuple)) => Rc::clone(tuple),
+      value => panic!("{name} is {value:?}"),
+    }
+  }
+
+  #[test]
+  fn literal_tuples_are_built_once_and_shared() {
+    let mut context = ExecutionContext::default();
+    let program = parse_with(
+      &mut context,
+      "a = [1, [2, 3]]; b = [1, [2, 3]]; c = [1, a];",
+    )
+    .unwrap();
+    Result::from(execute(&mut context, &program)).unwrap();
+    let (a, c) = (tuple_rc(&context, "a"), tuple_rc(&context, "c"));
+    assert!(Rc::ptr_eq(&a, &tuple_rc(&context, "b")));
+    Result::from(execute(&mut context, &program)).unwrap();
+    assert!(Rc::ptr_eq(&a, &tuple_rc(&context, "a")));
+    // Has a variable in it, so it's rebuilt every time
+    assert!(!Rc::ptr_eq(&c, &tuple_rc(&context, "c")));
+    assert_eq!(
+      context.get_runtime("b").unwrap().to_string(),
+      eval("[1, [2, 3]]").to_string()
+    );
+  }
+
+  #[test]
+  fn constant_tuples_are_not_shared_between_contexts() {
+    let source = "a = [1, 2];";
+    let (first, second) = (run(source), run(source));
+    assert!(!Rc::ptr_eq(&tuple_rc(&first, "a"), &tuple_rc(&second, "a")));
+    assert_eq!(tuple_variable(&first, "a"), [1.0, 2.0]);
+  }
+
+  #[test]
+  fn range_errors_name_the_access_that_failed_in_nested_tuples() {
+    let err = located_error("[[1, 2], [3]][1][1]");
+    assert_eq!(
+      err.error.to_string(),
+      "RangeError: Index 1 out of bounds for tuple of length 1"
+    );
+    assert_eq!(columns(&err), (18, 19));
+    let err = located_error("range(3)[1:][0:3]");
+    assert_eq!(
+      err.error.to_string(),
+      "RangeError: Slice end 3 out of bounds for tuple of length 2"
+    );
+    assert_eq!(columns(&err), (16, 17));
+  }
+
+  #[test]
+  fn functions_keep_their_locals_to_themselves() {
+    let context = run(
+      "function first(v) { t = v * 2; return t; }
+      function second(v) { t = v + 100; return first(v) + t; }
+      t = 7;
+      r = second(1);",
+    );
+    assert_eq!(variable(&context, "r"), 103.0);
+    assert_eq!(variable(&context, "t"), 7.0);
+  }
+
+  #[test]
+  fn recursive_calls_get_their_own_locals() {
+    // Each call's `here` has to survive the call it makes
+    let context = run(
+      "function sum_to(n) {
+        here = n;
+        if (n <= 0) { return 0; }
+        below = sum_to(n - 1);
+        return here + below;
+      }
+      r = sum_to(5);",
+    );
+    assert_eq!(variable(&context, "r"), 15.0);
+  }
+
+  #[test]
+  fn compound_assignment_applies_the_operator_to_the_old_value() {
+    let context = run(
+      "x = 1; x += 2;
+      a = 10; a -= 4; b = 3; b *= 5; c = 9; c /= 2;
+      d = 12; d &= 10; e2 = 12; e2 |= 3; f = 12; f ^= 10;",
+    );
+    assert_eq!(variable(&context, "x"), 3.0);
+    let results = ["a", "b", "c", "d", "e2", "f"].map(|name| variable(&context, name));
+    assert_eq!(results, [6.0, 15.0, 4.5, 8.0, 15.0, 6.0]);
+  }
+
+  #[test]
+  fn compound_assignment_works_on_tuples() {
+    let context = run("t = [1, 2]; t += 1; t *= [2, 3];");
+    assert_eq!(tuple_variable(&context, "t"), [4.0, 9.0]);
+  }
+
+  #[test]
+  fn compound_assignment_to_an_unset_variable_is_a_reference_error() {
+    let mut context = ExecutionContext::default();
+    let program = parse_with(&mut context, "acc += 1;").unwrap();
+    let err = Result::from(execute(&mut context, &program)).unwrap_err();
+    assert!(matches!(err.error, LanguageErrorType::Reference(name) if name == "::acc"));
+  }
+
+  #[test]
+  fn tuples_compare_structurally() {
+    assert_eq!(number("[1, 2] == [1, 2]"), 1.0);
+    assert_eq!(number("[1, 2] != [1, 2]"), 0.0);
+    assert_eq!(number("[1, 2] == [2, 1]"), 0.0);
+    assert_eq!(number("[1, 2] == [1, 2, 3]"), 0.0);
+    assert_eq!(number("[1, 2] != [1]"), 1.0);
+    assert_eq!(number("[] == []"), 1.0);
+  }
+
+  #[test]
+  fn nested_tuples_compare_structurally() {
+    assert_eq!(number("[1, [2, [3]]] == [1, [2, [3]]]"), 1.0);
+    assert_eq!(number("[1, [2, [3]]] == [1, [2, [4]]]"), 0.0);
+    assert_eq!(number("[[1, 2], 3] != [[1, 2, 3], 3]"), 1.0);
+  }
+
+  #[test]
+  fn comparing_a_number_to_a_tuple_is_a_type_error() {
+    let err = located_error("1 == [1]");
+    assert!(matches!(
+      err.error,
+      LanguageErrorType::Type(ValueType::Number, Value::Tuple(_))
+    ));
+    // Points at the side that doesn't match
+    assert_eq!(columns(&err), (6, 9));
+    assert!(matches!(
+      eval_error("[1, [2]] != [1, 2]"),
+      LanguageErrorType::Type(ValueType::Tuple, Value::Number(_))
+    ));
+  }
+
+  fn with_double() -> ExecutionContext {
+    let mut context = ExecutionContext::default();
+    context.register_function("double", 1, |args| {
+      Ok(Value::number(args[0].as_number().unwrap() * 2.0))
+    });
+    context
+  }
+
+  #[test]
+  fn programs_can_call_registered_host_functions() {
+    let mut context = with_double();
+    let program = parse_with(&mut context, "r = double(x) + double(1);").unwrap();
+    for x in [0.0, 2.5] {
+      context.set_runtime("x", Value::number(x));
+      Result::from(execute(&mut context, &program)).unwrap();
+      assert_eq!(variable(&context, "r"), x * 2.0 + 2.0);
+    }
+  }
+
+  #[test]
+  fn host_functions_check_their_arity_when_parsing() {
+    let err = parse_with(&mut with_double(), "r = double(1, 2);").unwrap_err();
+    assert!(matches!(
+      err,
+      ParseError::LanguageError(LanguageError {
+        error: LanguageErrorType::ArgumentCountMismatch(2, 1),
+        ..
+      })
+    ));
+  }
+
+  #[test]
+  fn functions_the_program_defines_win_over_host_functions() {
+    let mut context = with_double();
+    let program = parse_with(
+      &mut context,
+      "function double(v) { return v * 3; } r = double(2);",
+    )
+    .unwrap();
+    Result::from(execute(&mut context, &program)).unwrap();
+    assert_eq!(variable(&context, "r"), 6.0);
+  }
+
+  #[test]
+  fn host_function_errors_point_at_the_call() {
+    let mut context = ExecutionContext::default();
+    context.register_function("fail", 0, |_| {
+      Err(LanguageError {
+        location: None,
+        error: LanguageErrorType::AssertionFailed,
+      })
+    });
+    let program = parse_with(&mut context, "r = 1 + fail();").unwrap();
+    let err = Result::from(execute(&mut context, &program)).unwrap_err();
+    assert!(matches!(err.error, LanguageErrorType::AssertionFailed));
+    assert_eq!(columns(&err), (9, 15));
+  }
+
+  #[test]
+  fn parse_collecting_reports_every_recoverable_error() {
+    let errors = ParseOptions::default()
+      .parse_collecting(
+        &mut ExecutionContext::default(),
+        "function f(a) { return a; }\nr = nope(1);\ng = f(1, 2);\nb = sin(1, 2, 3);",
+      )
+      .unwrap_err();
+    let errors = errors
+      .into_iter()
+      .map(|err| match err {
+        ParseError::LanguageError(err) => err.error,
+        err => panic!("{err}"),
+      })
+      .collect::<Vec<_>>();
+    assert!(matches!(
+      errors[..],
+      [
+        LanguageErrorType::Reference(ref name),
+        LanguageErrorType::ArgumentCountMismatch(2, 1),
+        LanguageErrorType::ArgumentCountMismatch(3, 1),
+      ] if name == "nope"
+    ));
+  }
+
+  #[test]
+  fn parse_collecting_still_stops_at_a_syntax_error() {
+    // The grammar is checked before anything else, so the unknown function
+    // is never reached
+    let errors = ParseOptions::default()
+      .parse_collecting(&mut ExecutionContext::default(), "r = nope(1);\ng = (;")
+      .unwrap_err();
+    assert!(matches!(errors[..], [ParseError::PestError(_)]));
+  }
+
+  #[test]
+  fn parse_collecting_accepts_a_valid_program() {
+    let mut context = ExecutionContext::default();
+    let program = ParseOptions::default()
+      .parse_collecting(&mut context, "r = 1; g = 2; b = 3;")
+      .unwrap();
+    Result::from(execute(&mut context, &program)).unwrap();
+    assert_eq!(color(&context), [1.0, 2.0, 3.0]);
+  }
+
+  #[test]
+  fn tuples_still_convert_to_and_from_rc_vec() {
+    let location = Location {
+      start_line: 1,
+      start_column: 1,
+      end_line: 1,
+      end_column: 2,
+    };
+    let tuple = eval("[1, [2, 3], 4, 5, 6]");
+    let vec = <Rc<Vec<Value>>>::try_from(&TrackedValue(tuple.clone(), &location)).unwrap();
+    assert_eq!(vec.len(), 5);
+    assert_eq!(Value::from(vec).to_string(), tuple.to_string());
+    let err = <Rc<Vec<Value>>>::try_from(&TrackedValue(Value::number(1.0), &location)).unwrap_err();
+    assert!(matches!(
+      err.error,
+      LanguageErrorType::Type(ValueType::Tuple, Value::Number(_))
+    ));
+  }
+
+  #[test]
+  fn arguments_are_bound_in_order_however_many_there_are() {
+    let context = run(
+      "function two(a, b) { return a * 10 + b; }
+      function six(a, b, c, d, f, g) { return ((((a * 10 + b) * 10 + c) * 10 + d) * 10 + f) * 10 + g; }
+      r = two(1, 2); g = six(1, 2, 3, 4, 5, 6); b = six(two(0, 1), 0, 0, 0, 0, 2);",
+    );
+    assert_eq!(color(&context), [12.0, 123456.0, 100002.0]);
+  }
+
+  #[test]
+  fn host_functions_get_every_argument_in_order() {
+    let mut context = ExecutionContext::default();
+    context.register_function("digits", 6, |args| {
+      let digits = args.iter().map(|arg| arg.as_number().unwrap());
+      Ok(Value::number(
+        digits.fold(0.0, |total, digit| total * 10.0 + digit),
+      ))
+    });
+    let program = parse_with(&mut context, "r = digits(6, 5, 4, 3, 2, 1);").unwrap();
+    Result::from(execute(&mut context, &program)).unwrap();
+    assert_eq!(variable(&context, "r"), 654321.0);
+  }
+
+  #[test]
+  fn vector_builtins_read_tuple_variables_in_place() {
+    let mut context = run(
+      "three = 3; t = [three, 4];
+      r = len(t) + t[1] + dot(t, t) + magnitude(t);
+      g = sum(t) * product(t) + normalize(t)[0];
+      b = sum(t);",
+    );
+    assert_eq!(color(&context), [36.0, 84.6, 7.0]);
+    // The variable is the only thing still holding the tuple. It isn't a
+    // constant, so the context doesn't keep a copy either
+    let t = context.register(VariableKey {
+      name: "t".to_string(),
+      scope: "".to_string(),
+    });
+    let t = context.get_ref(t).unwrap();
+    assert!(matches!(t, Value::Tuple(tuple) if Rc::strong_count(tuple) == 1));
+  }
+
+  #[test]
+  fn borrowed_reads_still_report_errors_at_the_variable() {
+    for source in [
+      "n = 1; r = len(n);",
+      "n = 1; r = dot(n, [1]);",
+      "n = [1, [2]]; r = sum(n);",
+    ] {
+      let mut context = ExecutionContext::default();
+      let program = parse_with(&mut context, source).unwrap();
+      let err = Result::from(execute(&mut context, &program)).unwrap_err();
+      assert!(matches!(err.error, LanguageErrorType::Type(..)), "{source}");
+      // Points at the variable, which starts at the same column in each
+      assert_eq!(
+        err.location.unwrap().start_column,
+        source.find("(n").unwrap() + 2
+      );
+    }
+    let err = located_error("t[0]");
+    assert!(matches!(err.error, LanguageErrorType::Reference(_)));
+    assert_eq!(columns(&err), (1, 2));
+  }
+
+  fn reserved_name(source: &str) -> String {
+    match parse_error(source) {
+      LanguageErrorType::ReservedName(name) => name,
+      err => panic!("{source} failed with {err:?}"),
+    }
+  }
+
+  #[test]
+  fn builtins_and_constants_cant_be_assigned_to() {
+    assert_eq!(reserved_name("sin = 1;"), "sin");
+    assert_eq!(reserved_name("pi = 3;"), "pi");
+    assert_eq!(reserved_name("map = 1;"), "map");
+  }
+
+  #[test]
+  fn builtins_and_constants_cant_be_loop_variables() {
+    assert_eq!(reserved_name("for sin in [1] { r = sin; }"), "sin");
+    assert_eq!(reserved_name("for e in [1] { r = e; }"), "e");
+    assert_eq!(reserved_name("repeat (len until 3) { r = len; }"), "len");
+  }
+
+  #[test]
+  fn builtins_and_constants_cant_be_parameters() {
+    assert_eq!(
+      reserved_name("function f(len) { return len; } r = f(1);"),
+      "len"
+    );
+    assert_eq!(
+      reserved_name("function f(a, pi) { return a; } r = f(1, 2);"),
+      "pi"
+    );
+  }
+
+  #[test]
+  fn builtins_cant_be_redefined() {
+    assert_eq!(
+      reserved_name("function sin(a) { return 3; } r = sin(1);"),
+      "sin"
+    );
+    assert_eq!(reserved_name("function reduce(a) { return a; }"), "reduce");
+  }
+
+  #[test]
+  fn reserved_names_are_reported_at_the_name() {
+    let err = match parse_with(
+      &mut ExecutionContext::default(),
+      "function f(a, len) { return a; }",
+    ) {
+      Err(ParseError::LanguageError(err)) => err,
+      Err(err) => panic!("{err}"),
+      Ok(_) => panic!("parsed"),
+    };
+    assert_eq!(columns(&err), (15, 18));
+  }
+
+  #[test]
+  fn names_that_only_start_like_builtins_are_fine() {
+    let context =
+      run("function sine(length) { return length; } r = sine(2); for end in [1] { g = end; }");
+    assert_eq!(variable(&context, "r"), 2.0);
+    assert_eq!(variable(&context, "g"), 1.0);
+  }
+
+  #[test]
+  fn empty_tuples_are_written_as_brackets() {
+    assert_eq!(number("len([])"), 0.0);
+    assert_eq!(numbers("[] + []"), []);
+    assert!(matches!(
+      eval_error("[1, 2] + []"),
+      LanguageErrorType::LengthMismatch(2, 0)
+    ));
+    assert_eq!(number("[] == []"), 1.0);
+    assert_eq!(number("[] == [1]"), 0.0);
+  }
+}
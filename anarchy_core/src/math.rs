@@ -0,0 +1,162 @@
+// Without std, f32 has no math methods of its own, so these fill in for the
+// ones the language needs using libm. With std they're f32's own, unless the
+// deterministic feature is on. Sin and friends can differ in the last bits
+// between platforms (and between a native build and wasm), so then libm's
+// software versions are used everywhere instead. f32's methods win over a
+// trait's, so calls that should follow the feature are written
+// `Float::sin(value)`. Rounding and sqrt are exact everywhere, so they're
+// only here for no_std
+pub(crate) trait Float {
+  fn sin(self) -> Self;
+  fn cos(self) -> Self;
+  fn tan(self) -> Self;
+  fn asin(self) -> Self;
+  fn acos(self) -> Self;
+  fn atan(self) -> Self;
+  fn atan2(self, other: Self) -> Self;
+  #[cfg(not(feature = "std"))]
+  fn sqrt(self) -> Self;
+  fn ln(self) -> Self;
+  fn log(self, base: Self) -> Self;
+  fn powf(self, exponent: Self) -> Self;
+  #[cfg(not(feature = "std"))]
+  fn floor(self) -> Self;
+  #[cfg(not(feature = "std"))]
+  fn ceil(self) -> Self;
+  #[cfg(not(feature = "std"))]
+  fn round(self) -> Self;
+  #[cfg(not(feature = "std"))]
+  fn rem_euclid(self, divisor: Self) -> Self;
+}
+
+#[cfg(any(not(feature = "std"), feature = "deterministic"))]
+impl Float for f32 {
+  fn sin(self) -> f32 {
+    libm::sinf(self)
+  }
+  fn cos(self) -> f32 {
+    libm::cosf(self)
+  }
+  fn tan(self) -> f32 {
+    libm::tanf(self)
+  }
+  fn asin(self) -> f32 {
+    libm::asinf(self)
+  }
+  fn acos(self) -> f32 {
+    libm::acosf(self)
+  }
+  fn atan(self) -> f32 {
+    libm::atanf(self)
+  }
+  fn atan2(self, other: f32) -> f32 {
+    libm::atan2f(self, other)
+  }
+  #[cfg(not(feature = "std"))]
+  fn sqrt(self) -> f32 {
+    libm::sqrtf(self)
+  }
+  fn ln(self) -> f32 {
+    libm::logf(self)
+  }
+  fn log(self, base: f32) -> f32 {
+    self.ln() / base.ln()
+  }
+  fn powf(self, exponent: f32) -> f32 {
+    libm::powf(self, exponent)
+  }
+  #[cfg(not(feature = "std"))]
+  fn floor(self) -> f32 {
+    libm::floorf(self)
+  }
+  #[cfg(not(feature = "std"))]
+  fn ceil(self) -> f32 {
+    libm::ceilf(self)
+  }
+  #[cfg(not(feature = "std"))]
+  fn round(self) -> f32 {
+    libm::roundf(self)
+  }
+  // Same as std's
+  #[cfg(not(feature = "std"))]
+  fn rem_euclid(self, divisor: f32) -> f32 {
+    let remainder = self % divisor;
+    if remainder < 0.0 {
+      remainder + divisor.abs()
+    } else {
+      remainder
+    }
+  }
+}
+
+#[cfg(all(feature = "std", not(feature = "deterministic")))]
+impl Float for f32 {
+  fn sin(self) -> f32 {
+    f32::sin(self)
+  }
+  fn cos(self) -> f32 {
+    f32::cos(self)
+  }
+  fn tan(self) -> f32 {
+    f32::tan(self)
+  }
+  fn asin(self) -> f32 {
+    f32::asin(self)
+  }
+  fn acos(self) -> f32 {
+    f32::acos(self)
+  }
+  fn atan(self) -> f32 {
+    f32::atan(self)
+  }
+  fn atan2(self, other: f32) -> f32 {
+    f32::atan2(self, other)
+  }
+  fn ln(self) -> f32 {
+    f32::ln(self)
+  }
+  fn log(self, base: f32) -> f32 {
+    f32::log(self, base)
+  }
+  fn powf(self, exponent: f32) -> f32 {
+    f32::powf(self, exponent)
+  }
+}
+
+#[cfg(all(test, feature = "deterministic"))]
+mod tests {
+  use super::Float;
+  use crate::{eval_expression, ExecutionContext};
+
+  // libm's results, which are the same on every platform. Compared as bits,
+  // since the point is that not even the last one changes
+  #[test]
+  fn transcendental_results_are_pinned() {
+    let results = [
+      (Float::sin(1.0f32), 0x3f576aa4),
+      (Float::cos(1.0f32), 0x3f0a5140),
+      (Float::tan(0.5f32), 0x3f0bda7b),
+      (Float::asin(0.5f32), 0x3f060a92),
+      (Float::atan2(1.0f32, 2.0), 0x3eed6338),
+      (Float::ln(10.0f32), 0x40135d8e),
+      (Float::powf(2.0f32, 0.5), 0x3fb504f3),
+      (Float::sin(1000.0f32), 0x3f53ae61),
+    ];
+    for (index, (result, bits)) in results.into_iter().enumerate() {
+      assert_eq!(result.to_bits(), bits, "result {index} was {result}");
+    }
+  }
+
+  #[test]
+  fn programs_use_the_pinned_results() {
+    for (source, bits) in [
+      ("sin(1)", 0x3f576aa4),
+      ("cos(1)", 0x3f0a5140),
+      ("log(10)", 0x40135d8e),
+      ("2 ** 0.5", 0x3fb504f3),
+    ] {
+      let value = eval_expression(&mut ExecutionContext::default(), source).unwrap();
+      assert_eq!(value.as_number().unwrap().to_bits(), bits, "{source}");
+    }
+  }
+}
@@ -0,0 +1,302 @@
+use crate::{
+  next_constant_tuple, ElseBranch, ExecutionContext, Expression, ExpressionOp, FunctionIdentifier,
+  Location, ParsedLanguage, Statement, Value,
+};
+use alloc::vec::Vec;
+
+impl ParsedLanguage {
+  // Precomputes every sub-expression that only depends on literals, so it's
+  // evaluated once instead of once per pixel. Anything that would error or
+  // produce a non-finite number is left alone to fail (or overflow) at runtime
+  // exactly like it did before
+  pub fn optimize(&mut self) {
+    let mut context = ExecutionContext::default();
+    optimize_statements(&mut self.top_level, &mut context);
+    // Only the channels, since execute_output expects a TupleLiteral
+    if let Some(Expression {
+      op: ExpressionOp::TupleLiteral(channels),
+      ..
+    }) = &mut self.output
+    {
+      for channel in channels {
+        optimize_expression(channel, &mut context);
+      }
+    }
+    for function in &mut self.functions {
+      optimize_statements(&mut function.contents, &mut context);
+    }
+  }
+}
+
+fn optimize_statements(statements: &mut [Statement], context: &mut ExecutionContext) {
+  for statement in statements {
+    match statement {
+      Statement::Assignment { value, .. } | Statement::Return(value) => {
+        optimize_expression(value, context)
+      }
+      // Folding the call itself (say `assert(1);`) would leave a statement
+      // with no call in it
+      Statement::Call(call) => {
+        if let ExpressionOp::FunctionCall(_, arguments) = &mut call.op {
+          for argument in arguments {
+            optimize_expression(argument, context);
+          }
+        }
+      }
+      Statement::Break | Statement::Continue => {}
+      Statement::Repeat(repeat_statement) => {
+        optimize_statements(&mut repeat_statement.block, context)
+      }
+      Statement::ForEach { iterable, body, .. } => {
+        optimize_expression(iterable, context);
+        optimize_statements(body, context);
+      }
+      Statement::If(if_statement) => {
+        let mut if_statement = if_statement;
+        loop {
+          optimize_expression(&mut if_statement.condition, context);
+          optimize_statements(&mut if_statement.if_branch, context);
+          match &mut if_statement.else_branch {
+            ElseBranch::IfStatement(else_if) => if_statement = else_if,
+            ElseBranch::ElseStatement(else_block) => {
+              optimize_statements(else_block, context);
+              break;
+            }
+            ElseBranch::None => break,
+          }
+        }
+      }
+    }
+  }
+}
+
+fn optimize_expression(expression: &mut Expression, context: &mut ExecutionContext) {
+  let foldable = match &mut expression.op {
+    ExpressionOp::NumberLiteral(_)
+    | ExpressionOp::StringLiteral(_)
+    | ExpressionOp::ConstantTuple(..)
+    | ExpressionOp::Reference(_) => false,
+    // Already as folded as it gets, but its elements may not be. If they all
+    // fold to literals it can be shared like any other constant tuple
+    ExpressionOp::TupleLiteral(values) => {
+      for value in values.iter_mut() {
+        optimize_expression(value, context);
+      }
+      if values.iter().all(is_literal) {
+        expression.op = ExpressionOp::ConstantTuple(next_constant_tuple(), core::mem::take(values));
+      }
+      return;
+    }
+    // Host functions and print() may not be pure, and user-defined ones may
+    // read variables or recurse forever
+    ExpressionOp::FunctionCall(function, arguments) => {
+      for argument in arguments.iter_mut() {
+        optimize_expression(argument, context);
+      }
+      !matches!(
+        function,
+        FunctionIdentifier::UserDefined(_)
+          | FunctionIdentifier::Map(_)
+          | FunctionIdentifier::Reduce(_)
+          | FunctionIdentifier::Host(_)
+          | FunctionIdentifier::Print
+      ) && arguments.iter().all(is_literal)
+    }
+    ExpressionOp::Neg(value) | ExpressionOp::Invert(value) => {
+      optimize_expression(value, context);
+      is_literal(value)
+    }
+    ExpressionOp::Slice(tuple, start, end) => {
+      optimize_expression(tuple, context);
+      for bound in [&mut *start, &mut *end].into_iter().flatten() {
+        optimize_expression(bound, context);
+      }
+      is_literal(tuple)
+        && [start, end]
+          .iter()
+          .all(|bound| bound.as_ref().is_none_or(|bound| is_literal(bound)))
+    }
+    ExpressionOp::Ternary(condition, if_true, if_false) => {
+      optimize_expression(condition, context);
+      optimize_expression(if_true, context);
+      optimize_expression(if_false, context);
+      // Only one branch is ever taken, so a known condition is enough
+      if let ExpressionOp::NumberLiteral(condition) = condition.op {
+        let taken = if condition != 0.0 { if_true } else { if_false };
+        *expression = core::mem::replace(
+          &mut **taken,
+          Expression {
+            location: expression.location.clone(),
+            op: ExpressionOp::NumberLiteral(0.0),
+          },
+        );
+        return;
+      }
+      false
+    }
+    ExpressionOp::Add(lhs, rhs)
+    | ExpressionOp::Mul(lhs, rhs)
+    | ExpressionOp::Sub(lhs, rhs)
+    | ExpressionOp::Div(lhs, rhs)
+    | ExpressionOp::FloorDiv(lhs, rhs)
+    | ExpressionOp::BinaryAnd(lhs, rhs)
+    | ExpressionOp::Xor(lhs, rhs)
+    | ExpressionOp::ShiftLeft(lhs, rhs)
+    | ExpressionOp::ShiftRight(lhs, rhs)
+    | ExpressionOp::BinaryOr(lhs, rhs)
+    | ExpressionOp::GreaterThan(lhs, rhs)
+    | ExpressionOp::LessThan(lhs, rhs)
+    | ExpressionOp::LessThanOrEqual(lhs, rhs)
+    | ExpressionOp::GreaterThanOrEqual(lhs, rhs)
+    | ExpressionOp::Equal(lhs, rhs)
+    | ExpressionOp::NotEqual(lhs, rhs)
+    | ExpressionOp::Index(lhs, rhs)
+    | ExpressionOp::Or(lhs, rhs)
+    | ExpressionOp::And(lhs, rhs)
+    | ExpressionOp::Modulo(lhs, rhs)
+    | ExpressionOp::Pow(lhs, rhs) => {
+      optimize_expression(lhs, context);
+      optimize_expression(rhs, context);
+      is_literal(lhs) && is_literal(rhs)
+    }
+  };
+  if !foldable {
+    return;
+  }
+  // Literals never touch the context or call user-defined functions
+  if let Ok(value) = expression.evaluate(context, &Vec::new()) {
+    if let Some(op) = literal(&value, &expression.location) {
+      expression.op = op;
+    }
+  }
+}
+
+fn is_literal(expression: &Expression) -> bool {
+  match &expression.op {
+    ExpressionOp::NumberLiteral(_) | ExpressionOp::StringLiteral(_) => true,
+    ExpressionOp::TupleLiteral(values) | ExpressionOp::ConstantTuple(_, values) => {
+      values.iter().all(is_literal)
+    }
+    _ => false,
+  }
+}
+
+// None if the value has no literal spelling (inf or NaN)
+fn literal(value: &Value, location: &Location) -> Option<ExpressionOp> {
+  match value {
+    Value::Number(number) if number.is_finite() => Some(ExpressionOp::NumberLiteral(*number)),
+    Value::Number(_) => None,
+    Value::Str(string) => Some(ExpressionOp::StringLiteral(string.as_ref().into())),
+    Value::Tuple(values) => Some(ExpressionOp::ConstantTuple(
+      next_constant_tuple(),
+      values
+        .iter()
+        .map(|value| {
+          Some(Expression {
+            location: location.clone(),
+            op: literal(value, location)?,
+          })
+        })
+        .collect::<Option<Vec<Expression>>>()?,
+    )),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::{execute, ExecutionContext, ParseOptions, ParsedLanguage, Value};
+  use alloc::rc::Rc;
+  use alloc::string::String;
+
+  fn parse(source: &str) -> (ExecutionContext, ParsedLanguage) {
+    let mut context = ExecutionContext::default();
+    let program = ParseOptions::default().parse(&mut context, source).unwrap();
+    (context, program)
+  }
+
+  fn optimized(source: &str) -> String {
+    let (context, mut program) = parse(source);
+    program.optimize();
+    program.to_source(&context.export_scope_locations())
+  }
+
+  fn color(context: &mut ExecutionContext, program: &ParsedLanguage, x: f32) -> [f32; 3] {
+    context.set_runtime("x", Value::number(x));
+    Result::from(execute(context, program)).unwrap();
+    ["r", "g", "b"].map(|name| context.get_runtime(name).unwrap().as_number().unwrap())
+  }
+
+  #[test]
+  fn constant_sub_expressions_fold() {
+    for (source, expected) in [
+      ("r = x * (2 * 3.5 / 7);", "r = x * 1;\n"),
+      ("r = sin(0) + x;", "r = 0 + x;\n"),
+      ("r = -(2 ** 3) + x;", "r = (-8) + x;\n"),
+      ("r = [1, 2 + 3][1];", "r = 5;\n"),
+      ("r = 1 ? x : y;", "r = x;\n"),
+      ("r = 0 ? x : 2 * 3;", "r = 6;\n"),
+      ("print(1 + 2);", "print(3);\n"),
+    ] {
+      assert_eq!(optimized(source), expected, "{source}");
+    }
+  }
+
+  #[test]
+  fn errors_and_non_finite_results_are_left_to_runtime() {
+    for source in [
+      "r = 1 / 0;\n",
+      "r = 0 / 0;\n",
+      "r = [1, 2][3];\n",
+      "r = [1, 2] + [1, 2, 3];\n",
+      "assert(0);\n",
+    ] {
+      assert_eq!(optimized(source), source);
+    }
+  }
+
+  #[test]
+  fn variables_and_impure_calls_are_not_folded() {
+    for source in [
+      "function two() {\n  return 2;\n}\nr = two() + 1;\n",
+      "r = x + 1;\n",
+      "r = x > 1 ? 2 : 3;\n",
+    ] {
+      assert_eq!(optimized(source), source);
+    }
+  }
+
+  #[test]
+  fn optimized_program_renders_the_same() {
+    let source = "function f(v) { return v * (2 * 3); }
+      r = f(x) + 10 / 4;
+      g = x > 2 ? [1, 2, 3][1:][0] : sqrt(16) ** 2;
+      b = 0;
+      repeat (i until 3) { b = b + i * (1 + 1); }";
+    let (mut context, program) = parse(source);
+    let mut optimized = program.clone();
+    optimized.optimize();
+    for x in [0.0, 1.0, 3.0, 7.5] {
+      assert_eq!(
+        color(&mut context, &optimized, x),
+        color(&mut context, &program, x),
+        "x = {x}"
+      );
+    }
+  }
+
+  #[test]
+  fn folded_tuples_are_built_once() {
+    let (mut context, mut program) = parse("t = [1 + 1, sin(0)];");
+    program.optimize();
+    let mut run = || {
+      Result::from(execute(&mut context, &program)).unwrap();
+      match context.get_runtime("t") {
+        Some(Value::Tuple(tuple)) => Rc::clone(tuple),
+        value => panic!("{value:?}"),
+      }
+    };
+    let first = run();
+    assert!(Rc::ptr_eq(&first, &run()));
+    assert_eq!(first.len(), 2);
+  }
+}
@@ -0,0 +1,883 @@
+#[cfg(not(feature = "std"))]
+use crate::math::Float;
+#[cfg(feature = "rayon")]
+use crate::ExecutionContextLUT;
+use crate::{
+  execute, execute_output, ExecutionContext, Identifier, LanguageError, LanguageErrorType,
+  ParsedLanguage, UntrackedValue, Value, VariableKey,
+};
+use alloc::string::ToString;
+use core::iter::zip;
+use core::ops::Range;
+#[cfg(feature = "rayon")]
+use core::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
+// How a program's RGBA output is combined with what's already in the
+// destination buffer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BlendMode {
+  // Overwrite the destination pixel
+  #[default]
+  Replace,
+  // out = src * a + dst * (1 - a), so a = 0 leaves the destination alone
+  Alpha,
+}
+
+impl BlendMode {
+  pub fn blend(&self, destination: &mut [u8; 4], source: [u8; 4]) {
+    match self {
+      BlendMode::Replace => *destination = source,
+      BlendMode::Alpha => {
+        let alpha = source[3] as f32 / 255.0;
+        for (destination, source) in zip(destination[..3].iter_mut(), source) {
+          *destination =
+            (source as f32 * alpha + *destination as f32 * (1.0 - alpha)).round() as u8;
+        }
+        // Standard "over" operator for the coverage itself
+        destination[3] = (source[3] as f32 + destination[3] as f32 * (1.0 - alpha)).round() as u8;
+      }
+    }
+  }
+}
+
+// What to do with a color channel that comes out NaN or infinite
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NonFiniteMode {
+  // Fail with NonFiniteOutput, so the bug doesn't go unnoticed
+  #[default]
+  Error,
+  // Infinities become 0 or 255 and NaN becomes 0, like an `as u8` cast
+  Saturate,
+}
+
+// What to do when a pixel finishes without assigning r, g or b
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MissingChannelMode {
+  // Fail with UnsetChannel, naming the channel that was left out
+  #[default]
+  Error,
+  // Treat the channel as 0
+  Zero,
+}
+
+// A color channel as a byte, or None if it's NaN or infinite. Anything else
+// is clamped to 0..=255 and truncated
+pub fn color_channel(value: f32) -> Option<u8> {
+  value.is_finite().then_some(value as u8)
+}
+
+// The top-level variables every frame reads and writes, see
+// FrameIdentifiers::register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameIdentifiers {
+  pub x: Identifier,
+  pub y: Identifier,
+  // x / width and y / height, from 0 up to (but not including) 1
+  pub u: Identifier,
+  pub v: Identifier,
+  // width / height, the same for the whole frame
+  pub aspect: Identifier,
+  pub time: Identifier,
+  pub random: Identifier,
+  pub r: Identifier,
+  pub g: Identifier,
+  pub b: Identifier,
+  // Optional, fully opaque unless the program assigns it
+  pub a: Identifier,
+}
+
+impl FrameIdentifiers {
+  pub fn register(context: &mut ExecutionContext) -> Self {
+    let mut register = |name: &str| {
+      context.register(VariableKey {
+        name: name.to_string(),
+        scope: "".to_string(),
+      })
+    };
+    Self {
+      x: register("x"),
+      y: register("y"),
+      u: register("u"),
+      v: register("v"),
+      aspect: register("aspect"),
+      time: register("time"),
+      random: register("random"),
+      r: register("r"),
+      g: register("g"),
+      b: register("b"),
+      a: register("a"),
+    }
+  }
+
+  // The inputs that change from pixel to pixel, for partition_invariant
+  pub fn varying(&self) -> [Identifier; 4] {
+    [self.x, self.y, self.u, self.v]
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct FrameParams<'a> {
+  pub width: usize,
+  pub height: usize,
+  pub time: f32,
+  pub random: f32,
+  pub identifiers: FrameIdentifiers,
+  // Set alongside time and random, e.g. the mouse position
+  pub variables: &'a [(Identifier, f32)],
+  // The first half of ParsedLanguage::partition_invariant, run once before
+  // any pixel. The program passed to render_frame should be the second half
+  pub invariant: Option<&'a ParsedLanguage>,
+  // Drawn wherever the program returns something other than an [r, g, b]
+  // tuple before producing a color
+  pub clear_color: [u8; 4],
+  pub blend: BlendMode,
+  pub non_finite: NonFiniteMode,
+  pub missing_channel: MissingChannelMode,
+}
+
+// Runs `program` once per pixel and writes the results to `out` as row-major
+// RGBA. Every pixel starts from the same scope, so nothing leaks between
+// them. Stops at the first pixel that fails, leaving the rest of `out` alone
+pub fn render_frame(
+  context: &mut ExecutionContext,
+  program: &ParsedLanguage,
+  params: &FrameParams,
+  out: &mut [u8],
+) -> Result<(), LanguageError> {
+  let out = frame_buffer(params, out)?;
+  start_frame(context, params)?;
+  render_rows(context, program, params, 0, out)
+}
+
+// Like render_frame, but only draws `rows`, leaving the rest of `out` (still
+// the whole frame) alone. Rendering a frame a few rows at a time lets a
+// single-threaded host stay responsive and give up on a frame partway
+pub fn render_frame_rows(
+  context: &mut ExecutionContext,
+  program: &ParsedLanguage,
+  params: &FrameParams,
+  rows: Range<usize>,
+  out: &mut [u8],
+) -> Result<(), LanguageError> {
+  let out = frame_buffer(params, out)?;
+  let row_length = params.width * 4;
+  let end = rows.end.min(params.height);
+  let rows = rows.start.min(end)..end;
+  start_frame(context, params)?;
+  render_rows(
+    context,
+    program,
+    params,
+    rows.start,
+    &mut out[rows.start * row_length..rows.end * row_length],
+  )
+}
+
+/// A parsed program bundled with the context it runs in, its
+/// FrameIdentifiers and the half of it that only needs to run once a frame.
+/// Build it once the program is parsed (and linted and optimized, if
+/// wanted), then render as many frames as needed
+///
+/// ```
+/// use anarchy_core::*;
+/// let mut context = ExecutionContext::default();
+/// let program = ParseOptions::default()
+///   .parse(&mut context, "r = x * 50; g = time; b = 0;")
+///   .unwrap();
+/// let mut compiled = CompiledProgram::new(context, program);
+/// let mut out = [0; 2 * 1 * 4];
+/// for time in [0.0, 100.0] {
+///   let frame = FrameParams {
+///     width: 2,
+///     height: 1,
+///     time,
+///     random: 0.0,
+///     identifiers: compiled.identifiers(),
+///     variables: &[],
+///     invariant: None,
+///     clear_color: [0, 0, 0, 255],
+///     blend: BlendMode::Replace,
+///     non_finite: NonFiniteMode::Error,
+///     missing_channel: MissingChannelMode::Error,
+///   };
+///   compiled.render(frame, &mut out).unwrap();
+///   let g = time as u8;
+///   assert_eq!(out, [0, g, 0, 255, 50, g, 0, 255]);
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct CompiledProgram {
+  context: ExecutionContext,
+  invariant: ParsedLanguage,
+  program: ParsedLanguage,
+  identifiers: FrameIdentifiers,
+}
+
+impl CompiledProgram {
+  // `context` must be the one `program` was parsed with
+  pub fn new(mut context: ExecutionContext, program: ParsedLanguage) -> Self {
+    let identifiers = FrameIdentifiers::register(&mut context);
+    let (invariant, program) = program.partition_invariant(&identifiers.varying());
+    Self {
+      context,
+      invariant,
+      program,
+      identifiers,
+    }
+  }
+
+  pub fn identifiers(&self) -> FrameIdentifiers {
+    self.identifiers
+  }
+
+  // The identifier of top-level variable `name`, for FrameParams::variables
+  pub fn register(&mut self, name: &str) -> Identifier {
+    self.context.register(VariableKey {
+      name: name.to_string(),
+      scope: "".to_string(),
+    })
+  }
+
+  // Holds the variables of the last pixel rendered
+  pub fn context(&self) -> &ExecutionContext {
+    &self.context
+  }
+
+  pub fn context_mut(&mut self) -> &mut ExecutionContext {
+    &mut self.context
+  }
+
+  // render_frame, with the program's own invariant half standing in for
+  // `frame.invariant`
+  pub fn render(&mut self, frame: FrameParams, out: &mut [u8]) -> Result<(), LanguageError> {
+    let frame = FrameParams {
+      invariant: Some(&self.invariant),
+      ..frame
+    };
+    render_frame(&mut self.context, &self.program, &frame, out)
+  }
+
+  // Like render, but only draws `rows`, see render_frame_rows
+  pub fn render_rows(
+    &mut self,
+    frame: FrameParams,
+    rows: Range<usize>,
+    out: &mut [u8],
+  ) -> Result<(), LanguageError> {
+    let frame = FrameParams {
+      invariant: Some(&self.invariant),
+      ..frame
+    };
+    render_frame_rows(&mut self.context, &self.program, &frame, rows, out)
+  }
+}
+
+// Like render_frame, but splits the rows between rayon's threads, each
+// with its own context built from `lut`. Rows after a failing one may still
+// get drawn
+#[cfg(feature = "rayon")]
+pub fn render_frame_parallel(
+  lut: &ExecutionContextLUT,
+  program: &ParsedLanguage,
+  params: &FrameParams,
+  out: &mut [u8],
+) -> Result<(), LanguageError> {
+  let row_length = params.width * 4;
+  let out = frame_buffer(params, out)?;
+  let failed_row = AtomicUsize::new(usize::MAX);
+  out.par_chunks_mut(row_length).enumerate().for_each_init(
+    || {
+      let mut context = ExecutionContext::new_with_scope_locations(lut.clone());
+      let started = start_frame(&mut context, params).is_ok();
+      (context, started)
+    },
+    |(context, started), (y, row)| {
+      if !*started || render_rows(context, program, params, y, row).is_err() {
+        failed_row.fetch_min(y, Ordering::Relaxed);
+      }
+    },
+  );
+  // LanguageError can't leave the thread it was made on, so redo the first
+  // failing row here to get it back
+  let y = failed_row.into_inner();
+  if y == usize::MAX {
+    return Ok(());
+  }
+  let mut context = ExecutionContext::new_with_scope_locations(lut.clone());
+  start_frame(&mut context, params)?;
+  render_rows(
+    &mut context,
+    program,
+    params,
+    y,
+    &mut out[y * row_length..(y + 1) * row_length],
+  )
+}
+
+// The part of `out` a whole frame of `params` goes in, or an error if `out`
+// is too short for it
+pub(crate) fn frame_buffer<'a>(
+  params: &FrameParams,
+  out: &'a mut [u8],
+) -> Result<&'a mut [u8], LanguageError> {
+  let length = params.width * params.height * 4;
+  let found = out.len();
+  out.get_mut(..length).ok_or(LanguageError {
+    error: LanguageErrorType::OutputBuffer(length, found),
+    location: None,
+  })
+}
+
+// Sets up everything that's the same for every pixel, which each one then
+// starts from
+pub(crate) fn start_frame(
+  context: &mut ExecutionContext,
+  params: &FrameParams,
+) -> Result<(), LanguageError> {
+  let identifiers = params.identifiers;
+  context.reset();
+  context.set(identifiers.time, Value::number(params.time));
+  context.set(identifiers.random, Value::number(params.random));
+  context.set(
+    identifiers.aspect,
+    Value::number(params.width as f32 / params.height as f32),
+  );
+  for (identifier, value) in params.variables {
+    context.set(*identifier, Value::number(*value));
+  }
+  if let Some(invariant) = params.invariant {
+    Result::from(execute(context, invariant))?;
+  }
+  context.save_checkpoint();
+  Ok(())
+}
+
+// Renders whole rows into `out`, the first of which is row `first_row`
+pub(crate) fn render_rows(
+  context: &mut ExecutionContext,
+  program: &ParsedLanguage,
+  params: &FrameParams,
+  first_row: usize,
+  out: &mut [u8],
+) -> Result<(), LanguageError> {
+  for (y, row) in (first_row..).zip(out.chunks_exact_mut(params.width * 4)) {
+    for (x, pixel) in row.as_chunks_mut().0.iter_mut().enumerate() {
+      render_pixel_at(context, program, params, x, y, pixel)?;
+    }
+  }
+  Ok(())
+}
+
+pub(crate) fn render_pixel_at(
+  context: &mut ExecutionContext,
+  program: &ParsedLanguage,
+  params: &FrameParams,
+  x: usize,
+  y: usize,
+  pixel: &mut [u8; 4],
+) -> Result<(), LanguageError> {
+  context.rewind();
+  context.count_pixel();
+  context.set(params.identifiers.x, Value::number(x as f32));
+  context.set(params.identifiers.y, Value::number(y as f32));
+  context.set(
+    params.identifiers.u,
+    Value::number(x as f32 / params.width as f32),
+  );
+  context.set(
+    params.identifiers.v,
+    Value::number(y as f32 / params.height as f32),
+  );
+  let color = match render_pixel(context, program, params, x, y)? {
+    Some(color) => to_bytes(color, params.non_finite, x, y)?,
+    // Returned early
+    None => params.clear_color,
+  };
+  params.blend.blend(pixel, color);
+  Ok(())
+}
+
+// Converts the RGBA a program produced for pixel (x, y)
+pub(crate) fn to_bytes(
+  color: [f32; 4],
+  non_finite: NonFiniteMode,
+  x: usize,
+  y: usize,
+) -> Result<[u8; 4], LanguageError> {
+  let mut bytes = [0; 4];
+  for (byte, channel) in zip(&mut bytes, color) {
+    *byte = match (color_channel(channel), non_finite) {
+      (Some(byte), _) => byte,
+      (None, NonFiniteMode::Saturate) => channel as u8,
+      (None, NonFiniteMode::Error) => {
+        return Err(LanguageError {
+          error: LanguageErrorType::NonFiniteOutput(x, y, channel),
+          location: None,
+        })
+      }
+    };
+  }
+  Ok(bytes)
+}
+
+// The color of pixel (x, y), or None if the program returned before setting
+// one. A top-level `return [r, g, b];` ends the pixel early with that color,
+// whatever r, g and b were assigned. Returning anything other than a tuple
+// of three leaves the pixel to clear_color
+fn render_pixel(
+  context: &mut ExecutionContext,
+  program: &ParsedLanguage,
+  params: &FrameParams,
+  x: usize,
+  y: usize,
+) -> Result<Option<[f32; 4]>, LanguageError> {
+  let identifiers = &params.identifiers;
+  let color = match execute(context, program).into_result()? {
+    Some(Value::Tuple(channels)) if channels.len() == 3 => {
+      let mut color = [0.0; 3];
+      for (channel, value) in zip(&mut color, channels.iter()) {
+        *channel = UntrackedValue(value.clone()).try_into()?;
+      }
+      Some(color)
+    }
+    Some(_) => return Ok(None),
+    None => execute_output(context, program)?,
+  };
+  let [r, g, b] = match color {
+    Some(color) => color,
+    None => {
+      let mut color = [0.0; 3];
+      let channels = [
+        (identifiers.r, 'r'),
+        (identifiers.g, 'g'),
+        (identifiers.b, 'b'),
+      ];
+      for (channel, (identifier, name)) in zip(&mut color, channels) {
+        *channel = match (context.slot(identifier), params.missing_channel) {
+          (Some(value), _) => UntrackedValue(value.clone()).try_into()?,
+          (None, MissingChannelMode::Zero) => 0.0,
+          (None, MissingChannelMode::Error) => {
+            return Err(LanguageError {
+              error: LanguageErrorType::UnsetChannel(x, y, name),
+              location: None,
+            })
+          }
+        };
+      }
+      color
+    }
+  };
+  let a: f32 =
+    UntrackedValue(context.unattributed_get_or(identifiers.a, Value::number(255.0))).try_into()?;
+  Ok(Some([r, g, b, a]))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{ParseError, ParseOptions};
+
+  fn compile(source: &str) -> CompiledProgram {
+    compile_with(&ParseOptions::default(), source)
+  }
+
+  fn compile_with(options: &ParseOptions, source: &str) -> CompiledProgram {
+    let mut context = ExecutionContext::default();
+    let program = options.parse(&mut context, source).unwrap();
+    CompiledProgram::new(context, program)
+  }
+
+  fn frame(compiled: &CompiledProgram, width: usize, height: usize) -> FrameParams<'static> {
+    FrameParams {
+      width,
+      height,
+      time: 0.0,
+      random: 0.0,
+      identifiers: compiled.identifiers(),
+      variables: &[],
+      invariant: None,
+      clear_color: [0, 0, 0, 255],
+      blend: BlendMode::Replace,
+      non_finite: NonFiniteMode::Error,
+      missing_channel: MissingChannelMode::Error,
+    }
+  }
+
+  #[test]
+  fn pixel_that_returns_before_setting_a_color_gets_clear_color() {
+    let mut compiled = compile("if (x == 1) { return 0; } r = 10; g = 20; b = 30;");
+    let frame = FrameParams {
+      clear_color: [1, 2, 3, 4],
+      ..frame(&compiled, 2, 1)
+    };
+    let mut out = [0; 2 * 4];
+    compiled.render(frame, &mut out).unwrap();
+    assert_eq!(out, [10, 20, 30, 255, 1, 2, 3, 4]);
+  }
+
+  #[test]
+  fn written_pixels_ignore_clear_color() {
+    let mut compiled = compile("r = 10; g = 20; b = 30;");
+    let frame = FrameParams {
+      clear_color: [1, 2, 3, 4],
+      ..frame(&compiled, 2, 1)
+    };
+    let mut out = [0; 2 * 4];
+    compiled.render(frame, &mut out).unwrap();
+    assert_eq!(out, [10, 20, 30, 255, 10, 20, 30, 255]);
+  }
+
+  #[test]
+  fn alpha_blend_mixes_by_the_source_alpha() {
+    let blend = |alpha| {
+      let mut destination = [200, 100, 0, 255];
+      BlendMode::Alpha.blend(&mut destination, [0, 50, 100, alpha]);
+      destination
+    };
+    assert_eq!(blend(0), [200, 100, 0, 255]);
+    assert_eq!(blend(255), [0, 50, 100, 255]);
+    assert_eq!(blend(128), [100, 75, 50, 255]);
+  }
+
+  #[test]
+  fn alpha_blend_mode_renders_over_the_buffer() {
+    let mut compiled = compile("r = 0; g = 50; b = 100; a = x * 128;");
+    let frame = FrameParams {
+      blend: BlendMode::Alpha,
+      ..frame(&compiled, 2, 1)
+    };
+    let mut out = [200, 100, 0, 255, 200, 100, 0, 255];
+    compiled.render(frame, &mut out).unwrap();
+    assert_eq!(out, [200, 100, 0, 255, 100, 75, 50, 255]);
+  }
+
+  #[test]
+  fn trailing_tuple_sets_the_color() {
+    let options = ParseOptions {
+      implicit_color_output: true,
+    };
+    let mut compiled = compile_with(&options, "c = x * 10; c, c + 1, 7");
+    let frame = frame(&compiled, 2, 1);
+    let mut out = [0; 2 * 4];
+    compiled.render(frame, &mut out).unwrap();
+    assert_eq!(out, [0, 1, 7, 255, 10, 11, 7, 255]);
+  }
+
+  #[test]
+  fn trailing_non_tuple_is_a_parse_error() {
+    let options = ParseOptions {
+      implicit_color_output: true,
+    };
+    let mut context = ExecutionContext::default();
+    let error = options.parse(&mut context, "r = 1; g = 2; b = 3; x");
+    assert!(matches!(
+      error,
+      Err(ParseError::LanguageError(LanguageError {
+        error: LanguageErrorType::ImplicitOutputShape(1),
+        ..
+      }))
+    ));
+  }
+
+  fn buffer_error(result: Result<(), LanguageError>) -> (usize, usize) {
+    match result {
+      Err(LanguageError {
+        error: LanguageErrorType::OutputBuffer(needed, found),
+        location: None,
+      }) => (needed, found),
+      other => panic!("expected an output buffer error, got {other:?}"),
+    }
+  }
+
+  #[test]
+  fn render_frame_writes_rows_in_order() {
+    let mut compiled = compile("r = x; g = y; b = 0;");
+    let mut out = [0; 2 * 2 * 4];
+    compiled.render(frame(&compiled, 2, 2), &mut out).unwrap();
+    assert_eq!(
+      out,
+      [0, 0, 0, 255, 1, 0, 0, 255, 0, 1, 0, 255, 1, 1, 0, 255]
+    );
+  }
+
+  #[test]
+  fn render_leaves_the_rest_of_a_long_buffer_alone() {
+    let mut compiled = compile("r = 10; g = 20; b = 30;");
+    let mut out = [7; 2 * 4];
+    compiled.render(frame(&compiled, 1, 1), &mut out).unwrap();
+    assert_eq!(out, [10, 20, 30, 255, 7, 7, 7, 7]);
+  }
+
+  #[test]
+  fn render_rejects_a_short_buffer() {
+    let mut compiled = compile("r = 10; g = 20; b = 30;");
+    let mut out = [0; 3 * 4];
+    let result = compiled.render(frame(&compiled, 2, 2), &mut out);
+    assert_eq!(buffer_error(result), (16, 12));
+    assert_eq!(out, [0; 3 * 4]);
+  }
+
+  #[test]
+  fn render_rows_rejects_a_buffer_shorter_than_the_frame() {
+    let mut compiled = compile("r = 10; g = 20; b = 30;");
+    // Enough for the first row, but not the whole frame
+    let mut out = [0; 2 * 4];
+    let result = compiled.render_rows(frame(&compiled, 2, 2), 0..1, &mut out);
+    assert_eq!(buffer_error(result), (16, 8));
+  }
+
+  #[cfg(feature = "rayon")]
+  #[test]
+  fn render_frame_parallel_rejects_a_short_buffer() {
+    let mut context = ExecutionContext::default();
+    let program = ParseOptions::default()
+      .parse(&mut context, "r = 10; g = 20; b = 30;")
+      .unwrap();
+    let identifiers = FrameIdentifiers::register(&mut context);
+    let params = FrameParams {
+      identifiers,
+      ..frame(&compile("r = 0; g = 0; b = 0;"), 2, 2)
+    };
+    let mut out = [0; 4];
+    let result = render_frame_parallel(
+      &context.export_scope_locations(),
+      &program,
+      &params,
+      &mut out,
+    );
+    assert_eq!(buffer_error(result), (16, 4));
+  }
+
+  #[cfg(feature = "simd")]
+  #[test]
+  fn render_frame_simd_rejects_a_short_buffer() {
+    let mut context = ExecutionContext::default();
+    let program = ParseOptions::default()
+      .parse(&mut context, "r = 10; g = 20; b = 30;")
+      .unwrap();
+    let identifiers = FrameIdentifiers::register(&mut context);
+    let params = FrameParams {
+      identifiers,
+      ..frame(&compile("r = 0; g = 0; b = 0;"), 2, 2)
+    };
+    let mut out = [0; 4];
+    let result = crate::render_frame_simd(&mut context, &program, &params, &mut out);
+    assert_eq!(buffer_error(result), (16, 4));
+  }
+
+  #[test]
+  fn x_runs_along_rows_of_a_non_square_frame() {
+    let mut compiled = compile("r = x * 10; g = y * 10; b = 0;");
+    let mut out = [0; 3 * 2 * 4];
+    compiled.render(frame(&compiled, 3, 2), &mut out).unwrap();
+    let pixels = out.as_chunks::<4>().0;
+    assert_eq!(
+      pixels,
+      [
+        [0, 0, 0, 255],
+        [10, 0, 0, 255],
+        [20, 0, 0, 255],
+        [0, 10, 0, 255],
+        [10, 10, 0, 255],
+        [20, 10, 0, 255],
+      ]
+    );
+  }
+
+  #[cfg(feature = "rayon")]
+  fn parallel(source: &str, width: usize, height: usize) -> (Result<(), LanguageError>, Vec<u8>) {
+    let mut context = ExecutionContext::default();
+    let program = ParseOptions::default().parse(&mut context, source).unwrap();
+    let params = FrameParams {
+      identifiers: FrameIdentifiers::register(&mut context),
+      ..frame(&compile("r = 0; g = 0; b = 0;"), width, height)
+    };
+    let mut out = vec![0; width * height * 4];
+    let result = render_frame_parallel(
+      &context.export_scope_locations(),
+      &program,
+      &params,
+      &mut out,
+    );
+    (result, out)
+  }
+
+  #[cfg(feature = "rayon")]
+  #[test]
+  fn render_frame_parallel_matches_render_frame() {
+    let source = "t = [x, y, x * y];
+      if (x > y) { r = t[2] * 3; } else { r = t[0]; }
+      g = 0;
+      for v in t { g = g + v; }
+      b = (x ^ y) * 16;";
+    let mut compiled = compile(source);
+    let mut serial = vec![0; 7 * 5 * 4];
+    compiled
+      .render(frame(&compiled, 7, 5), &mut serial)
+      .unwrap();
+    let (result, out) = parallel(source, 7, 5);
+    result.unwrap();
+    assert_eq!(out, serial);
+  }
+
+  #[cfg(feature = "rayon")]
+  #[test]
+  fn render_frame_parallel_reports_the_first_failing_row() {
+    let source = "r = [1, 2, 3][y]; g = 0; b = 0;";
+    let mut compiled = compile(source);
+    let mut out = vec![0; 2 * 5 * 4];
+    let serial = compiled
+      .render(frame(&compiled, 2, 5), &mut out)
+      .unwrap_err();
+    let (result, _) = parallel(source, 2, 5);
+    let parallel = result.unwrap_err();
+    assert!(matches!(
+      parallel.error,
+      LanguageErrorType::Range(crate::TupleAccess::Index, 3.0, 3)
+    ));
+    assert_eq!(
+      format!("{:?}", parallel.location),
+      format!("{:?}", serial.location)
+    );
+  }
+
+  #[test]
+  fn variables_set_by_an_earlier_pixel_are_unset_in_the_next() {
+    let mut compiled = compile("if (x == 0) { t = 5; } r = t; g = 0; b = 0;");
+    let mut out = [0; 2 * 4];
+    let err = compiled
+      .render(frame(&compiled, 2, 1), &mut out)
+      .unwrap_err();
+    assert!(matches!(&err.error, LanguageErrorType::Reference(name) if name == "::t"));
+    assert_eq!(out[..4], [5, 0, 0, 255]);
+  }
+
+  #[test]
+  fn frame_variables_go_back_to_their_value_for_every_pixel() {
+    let mut compiled = compile("r = time; time = 99; g = 0; b = 0;");
+    let mut out = [0; 3 * 4];
+    let frame = FrameParams {
+      time: 7.0,
+      ..frame(&compiled, 3, 1)
+    };
+    compiled.render(frame, &mut out).unwrap();
+    assert_eq!(out, [7, 0, 0, 255, 7, 0, 0, 255, 7, 0, 0, 255]);
+  }
+
+  #[test]
+  fn color_channel_clamps_and_rejects_non_finite_values() {
+    assert_eq!(color_channel(12.7), Some(12));
+    assert_eq!(color_channel(300.0), Some(255));
+    assert_eq!(color_channel(-5.0), Some(0));
+    assert_eq!(color_channel(f32::NAN), None);
+    assert_eq!(color_channel(f32::INFINITY), None);
+    assert_eq!(color_channel(f32::NEG_INFINITY), None);
+  }
+
+  #[test]
+  fn non_finite_channels_are_an_error_by_default() {
+    for (source, pixel, nan) in [
+      ("r = x == 2 ? 1 / 0 : 0; g = 0; b = 0;", (2, 0), false),
+      ("r = 0; g = y == 1 ? 0 / 0 : 0; b = 0;", (0, 1), true),
+    ] {
+      let mut compiled = compile(source);
+      let mut out = [0; 3 * 2 * 4];
+      let err = compiled
+        .render(frame(&compiled, 3, 2), &mut out)
+        .unwrap_err();
+      match err.error {
+        LanguageErrorType::NonFiniteOutput(x, y, value) => {
+          assert_eq!((x, y), pixel, "{source}");
+          assert_eq!(value.is_nan(), nan, "{source}");
+        }
+        error => panic!("{source}: {error:?}"),
+      }
+    }
+  }
+
+  #[test]
+  fn non_finite_channels_saturate_when_asked() {
+    let mut compiled = compile("r = 1 / 0; g = 0 - 1 / 0; b = 0 / 0;");
+    let frame = FrameParams {
+      non_finite: NonFiniteMode::Saturate,
+      ..frame(&compiled, 1, 1)
+    };
+    let mut out = [7; 4];
+    compiled.render(frame, &mut out).unwrap();
+    assert_eq!(out, [255, 0, 0, 255]);
+  }
+
+  #[test]
+  fn unset_channels_are_an_error_naming_the_channel() {
+    for (source, channel) in [
+      ("g = 0; b = 0;", 'r'),
+      ("r = 0; b = 0;", 'g'),
+      ("r = 0; g = 0; if (x > 0) { b = 0; }", 'b'),
+    ] {
+      let mut compiled = compile(source);
+      let mut out = [0; 2 * 4];
+      let err = compiled
+        .render(frame(&compiled, 2, 1), &mut out)
+        .unwrap_err();
+      assert!(
+        matches!(err.error, LanguageErrorType::UnsetChannel(0, 0, unset) if unset == channel),
+        "{source}: {:?}",
+        err.error
+      );
+      assert_eq!(
+        err.error.to_string(),
+        format!("OutputError: Pixel (0, 0) finished without setting {channel}")
+      );
+    }
+  }
+
+  #[test]
+  fn unset_channels_are_zero_when_lenient() {
+    let mut compiled = compile("r = 10; if (x > 0) { b = 30; }");
+    let frame = FrameParams {
+      missing_channel: MissingChannelMode::Zero,
+      ..frame(&compiled, 2, 1)
+    };
+    let mut out = [7; 2 * 4];
+    compiled.render(frame, &mut out).unwrap();
+    assert_eq!(out, [10, 0, 0, 255, 10, 0, 30, 255]);
+  }
+
+  #[test]
+  fn u_runs_from_0_to_just_under_1_across_a_row() {
+    let mut compiled = compile("r = u * 256; g = (u < 1) * 255; b = 0;");
+    let mut out = [0; 256 * 4];
+    compiled.render(frame(&compiled, 256, 1), &mut out).unwrap();
+    let (pixels, _) = out.as_chunks::<4>();
+    for (x, pixel) in pixels.iter().enumerate() {
+      assert_eq!(pixel[..2], [x as u8, 255]);
+    }
+  }
+
+  #[test]
+  fn v_and_aspect_follow_the_frame_size() {
+    let mut compiled = compile("r = u * 4; g = v * 2; b = aspect * 10;");
+    let mut out = [0; 4 * 2 * 4];
+    compiled.render(frame(&compiled, 4, 2), &mut out).unwrap();
+    let (pixels, _) = out.as_chunks::<4>();
+    let colors = pixels
+      .iter()
+      .map(|pixel| pixel[..3].to_vec())
+      .collect::<Vec<_>>();
+    assert_eq!(
+      colors,
+      [
+        [0, 0, 20],
+        [1, 0, 20],
+        [2, 0, 20],
+        [3, 0, 20],
+        [0, 1, 20],
+        [1, 1, 20],
+        [2, 1, 20],
+        [3, 1, 20],
+      ]
+    );
+  }
+}
@@ -0,0 +1,391 @@
+use crate::analysis::walk_expression;
+use crate::render::{frame_buffer, render_pixel_at, render_rows, start_frame, to_bytes};
+use crate::{
+  ExecutionContext, Expression, ExpressionOp, FrameIdentifiers, FrameParams, FunctionIdentifier,
+  Identifier, LanguageError, MathFunction, ParsedLanguage, Statement, Value,
+};
+use std::simd::prelude::*;
+use std::simd::{Select, StdFloat};
+
+const LANES: usize = 4;
+type Lanes = Simd<f32, LANES>;
+
+// Experimental take on render_frame that runs four horizontally adjacent
+// pixels at once. Only straight-line arithmetic is vectorized: programs with
+// control flow, tuples or calls into other functions, along with any group of
+// pixels that reads a variable before it's set, go through the scalar path.
+// Either way, `out` ends up the same as it would with render_frame
+pub fn render_frame_simd(
+  context: &mut ExecutionContext,
+  program: &ParsedLanguage,
+  params: &FrameParams,
+  out: &mut [u8],
+) -> Result<(), LanguageError> {
+  let out = frame_buffer(params, out)?;
+  start_frame(context, params)?;
+  // Tuples and strings from the invariant half or the host can't go in a lane
+  let baseline = (0..context.scope.len())
+    .map(|identifier| match context.slot(identifier) {
+      Some(Value::Number(number)) => Some(Some(Lanes::splat(*number))),
+      Some(Value::Tuple(_) | Value::Str(_)) => None,
+      None => Some(None),
+    })
+    .collect::<Option<Vec<Option<Lanes>>>>();
+  let (Some(baseline), Some(written)) = (baseline, vectorizable(program)) else {
+    return render_rows(context, program, params, 0, out);
+  };
+
+  let FrameIdentifiers { x, y, u, v, a, .. } = params.identifiers;
+  let (width, height) = (params.width as f32, params.height as f32);
+  let mut lanes = baseline.clone();
+  let offsets = Lanes::from_array([0.0, 1.0, 2.0, 3.0]);
+  for (row_index, row) in out.chunks_exact_mut(params.width * 4).enumerate() {
+    let mut groups = row.chunks_exact_mut(LANES * 4);
+    for (group_index, group) in groups.by_ref().enumerate() {
+      for identifier in &written {
+        lanes[*identifier] = baseline[*identifier];
+      }
+      let xs = Lanes::splat((group_index * LANES) as f32) + offsets;
+      lanes[x] = Some(xs);
+      lanes[y] = Some(Lanes::splat(row_index as f32));
+      lanes[u] = Some(xs / Lanes::splat(width));
+      lanes[v] = Some(Lanes::splat(row_index as f32 / height));
+      let Some([red, green, blue]) = run(program, &params.identifiers, &mut lanes) else {
+        // Let the scalar path work out what went wrong
+        for (lane, pixel) in group.as_chunks_mut().0.iter_mut().enumerate() {
+          let x = group_index * LANES + lane;
+          render_pixel_at(context, program, params, x, row_index, pixel)?;
+        }
+        continue;
+      };
+      let alpha = lanes[a].unwrap_or(Lanes::splat(255.0));
+      for (lane, pixel) in group.as_chunks_mut().0.iter_mut().enumerate() {
+        let color = [red[lane], green[lane], blue[lane], alpha[lane]];
+        let x = group_index * LANES + lane;
+        params
+          .blend
+          .blend(pixel, to_bytes(color, params.non_finite, x, row_index)?);
+      }
+    }
+    let remainder = groups.into_remainder();
+    let first = params.width - remainder.len() / 4;
+    for (x, pixel) in (first..).zip(remainder.as_chunks_mut().0) {
+      render_pixel_at(context, program, params, x, row_index, pixel)?;
+    }
+  }
+  Ok(())
+}
+
+// The variables `program` assigns, or None if it does anything that can't be
+// vectorized
+fn vectorizable(program: &ParsedLanguage) -> Option<Vec<Identifier>> {
+  let mut written = Vec::new();
+  let mut expressions = Vec::new();
+  for statement in &program.top_level {
+    let Statement::Assignment { variable, value } = statement else {
+      return None;
+    };
+    written.push(*variable);
+    expressions.push(value);
+  }
+  match &program.output {
+    Some(Expression {
+      op: ExpressionOp::TupleLiteral(channels),
+      ..
+    }) => expressions.extend(channels),
+    Some(_) => return None,
+    None => {}
+  }
+  let mut supported = true;
+  for expression in expressions {
+    walk_expression(expression, &mut |expression| {
+      supported &= matches!(
+        &expression.op,
+        ExpressionOp::NumberLiteral(_)
+          | ExpressionOp::Reference(_)
+          | ExpressionOp::Add(..)
+          | ExpressionOp::Sub(..)
+          | ExpressionOp::Mul(..)
+          | ExpressionOp::Div(..)
+          | ExpressionOp::FloorDiv(..)
+          | ExpressionOp::Modulo(..)
+          | ExpressionOp::BinaryAnd(..)
+          | ExpressionOp::BinaryOr(..)
+          | ExpressionOp::Xor(..)
+          | ExpressionOp::GreaterThan(..)
+          | ExpressionOp::LessThan(..)
+          | ExpressionOp::GreaterThanOrEqual(..)
+          | ExpressionOp::LessThanOrEqual(..)
+          | ExpressionOp::Equal(..)
+          | ExpressionOp::NotEqual(..)
+          | ExpressionOp::And(..)
+          | ExpressionOp::Or(..)
+          | ExpressionOp::Ternary(..)
+          | ExpressionOp::Neg(_)
+          | ExpressionOp::Invert(_)
+          | ExpressionOp::FunctionCall(
+            FunctionIdentifier::Math(
+              MathFunction::Sin
+                | MathFunction::Cos
+                | MathFunction::Abs
+                | MathFunction::Sqrt
+                | MathFunction::Floor
+                | MathFunction::Ceil
+                | MathFunction::Round
+            ) | FunctionIdentifier::Min
+              | FunctionIdentifier::Max
+              | FunctionIdentifier::Clamp,
+            _
+          )
+      );
+      // The lanes use std's sin and cos, which may not match libm's
+      #[cfg(feature = "deterministic")]
+      {
+        supported &= !matches!(
+          &expression.op,
+          ExpressionOp::FunctionCall(
+            FunctionIdentifier::Math(MathFunction::Sin | MathFunction::Cos),
+            _
+          )
+        );
+      }
+    });
+  }
+  supported.then_some(written)
+}
+
+// Runs a vectorizable program, returning its r, g and b. None if it read a
+// variable that wasn't set
+fn run(
+  program: &ParsedLanguage,
+  identifiers: &FrameIdentifiers,
+  lanes: &mut [Option<Lanes>],
+) -> Option<[Lanes; 3]> {
+  for statement in &program.top_level {
+    if let Statement::Assignment { variable, value } = statement {
+      lanes[*variable] = Some(evaluate(value, lanes)?);
+    }
+  }
+  let mut color = [Lanes::splat(0.0); 3];
+  match &program.output {
+    Some(Expression {
+      op: ExpressionOp::TupleLiteral(channels),
+      ..
+    }) => {
+      for (channel, expression) in color.iter_mut().zip(channels) {
+        *channel = evaluate(expression, lanes)?;
+      }
+    }
+    _ => {
+      for (channel, identifier) in
+        color
+          .iter_mut()
+          .zip([identifiers.r, identifiers.g, identifiers.b])
+      {
+        *channel = lanes[identifier]?;
+      }
+    }
+  }
+  Some(color)
+}
+
+fn evaluate(expression: &Expression, lanes: &[Option<Lanes>]) -> Option<Lanes> {
+  let zero = Lanes::splat(0.0);
+  let one = Lanes::splat(1.0);
+  // Matches the scalar path's bits() before bitwise operators
+  let bitwise = |lhs: &Expression, rhs: &Expression, op: fn(u32x4, u32x4) -> u32x4| {
+    let bits = |value: Lanes| value.cast::<i64>().cast::<u32>();
+    Some(op(bits(evaluate(lhs, lanes)?), bits(evaluate(rhs, lanes)?)).cast())
+  };
+  let compare = |mask: mask32x4| mask.select(one, zero);
+  Some(match &expression.op {
+    ExpressionOp::NumberLiteral(number) => Lanes::splat(*number),
+    ExpressionOp::Reference(identifier) => lanes[*identifier]?,
+    ExpressionOp::Add(lhs, rhs) => evaluate(lhs, lanes)? + evaluate(rhs, lanes)?,
+    ExpressionOp::Sub(lhs, rhs) => evaluate(lhs, lanes)? - evaluate(rhs, lanes)?,
+    ExpressionOp::Mul(lhs, rhs) => evaluate(lhs, lanes)? * evaluate(rhs, lanes)?,
+    ExpressionOp::Div(lhs, rhs) => evaluate(lhs, lanes)? / evaluate(rhs, lanes)?,
+    ExpressionOp::FloorDiv(lhs, rhs) => (evaluate(lhs, lanes)? / evaluate(rhs, lanes)?).floor(),
+    ExpressionOp::Modulo(lhs, rhs) => evaluate(lhs, lanes)? % evaluate(rhs, lanes)?,
+    ExpressionOp::BinaryAnd(lhs, rhs) => bitwise(lhs, rhs, |lhs, rhs| lhs & rhs)?,
+    ExpressionOp::BinaryOr(lhs, rhs) => bitwise(lhs, rhs, |lhs, rhs| lhs | rhs)?,
+    ExpressionOp::Xor(lhs, rhs) => bitwise(lhs, rhs, |lhs, rhs| lhs ^ rhs)?,
+    ExpressionOp::GreaterThan(lhs, rhs) => {
+      compare(evaluate(lhs, lanes)?.simd_gt(evaluate(rhs, lanes)?))
+    }
+    ExpressionOp::LessThan(lhs, rhs) => {
+      compare(evaluate(lhs, lanes)?.simd_lt(evaluate(rhs, lanes)?))
+    }
+    ExpressionOp::GreaterThanOrEqual(lhs, rhs) => {
+      compare(evaluate(lhs, lanes)?.simd_ge(evaluate(rhs, lanes)?))
+    }
+    ExpressionOp::LessThanOrEqual(lhs, rhs) => {
+      compare(evaluate(lhs, lanes)?.simd_le(evaluate(rhs, lanes)?))
+    }
+    ExpressionOp::Equal(lhs, rhs) => compare(evaluate(lhs, lanes)?.simd_eq(evaluate(rhs, lanes)?)),
+    ExpressionOp::NotEqual(lhs, rhs) => {
+      compare(evaluate(lhs, lanes)?.simd_ne(evaluate(rhs, lanes)?))
+    }
+    // Both sides get evaluated for every lane, which is only safe because
+    // nothing vectorizable has side effects
+    ExpressionOp::And(lhs, rhs) => {
+      let lhs = evaluate(lhs, lanes)?;
+      lhs.simd_ne(zero).select(evaluate(rhs, lanes)?, zero)
+    }
+    ExpressionOp::Or(lhs, rhs) => {
+      let lhs = evaluate(lhs, lanes)?;
+      lhs.simd_ne(zero).select(lhs, evaluate(rhs, lanes)?)
+    }
+    ExpressionOp::Ternary(condition, if_true, if_false) => evaluate(condition, lanes)?
+      .simd_ne(zero)
+      .select(evaluate(if_true, lanes)?, evaluate(if_false, lanes)?),
+    ExpressionOp::Neg(value) => -evaluate(value, lanes)?,
+    ExpressionOp::Invert(value) => evaluate(value, lanes)?.simd_eq(zero).select(one, zero),
+    ExpressionOp::FunctionCall(function, arguments) => {
+      let values = arguments
+        .iter()
+        .map(|argument| evaluate(argument, lanes))
+        .collect::<Option<Vec<Lanes>>>()?;
+      match function {
+        FunctionIdentifier::Math(MathFunction::Sin) => values[0].sin(),
+        FunctionIdentifier::Math(MathFunction::Cos) => values[0].cos(),
+        FunctionIdentifier::Math(MathFunction::Abs) => values[0].abs(),
+        FunctionIdentifier::Math(MathFunction::Sqrt) => values[0].sqrt(),
+        FunctionIdentifier::Math(MathFunction::Floor) => values[0].floor(),
+        FunctionIdentifier::Math(MathFunction::Ceil) => values[0].ceil(),
+        FunctionIdentifier::Math(MathFunction::Round) => values[0].round(),
+        FunctionIdentifier::Min => values[1..]
+          .iter()
+          .fold(values[0], |result, value| result.simd_min(*value)),
+        FunctionIdentifier::Max => values[1..]
+          .iter()
+          .fold(values[0], |result, value| result.simd_max(*value)),
+        FunctionIdentifier::Clamp => values[0].simd_max(values[1]).simd_min(values[2]),
+        _ => unreachable!(),
+      }
+    }
+    _ => unreachable!(),
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{run, vectorizable, Lanes};
+  use crate::*;
+
+  fn params(identifiers: FrameIdentifiers, width: usize, height: usize) -> FrameParams<'static> {
+    FrameParams {
+      width,
+      height,
+      time: 3.0,
+      random: 0.5,
+      identifiers,
+      variables: &[],
+      invariant: None,
+      clear_color: [0, 0, 0, 255],
+      blend: BlendMode::Replace,
+      non_finite: NonFiniteMode::Error,
+      missing_channel: MissingChannelMode::Error,
+    }
+  }
+
+  // The SIMD and scalar renderers' output for `source`, along with the
+  // first error each ran into
+  #[allow(clippy::type_complexity)]
+  fn both(
+    source: &str,
+    width: usize,
+    height: usize,
+  ) -> ((Vec<u8>, Option<String>), (Vec<u8>, Option<String>)) {
+    let mut context = ExecutionContext::default();
+    let program = ParseOptions::default().parse(&mut context, source).unwrap();
+    let params = params(FrameIdentifiers::register(&mut context), width, height);
+    let mut render = |simd: bool| {
+      let mut out = vec![0; width * height * 4];
+      let result = if simd {
+        render_frame_simd(&mut context, &program, &params, &mut out)
+      } else {
+        render_frame(&mut context, &program, &params, &mut out)
+      };
+      (out, result.err().map(|err| format!("{err:?}")))
+    };
+    (render(true), render(false))
+  }
+
+  // Whether `source` runs through the vector path rather than falling back
+  // to the scalar one, with x, y, u, v, time and random set
+  fn vectorized(source: &str) -> bool {
+    let mut context = ExecutionContext::default();
+    let program = ParseOptions::default().parse(&mut context, source).unwrap();
+    let identifiers = FrameIdentifiers::register(&mut context);
+    if vectorizable(&program).is_none() {
+      return false;
+    }
+    let mut lanes = vec![None; context.scope.len()];
+    let FrameIdentifiers {
+      x,
+      y,
+      u,
+      v,
+      time,
+      random,
+      ..
+    } = identifiers;
+    for identifier in [x, y, u, v, time, random] {
+      lanes[identifier] = Some(Lanes::from_array([0.0, 1.0, 2.0, 3.0]));
+    }
+    run(&program, &identifiers, &mut lanes).is_some()
+  }
+
+  #[test]
+  fn arithmetic_matches_the_scalar_renderer() {
+    let source = "t = x * 3.5 - y / 2;
+      r = (t * time + random) % 256;
+      g = abs(sin(x / 3) * 200) + sqrt(y) * ceil(y / 2) - cos(t);
+      b = floor(t) ^ (x * 4) | y & 7 + round(x ~/ 3);";
+    // sin and cos stay scalar so deterministic builds get libm's results
+    assert_eq!(vectorized(source), !cfg!(feature = "deterministic"));
+    // 7 wide, so each row ends with a group narrower than the lanes
+    let (simd, scalar) = both(source, 7, 3);
+    assert_eq!(simd.1, None);
+    assert_eq!(simd, scalar);
+  }
+
+  #[test]
+  fn comparisons_and_logic_match_the_scalar_renderer() {
+    let source = "r = x > 2 ? clamp(x * 40, 0, 255) : min(x, y, 1);
+      g = (x && y) * 100 + (x || 7) * 10 + !(x == y) + (x != 1);
+      b = max(-x, y) + (y <= 1) * 2 + (x >= y) * 4 + (x < y) * 8;";
+    assert!(vectorized(source));
+    let (simd, scalar) = both(source, 9, 4);
+    assert_eq!(simd.1, None);
+    assert_eq!(simd, scalar);
+  }
+
+  #[test]
+  fn everything_else_falls_back_to_the_scalar_renderer() {
+    for source in [
+      "c = [x, y, x + y];
+        if (x > 2) { r = c[2] * 10; } else { r = 0; }
+        g = max(x, y); b = len(c);",
+      "r = sqrt(y) ** 2; g = x << 2; b = x >> 1;",
+    ] {
+      assert!(!vectorized(source), "{source}");
+      let (simd, scalar) = both(source, 6, 2);
+      assert_eq!(simd.1, None);
+      assert_eq!(simd, scalar);
+    }
+  }
+
+  #[test]
+  fn errors_match_the_scalar_renderer() {
+    for source in [
+      "r = 1 / (x - 2); g = 0; b = 0;",
+      "if (x > 1) { t = 1; } r = t; g = 0; b = 0;",
+    ] {
+      let (simd, scalar) = both(source, 5, 2);
+      assert!(simd.1.is_some(), "{source}");
+      assert_eq!(simd.1, scalar.1, "{source}");
+    }
+  }
+}
@@ -0,0 +1,417 @@
+use crate::{
+  ElseBranch, ExecutionContextLUT, Expression, ExpressionOp, FunctionIdentifier, Identifier,
+  IfStatement, MathFunction, ParsedLanguage, RepeatStatement, Statement,
+};
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+// Binding strength of each kind of expression, mirroring PRATT_PARSER. An
+// operand that binds looser than its position allows gets parenthesized
+const TERNARY: u8 = 0;
+const LOGICAL: u8 = 1;
+const COMPARISON: u8 = 2;
+const BITWISE: u8 = 3;
+const ADDITIVE: u8 = 4;
+const MULTIPLICATIVE: u8 = 5;
+const POWER: u8 = 6;
+const PREFIX: u8 = 7;
+const POSTFIX: u8 = 9;
+const PRIMARY: u8 = 10;
+
+impl ParsedLanguage {
+  // Reproduces source that parses back to an equivalent program. `lut` must
+  // come from the context the program was parsed with
+  pub fn to_source(&self, lut: &ExecutionContextLUT) -> String {
+    let printer = SourcePrinter {
+      lut,
+      parsed: self,
+      source: String::new(),
+    };
+    printer.print()
+  }
+}
+
+struct SourcePrinter<'a> {
+  lut: &'a ExecutionContextLUT,
+  parsed: &'a ParsedLanguage,
+  source: String,
+}
+
+impl<'a> SourcePrinter<'a> {
+  fn print(mut self) -> String {
+    for function in &self.parsed.functions {
+      let arguments = function
+        .arguments
+        .iter()
+        .map(|argument| self.variable(*argument))
+        .collect::<Vec<&str>>()
+        .join(", ");
+      writeln!(self.source, "function {}({arguments}) {{", function.name).unwrap();
+      self.statements(&function.contents, 1);
+      self.source.push_str("}\n");
+    }
+    self.statements(&self.parsed.top_level, 0);
+    if let Some(Expression {
+      op: ExpressionOp::TupleLiteral(channels),
+      ..
+    }) = &self.parsed.output
+    {
+      let channels = channels
+        .iter()
+        .map(|channel| self.expression(channel, TERNARY))
+        .collect::<Vec<String>>()
+        .join(", ");
+      writeln!(self.source, "{channels}").unwrap();
+    }
+    self.source
+  }
+
+  fn variable(&self, identifier: Identifier) -> &'a str {
+    &self
+      .lut
+      .scope_locations
+      .get_by_right(&identifier)
+      .unwrap()
+      .name
+  }
+
+  fn indent(&mut self, depth: usize) {
+    for _ in 0..depth {
+      self.source.push_str("  ");
+    }
+  }
+
+  fn statements(&mut self, statements: &[Statement], depth: usize) {
+    for statement in statements {
+      self.indent(depth);
+      match statement {
+        Statement::Assignment { variable, value } => {
+          let value = self.expression(value, TERNARY);
+          writeln!(self.source, "{} = {value};", self.variable(*variable)).unwrap();
+        }
+        Statement::Return(value) => {
+          let value = self.expression(value, TERNARY);
+          writeln!(self.source, "return {value};").unwrap();
+        }
+        Statement::Call(call) => {
+          let call = self.expression(call, TERNARY);
+          writeln!(self.source, "{call};").unwrap();
+        }
+        Statement::Break => self.source.push_str("break;\n"),
+        Statement::Continue => self.source.push_str("continue;\n"),
+        Statement::Repeat(RepeatStatement {
+          variable,
+          times,
+          block,
+        }) => {
+          writeln!(
+            self.source,
+            "repeat ({} until {times}) {{",
+            self.variable(*variable)
+          )
+          .unwrap();
+          self.block(block, depth);
+        }
+        Statement::ForEach {
+          binding,
+          iterable,
+          body,
+        } => {
+          let iterable = self.expression(iterable, TERNARY);
+          writeln!(
+            self.source,
+            "for {} in {iterable} {{",
+            self.variable(*binding)
+          )
+          .unwrap();
+          self.block(body, depth);
+        }
+        Statement::If(if_statement) => self.if_statement(if_statement, depth),
+      }
+    }
+  }
+
+  // Body of a braced block whose opening line has already been written
+  fn block(&mut self, statements: &[Statement], depth: usize) {
+    self.statements(statements, depth + 1);
+    self.indent(depth);
+    self.source.push_str("}\n");
+  }
+
+  fn if_statement(&mut self, if_statement: &IfStatement, depth: usize) {
+    let condition = self.expression(&if_statement.condition, TERNARY);
+    writeln!(self.source, "if ({condition}) {{").unwrap();
+    self.statements(&if_statement.if_branch, depth + 1);
+    self.indent(depth);
+    match &if_statement.else_branch {
+      ElseBranch::IfStatement(else_if) => {
+        self.source.push_str("} else ");
+        self.if_statement(else_if, depth);
+      }
+      ElseBranch::ElseStatement(else_block) => {
+        self.source.push_str("} else {\n");
+        self.block(else_block, depth);
+      }
+      ElseBranch::None => self.source.push_str("}\n"),
+    }
+  }
+
+  // Prints `expression`, parenthesized unless it binds at least as tightly as
+  // `minimum`
+  fn expression(&self, expression: &Expression, minimum: u8) -> String {
+    let (precedence, source) = self.unparenthesized(expression);
+    if precedence < minimum {
+      format!("({source})")
+    } else {
+      source
+    }
+  }
+
+  fn unparenthesized(&self, expression: &Expression) -> (u8, String) {
+    let binary = |precedence: u8, operator: &str, lhs: &Expression, rhs: &Expression| {
+      (
+        precedence,
+        format!(
+          "{} {operator} {}",
+          self.expression(lhs, precedence),
+          self.expression(rhs, precedence + 1)
+        ),
+      )
+    };
+    match &expression.op {
+      ExpressionOp::NumberLiteral(number) if number.is_sign_negative() => {
+        (PRIMARY, format!("(-{})", -number))
+      }
+      ExpressionOp::NumberLiteral(number) => (PRIMARY, number.to_string()),
+      ExpressionOp::StringLiteral(string) => (
+        PRIMARY,
+        format!("\"{}\"", string.replace('\\', "\\\\").replace('"', "\\\"")),
+      ),
+      ExpressionOp::Reference(identifier) => (PRIMARY, self.variable(*identifier).to_string()),
+      ExpressionOp::TupleLiteral(values) | ExpressionOp::ConstantTuple(_, values) => {
+        (PRIMARY, format!("[{}]", self.list(values)))
+      }
+      ExpressionOp::FunctionCall(
+        function @ (FunctionIdentifier::Map(identifier) | FunctionIdentifier::Reduce(identifier)),
+        arguments,
+      ) => (
+        PRIMARY,
+        format!(
+          "{}({}, {})",
+          self.function(function),
+          self.parsed.functions[*identifier].name,
+          self.list(arguments)
+        ),
+      ),
+      ExpressionOp::FunctionCall(function, arguments) => (
+        PRIMARY,
+        format!("{}({})", self.function(function), self.list(arguments)),
+      ),
+      ExpressionOp::Index(tuple, index) => (
+        POSTFIX,
+        format!(
+          "{}[{}]",
+          self.expression(tuple, POSTFIX),
+          self.expression(index, TERNARY)
+        ),
+      ),
+      ExpressionOp::Slice(tuple, start, end) => {
+        let bound = |bound: &Option<Box<Expression>>| match bound {
+          Some(bound) => self.expression(bound, LOGICAL),
+          None => String::new(),
+        };
+        (
+          POSTFIX,
+          format!(
+            "{}[{}:{}]",
+            self.expression(tuple, POSTFIX),
+            bound(start),
+            bound(end)
+          ),
+        )
+      }
+      ExpressionOp::Neg(value) => (PREFIX, format!("-{}", self.expression(value, PREFIX))),
+      ExpressionOp::Invert(value) => (PREFIX, format!("!{}", self.expression(value, PREFIX))),
+      // Right associative, so the parentheses go on the other side
+      ExpressionOp::Pow(lhs, rhs) => (
+        POWER,
+        format!(
+          "{} ** {}",
+          self.expression(lhs, POWER + 1),
+          self.expression(rhs, POWER)
+        ),
+      ),
+      ExpressionOp::Mul(lhs, rhs) => binary(MULTIPLICATIVE, "*", lhs, rhs),
+      ExpressionOp::Div(lhs, rhs) => binary(MULTIPLICATIVE, "/", lhs, rhs),
+      ExpressionOp::FloorDiv(lhs, rhs) => binary(MULTIPLICATIVE, "~/", lhs, rhs),
+      ExpressionOp::Modulo(lhs, rhs) => binary(MULTIPLICATIVE, "%", lhs, rhs),
+      ExpressionOp::Add(lhs, rhs) => binary(ADDITIVE, "+", lhs, rhs),
+      ExpressionOp::Sub(lhs, rhs) => binary(ADDITIVE, "-", lhs, rhs),
+      ExpressionOp::BinaryAnd(lhs, rhs) => binary(BITWISE, "&", lhs, rhs),
+      ExpressionOp::BinaryOr(lhs, rhs) => binary(BITWISE, "|", lhs, rhs),
+      ExpressionOp::Xor(lhs, rhs) => binary(BITWISE, "^", lhs, rhs),
+      ExpressionOp::ShiftLeft(lhs, rhs) => binary(BITWISE, "<<", lhs, rhs),
+      ExpressionOp::ShiftRight(lhs, rhs) => binary(BITWISE, ">>", lhs, rhs),
+      ExpressionOp::Equal(lhs, rhs) => binary(COMPARISON, "==", lhs, rhs),
+      ExpressionOp::NotEqual(lhs, rhs) => binary(COMPARISON, "!=", lhs, rhs),
+      ExpressionOp::LessThan(lhs, rhs) => binary(COMPARISON, "<", lhs, rhs),
+      ExpressionOp::GreaterThan(lhs, rhs) => binary(COMPARISON, ">", lhs, rhs),
+      ExpressionOp::LessThanOrEqual(lhs, rhs) => binary(COMPARISON, "<=", lhs, rhs),
+      ExpressionOp::GreaterThanOrEqual(lhs, rhs) => binary(COMPARISON, ">=", lhs, rhs),
+      ExpressionOp::And(lhs, rhs) => binary(LOGICAL, "&&", lhs, rhs),
+      ExpressionOp::Or(lhs, rhs) => binary(LOGICAL, "||", lhs, rhs),
+      ExpressionOp::Ternary(condition, if_true, if_false) => (
+        TERNARY,
+        format!(
+          "{} ? {} : {}",
+          self.expression(condition, LOGICAL),
+          self.expression(if_true, TERNARY),
+          self.expression(if_false, TERNARY)
+        ),
+      ),
+    }
+  }
+
+  fn list(&self, expressions: &[Expression]) -> String {
+    expressions
+      .iter()
+      .map(|expression| self.expression(expression, TERNARY))
+      .collect::<Vec<String>>()
+      .join(", ")
+  }
+
+  fn function(&self, function: &FunctionIdentifier) -> &'a str {
+    match function {
+      FunctionIdentifier::Math(MathFunction::Sin) => "sin",
+      FunctionIdentifier::Math(MathFunction::Cos) => "cos",
+      FunctionIdentifier::Math(MathFunction::Tan) => "tan",
+      FunctionIdentifier::Math(MathFunction::Abs) => "abs",
+      FunctionIdentifier::Math(MathFunction::Sqrt) => "sqrt",
+      FunctionIdentifier::Log => "log",
+      FunctionIdentifier::Math(MathFunction::Acos) => "acos",
+      FunctionIdentifier::Math(MathFunction::Asin) => "asin",
+      FunctionIdentifier::Math(MathFunction::Atan) => "atan",
+      FunctionIdentifier::Atan2 => "atan2",
+      FunctionIdentifier::Math(MathFunction::Floor) => "floor",
+      FunctionIdentifier::Math(MathFunction::Ceil) => "ceil",
+      FunctionIdentifier::Math(MathFunction::Round) => "round",
+      FunctionIdentifier::Mod => "mod",
+      FunctionIdentifier::Min => "min",
+      FunctionIdentifier::Max => "max",
+      FunctionIdentifier::Clamp => "clamp",
+      FunctionIdentifier::Step => "step",
+      FunctionIdentifier::Smoothstep => "smoothstep",
+      FunctionIdentifier::Hsv => "hsv",
+      FunctionIdentifier::Len => "len",
+      FunctionIdentifier::Range => "range",
+      FunctionIdentifier::Sum => "sum",
+      FunctionIdentifier::Product => "product",
+      FunctionIdentifier::Reverse => "reverse",
+      FunctionIdentifier::IndexWrap => "index_wrap",
+      FunctionIdentifier::IndexClamp => "index_clamp",
+      FunctionIdentifier::Dot => "dot",
+      FunctionIdentifier::Magnitude => "magnitude",
+      FunctionIdentifier::Normalize => "normalize",
+      FunctionIdentifier::Mix => "mix",
+      FunctionIdentifier::Quantize => "quantize",
+      FunctionIdentifier::Dither => "dither",
+      FunctionIdentifier::Random => "random",
+      FunctionIdentifier::Noise => "noise",
+      FunctionIdentifier::Print => "print",
+      FunctionIdentifier::Assert => "assert",
+      FunctionIdentifier::Map(_) => "map",
+      FunctionIdentifier::Reduce(_) => "reduce",
+      FunctionIdentifier::UserDefined(identifier) => &self.parsed.functions[*identifier].name,
+      FunctionIdentifier::Host(identifier) => self
+        .lut
+        .host_function_names
+        .iter()
+        .find(|(_, index)| *index == identifier)
+        .map(|(name, _)| name.as_str())
+        .unwrap(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::{execute, ExecutionContext, ParseOptions, ParsedLanguage, Value};
+  use alloc::string::String;
+
+  const NESTED: &str = "function shade(v, k) {
+  if (v > k) {
+    return (v - k) * 2;
+  } else if (v == k) {
+    return 0 - 1;
+  } else {
+    return -v ** 2;
+  }
+}
+t = [x, y, 3][1:];
+repeat (i until 3) {
+  r = shade(x, i) | 1;
+}
+for v in [1, 2] {
+  r = r + v;
+}
+if (x < 2 && y >= 1 || time) {
+  g = y > 1 ? 2 : 3;
+} else {
+  g = (1 + 2) * 3 % 4;
+}
+b = t[0];
+";
+
+  fn parse(source: &str) -> (ExecutionContext, ParsedLanguage) {
+    let mut context = ExecutionContext::default();
+    let program = ParseOptions::default().parse(&mut context, source).unwrap();
+    (context, program)
+  }
+
+  fn printed(source: &str) -> String {
+    let (context, program) = parse(source);
+    program.to_source(&context.export_scope_locations())
+  }
+
+  fn color(source: &str, x: f32, y: f32) -> [f32; 3] {
+    let (mut context, program) = parse(source);
+    for (name, value) in [("x", x), ("y", y), ("time", 0.0)] {
+      context.set_runtime(name, Value::number(value));
+    }
+    Result::from(execute(&mut context, &program)).unwrap();
+    ["r", "g", "b"].map(|name| context.get_runtime(name).unwrap().as_number().unwrap())
+  }
+
+  #[test]
+  fn nested_program_round_trips() {
+    let source = printed(NESTED);
+    assert_eq!(source, NESTED);
+    assert_eq!(printed(&source), source);
+    for (x, y) in [(0.0, 0.0), (1.0, 2.0), (2.0, 2.0), (5.0, 1.0)] {
+      assert_eq!(
+        color(&source, x, y),
+        color(NESTED, x, y),
+        "x = {x}, y = {y}"
+      );
+    }
+  }
+
+  #[test]
+  fn parentheses_only_where_precedence_needs_them() {
+    for (source, expected) in [
+      ("r = ((1 + 2) + 3);", "r = 1 + 2 + 3;\n"),
+      ("r = 1 - (2 - 3);", "r = 1 - (2 - 3);\n"),
+      ("r = (1 + 2) * 3;", "r = (1 + 2) * 3;\n"),
+      ("r = 1 + (2 * 3);", "r = 1 + 2 * 3;\n"),
+      ("r = 2 ** (3 ** 2);", "r = 2 ** 3 ** 2;\n"),
+      ("r = (2 ** 3) ** 2;", "r = (2 ** 3) ** 2;\n"),
+      ("r = -(1 + 2);", "r = -(1 + 2);\n"),
+      ("r = (1 ? 2 : 3) + 4;", "r = (1 ? 2 : 3) + 4;\n"),
+    ] {
+      assert_eq!(printed(source), expected, "{source}");
+    }
+  }
+}
@@ -0,0 +1,395 @@
+use crate::{
+  ElseBranch, ExecutionContextLUT, Expression, ExpressionOp, FunctionIdentifier, Identifier,
+  LanguageError, LanguageErrorType, ParsedLanguage, RepeatStatement, Statement, ValueType,
+};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+impl ParsedLanguage {
+  // Flags expressions that are bound to fail with a TypeError at runtime, e.g.
+  // `sin([1, 2])` or `r = [1, 2]`. Values are dynamic, so anything whose type
+  // can't be pinned down is let through. `lut` must come from the context the
+  // program was parsed with
+  pub fn typecheck(&self, lut: &ExecutionContextLUT) -> Result<(), Vec<LanguageError>> {
+    // Variables assigned more than one type are unknown, which takes a few
+    // rounds to settle when variables are assigned from each other
+    let mut variables = BTreeMap::new();
+    for _ in 0..=lut.scope_locations.len() {
+      let mut checker = TypeChecker {
+        lut,
+        variables: &variables,
+        assigned: BTreeMap::new(),
+        errors: Vec::new(),
+      };
+      checker.program(self);
+      if checker.assigned == variables {
+        break;
+      }
+      variables = checker.assigned;
+    }
+
+    let mut checker = TypeChecker {
+      lut,
+      variables: &variables,
+      assigned: BTreeMap::new(),
+      errors: Vec::new(),
+    };
+    checker.program(self);
+    if checker.errors.is_empty() {
+      Ok(())
+    } else {
+      Err(checker.errors)
+    }
+  }
+}
+
+struct TypeChecker<'a> {
+  lut: &'a ExecutionContextLUT,
+  // Types settled on by the previous round, None if it varies
+  variables: &'a BTreeMap<Identifier, Option<ValueType>>,
+  // Types assigned during this round
+  assigned: BTreeMap<Identifier, Option<ValueType>>,
+  errors: Vec<LanguageError>,
+}
+
+impl TypeChecker<'_> {
+  fn program(&mut self, parsed: &ParsedLanguage) {
+    for function in &parsed.functions {
+      // Arguments can be anything
+      for argument in &function.arguments {
+        self.assign(*argument, None);
+      }
+      self.statements(&function.contents, false);
+    }
+    self.statements(&parsed.top_level, true);
+    if let Some(Expression {
+      op: ExpressionOp::TupleLiteral(channels),
+      ..
+    }) = &parsed.output
+    {
+      for channel in channels {
+        self.expect(channel, ValueType::Number);
+      }
+    }
+  }
+
+  fn assign(&mut self, variable: Identifier, value_type: Option<ValueType>) {
+    self
+      .assigned
+      .entry(variable)
+      .and_modify(|assigned| {
+        if *assigned != value_type {
+          *assigned = None;
+        }
+      })
+      .or_insert(value_type);
+  }
+
+  fn statements(&mut self, statements: &[Statement], top_level: bool) {
+    for statement in statements {
+      match statement {
+        Statement::Assignment { variable, value } => {
+          // Hosts read r, g and b out as numbers
+          let key = self.lut.scope_locations.get_by_right(variable).unwrap();
+          let value_type = if top_level && ["r", "g", "b"].contains(&key.name.as_str()) {
+            self.expect(value, ValueType::Number)
+          } else {
+            self.check(value)
+          };
+          self.assign(*variable, value_type);
+        }
+        Statement::Return(value) | Statement::Call(value) => {
+          self.check(value);
+        }
+        Statement::Break | Statement::Continue => {}
+        Statement::Repeat(RepeatStatement {
+          variable, block, ..
+        }) => {
+          self.assign(*variable, Some(ValueType::Number));
+          self.statements(block, top_level);
+        }
+        Statement::ForEach {
+          binding,
+          iterable,
+          body,
+        } => {
+          self.expect(iterable, ValueType::Tuple);
+          self.assign(*binding, None);
+          self.statements(body, top_level);
+        }
+        Statement::If(if_statement) => {
+          let mut if_statement = if_statement;
+          loop {
+            self.expect(&if_statement.condition, ValueType::Number);
+            self.statements(&if_statement.if_branch, top_level);
+            match &if_statement.else_branch {
+              ElseBranch::IfStatement(else_if) => if_statement = else_if,
+              ElseBranch::ElseStatement(else_block) => {
+                self.statements(else_block, top_level);
+                break;
+              }
+              ElseBranch::None => break,
+            }
+          }
+        }
+      }
+    }
+  }
+
+  // Checks `expression` and reports it if it's sure to be something other
+  // than `expected`
+  fn expect(&mut self, expression: &Expression, expected: ValueType) -> Option<ValueType> {
+    let found = self.check(expression)?;
+    if found != expected {
+      self.errors.push(LanguageError {
+        location: Some(expression.location.clone()),
+        error: LanguageErrorType::StaticType(expected, found),
+      });
+    }
+    Some(expected)
+  }
+
+  // Like check, for an operand of +, -, * or /, which can't be a string
+  fn arithmetic(&mut self, expression: &Expression) -> Option<ValueType> {
+    let found = self.check(expression);
+    if found == Some(ValueType::Str) {
+      self.errors.push(LanguageError {
+        location: Some(expression.location.clone()),
+        error: LanguageErrorType::StaticType(ValueType::Number, ValueType::Str),
+      });
+      return None;
+    }
+    found
+  }
+
+  // Checks everything inside `expression` and returns its type, if known
+  fn check(&mut self, expression: &Expression) -> Option<ValueType> {
+    match &expression.op {
+      ExpressionOp::NumberLiteral(_) => Some(ValueType::Number),
+      ExpressionOp::StringLiteral(_) => Some(ValueType::Str),
+      ExpressionOp::Reference(identifier) => self.variables.get(identifier).copied().flatten(),
+      ExpressionOp::TupleLiteral(values) | ExpressionOp::ConstantTuple(_, values) => {
+        for value in values {
+          self.check(value);
+        }
+        Some(ValueType::Tuple)
+      }
+      ExpressionOp::Index(tuple, index) => {
+        self.expect(tuple, ValueType::Tuple);
+        self.expect(index, ValueType::Number);
+        None
+      }
+      ExpressionOp::Slice(tuple, start, end) => {
+        self.expect(tuple, ValueType::Tuple);
+        for bound in [start, end].into_iter().flatten() {
+          self.expect(bound, ValueType::Number);
+        }
+        Some(ValueType::Tuple)
+      }
+      ExpressionOp::Equal(lhs, rhs) | ExpressionOp::NotEqual(lhs, rhs) => {
+        // Either type works, as long as both sides match
+        if let (Some(lhs), Some(found)) = (self.check(lhs), self.check(rhs)) {
+          if lhs != found {
+            self.errors.push(LanguageError {
+              location: Some(rhs.location.clone()),
+              error: LanguageErrorType::StaticType(lhs, found),
+            });
+          }
+        }
+        Some(ValueType::Number)
+      }
+      ExpressionOp::Ternary(condition, if_true, if_false) => {
+        self.expect(condition, ValueType::Number);
+        let if_true = self.check(if_true);
+        let if_false = self.check(if_false);
+        if if_true == if_false {
+          if_true
+        } else {
+          None
+        }
+      }
+      ExpressionOp::FunctionCall(function, arguments) => match function {
+        FunctionIdentifier::UserDefined(_) | FunctionIdentifier::Host(_) => {
+          for argument in arguments {
+            self.check(argument);
+          }
+          None
+        }
+        FunctionIdentifier::Print => self.check(&arguments[0]),
+        FunctionIdentifier::Assert => {
+          self.expect(&arguments[0], ValueType::Number);
+          for message in &arguments[1..] {
+            self.check(message);
+          }
+          Some(ValueType::Number)
+        }
+        FunctionIdentifier::Mix => {
+          let types = arguments
+            .iter()
+            .map(|argument| self.check(argument))
+            .collect::<Vec<Option<ValueType>>>();
+          if types.contains(&Some(ValueType::Tuple)) {
+            Some(ValueType::Tuple)
+          } else if types.iter().all(|found| *found == Some(ValueType::Number)) {
+            Some(ValueType::Number)
+          } else {
+            None
+          }
+        }
+        FunctionIdentifier::Hsv | FunctionIdentifier::Range => {
+          for argument in arguments {
+            self.expect(argument, ValueType::Number);
+          }
+          Some(ValueType::Tuple)
+        }
+        FunctionIdentifier::Map(_) | FunctionIdentifier::Reverse => {
+          self.expect(&arguments[0], ValueType::Tuple);
+          Some(ValueType::Tuple)
+        }
+        FunctionIdentifier::Reduce(_) => {
+          self.expect(&arguments[0], ValueType::Tuple);
+          self.check(&arguments[1]);
+          None
+        }
+        FunctionIdentifier::IndexWrap | FunctionIdentifier::IndexClamp => {
+          self.expect(&arguments[0], ValueType::Tuple);
+          self.expect(&arguments[1], ValueType::Number);
+          None
+        }
+        FunctionIdentifier::Normalize => {
+          self.expect(&arguments[0], ValueType::Tuple);
+          Some(ValueType::Tuple)
+        }
+        FunctionIdentifier::Len
+        | FunctionIdentifier::Sum
+        | FunctionIdentifier::Product
+        | FunctionIdentifier::Dot
+        | FunctionIdentifier::Magnitude => {
+          for argument in arguments {
+            self.expect(argument, ValueType::Tuple);
+          }
+          Some(ValueType::Number)
+        }
+        _ => {
+          for argument in arguments {
+            self.expect(argument, ValueType::Number);
+          }
+          Some(ValueType::Number)
+        }
+      },
+      ExpressionOp::Neg(value) | ExpressionOp::Invert(value) => {
+        self.expect(value, ValueType::Number);
+        Some(ValueType::Number)
+      }
+      // Any mix of numbers and tuples works, and a tuple on either side makes
+      // a tuple
+      ExpressionOp::Add(lhs, rhs)
+      | ExpressionOp::Mul(lhs, rhs)
+      | ExpressionOp::Sub(lhs, rhs)
+      | ExpressionOp::Div(lhs, rhs) => match (self.arithmetic(lhs), self.arithmetic(rhs)) {
+        (Some(ValueType::Number), Some(ValueType::Number)) => Some(ValueType::Number),
+        (Some(ValueType::Tuple), _) | (_, Some(ValueType::Tuple)) => Some(ValueType::Tuple),
+        _ => None,
+      },
+      ExpressionOp::FloorDiv(lhs, rhs)
+      | ExpressionOp::BinaryAnd(lhs, rhs)
+      | ExpressionOp::Xor(lhs, rhs)
+      | ExpressionOp::ShiftLeft(lhs, rhs)
+      | ExpressionOp::ShiftRight(lhs, rhs)
+      | ExpressionOp::BinaryOr(lhs, rhs)
+      | ExpressionOp::GreaterThan(lhs, rhs)
+      | ExpressionOp::LessThan(lhs, rhs)
+      | ExpressionOp::LessThanOrEqual(lhs, rhs)
+      | ExpressionOp::GreaterThanOrEqual(lhs, rhs)
+      | ExpressionOp::Or(lhs, rhs)
+      | ExpressionOp::And(lhs, rhs)
+      | ExpressionOp::Modulo(lhs, rhs)
+      | ExpressionOp::Pow(lhs, rhs) => {
+        self.expect(lhs, ValueType::Number);
+        self.expect(rhs, ValueType::Number);
+        Some(ValueType::Number)
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::{ExecutionContext, LanguageErrorType, ParseOptions, ValueType};
+  use alloc::vec::Vec;
+
+  // The (expected, found) pair of each error typecheck reports for `source`
+  fn errors(source: &str) -> Vec<(ValueType, ValueType)> {
+    let mut context = ExecutionContext::default();
+    let program = ParseOptions::default().parse(&mut context, source).unwrap();
+    match program.typecheck(&context.export_scope_locations()) {
+      Ok(()) => Vec::new(),
+      Err(errors) => errors
+        .into_iter()
+        .map(|err| match err.error {
+          LanguageErrorType::StaticType(expected, found) => (expected, found),
+          error => panic!("{error}"),
+        })
+        .collect(),
+    }
+  }
+
+  #[test]
+  fn programs_that_always_work_pass() {
+    for source in [
+      "r = x * 2; g = sin(y); b = len([1, 2]);",
+      "t = [x, y]; r = t[0]; g = t[1]; b = dot(t, t);",
+      "function f(v) { return v; } r = f(1); g = f([1]); b = 0;",
+    ] {
+      assert_eq!(errors(source), [], "{source}");
+    }
+  }
+
+  #[test]
+  fn tuples_where_numbers_belong_are_flagged() {
+    assert_eq!(
+      errors("r = sin([1, 2]); g = 0; b = 0;"),
+      [(ValueType::Number, ValueType::Tuple)]
+    );
+    assert_eq!(
+      errors("t = [1, 2] * 2; r = t < 1; g = 0; b = 0;"),
+      [(ValueType::Number, ValueType::Tuple)]
+    );
+    // Color channels have to be numbers too
+    assert_eq!(
+      errors("r = [1, 2]; g = 0; b = 0;"),
+      [(ValueType::Number, ValueType::Tuple)]
+    );
+  }
+
+  #[test]
+  fn numbers_where_tuples_belong_are_flagged() {
+    assert_eq!(
+      errors("n = 3; r = len(n) + n[0]; g = 0; b = 0;"),
+      [
+        (ValueType::Tuple, ValueType::Number),
+        (ValueType::Tuple, ValueType::Number)
+      ]
+    );
+  }
+
+  #[test]
+  fn variables_of_more_than_one_type_are_let_through() {
+    assert_eq!(
+      errors("v = 1; if (x) { v = [1]; } r = sin(v); g = 0; b = 0;"),
+      []
+    );
+    assert_eq!(
+      errors("a = [1]; c = d; d = a; d = 0; r = sin(c); g = 0; b = 0;"),
+      []
+    );
+  }
+
+  #[test]
+  fn types_flow_through_assignments_in_any_order() {
+    assert_eq!(
+      errors("c = d; d = a; a = [1]; r = sin(c); g = 0; b = 0;"),
+      [(ValueType::Number, ValueType::Tuple)]
+    );
+  }
+}
@@ -1,30 +1,187 @@
-use anarchy_core::{parse, ExecutionContext, UntrackedValue, Value, VariableKey};
+use anarchy_core::{
+  BlendMode, CompiledProgram, ExecutionContext, ExecutionContextLUT, FrameParams,
+  MissingChannelMode, NonFiniteMode, ParseError, ParseOptions, ParsedLanguage, VariableKey,
+  STACK_SIZE,
+};
+use notify::{RecursiveMode, Watcher};
 use ringbuf::{HeapRb, Rb};
 use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::sync::{Arc, Mutex, RwLock};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use winit::dpi::LogicalSize;
 use winit::dpi::Size;
-use winit::event::{Event, WindowEvent};
+use winit::event::{ElementState, Event, KeyEvent, WindowEvent};
 use winit::event_loop::{ControlFlow, EventLoop, EventLoopBuilder};
+use winit::keyboard::{Key, NamedKey};
 use winit::window::WindowBuilder;
 
-const HEIGHT: usize = 200;
-const WIDTH: usize = 200;
+// Shown for pixels where the program returns before writing a color
+const CLEAR_COLOR: [u8; 4] = [0, 0, 0, 255];
 
 #[derive(Debug, Clone)]
 struct FrameMessage {
   buffer: Vec<u32>,
   time: Instant,
+  // Set if rendering stopped partway through. Formatted, since
+  // LanguageError can't leave the worker's thread
+  error: Option<String>,
+}
+
+struct Args {
+  width: usize,
+  height: usize,
+  // At most this many frames are shown per second, 0 for no limit
+  fps: u32,
+  path: PathBuf,
+}
+
+const USAGE: &str = "Usage: anarchy_gui [--width N] [--height N] [--fps N] [path]";
+
+// anarchy_gui [--width N] [--height N] [--fps N] [path]
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+  let mut parsed = Args {
+    width: 200,
+    height: 200,
+    fps: 60,
+    path: PathBuf::from("./input.anarchy"),
+  };
+  while let Some(arg) = args.next() {
+    match arg.as_str() {
+      "--width" | "--height" => {
+        let value = args.next().ok_or(format!("{arg} needs a value"))?;
+        let value = match value.parse::<usize>() {
+          Ok(value) if value > 0 => value,
+          _ => return Err(format!("{arg} must be a positive integer, got {value}")),
+        };
+        if arg == "--width" {
+          parsed.width = value;
+        } else {
+          parsed.height = value;
+        }
+      }
+      "--fps" => {
+        let value = args.next().ok_or(format!("{arg} needs a value"))?;
+        parsed.fps = value
+          .parse::<u32>()
+          .map_err(|_| format!("{arg} must be a non-negative integer, got {value}"))?;
+      }
+      flag if flag.starts_with("--") => return Err(format!("Unknown flag {flag}")),
+      path => parsed.path = PathBuf::from(path),
+    }
+  }
+  Ok(parsed)
+}
+
+// How far `.` and `,` move time while paused, in ms
+const FRAME_STEP_MS: f32 = 1000.0 / 60.0;
+
+// Program time in ms, which stands still while paused
+struct Clock {
+  start_time: Instant,
+  // Time spent paused, which doesn't count
+  offset: f32,
+  paused_at: Option<f32>,
+}
+
+impl Clock {
+  fn new() -> Self {
+    Self {
+      start_time: Instant::now(),
+      offset: 0.0,
+      paused_at: None,
+    }
+  }
+  fn time(&self, at: Instant) -> f32 {
+    match self.paused_at {
+      Some(time) => time,
+      None => at.saturating_duration_since(self.start_time).as_millis() as f32 - self.offset,
+    }
+  }
+  fn toggle_pause(&mut self) {
+    let now = Instant::now();
+    match self.paused_at.take() {
+      // Pick up from wherever time was left
+      Some(time) => self.offset = (now - self.start_time).as_millis() as f32 - time,
+      None => self.paused_at = Some(self.time(now)),
+    }
+  }
+  fn step(&mut self, delta: f32) {
+    if let Some(time) = &mut self.paused_at {
+      *time = (*time + delta).max(0.0);
+    }
+  }
+}
+
+// One version of the program, swapped out whenever the file changes
+struct Program {
+  // Kept around to point errors at the code that caused them
+  code: String,
+  parsed_language: ParsedLanguage,
+  scope_locations: ExecutionContextLUT,
+  mouse_x_identifier: usize,
+  mouse_y_identifier: usize,
+}
+
+impl Program {
+  // Each worker renders with a copy of its own
+  fn compile(&self) -> CompiledProgram {
+    CompiledProgram::new(
+      ExecutionContext::new_with_scope_locations(self.scope_locations.clone()),
+      self.parsed_language.clone(),
+    )
+  }
+}
+
+fn load(code: &str) -> Result<Program, ParseError> {
+  let mut context = ExecutionContext::default();
+  let options = ParseOptions {
+    implicit_color_output: true,
+  };
+  let mut parsed_language = options.parse(&mut context, code)?;
+  println!("Finished parsing!");
+  for warning in parsed_language.lint(&context.export_scope_locations()) {
+    println!("{warning}");
+  }
+  parsed_language.optimize();
+  let mut register = |name: &str| {
+    context.register(VariableKey {
+      name: name.to_string(),
+      scope: "".to_string(),
+    })
+  };
+  let mouse_x_identifier = register("mouse_x");
+  let mouse_y_identifier = register("mouse_y");
+  Ok(Program {
+    code: code.to_string(),
+    parsed_language,
+    scope_locations: context.export_scope_locations(),
+    mouse_x_identifier,
+    mouse_y_identifier,
+  })
 }
 
 fn main() {
-  let code = std::fs::read_to_string("./input.anarchy").unwrap();
+  let Args {
+    width,
+    height,
+    fps,
+    path,
+  } = match parse_args(std::env::args().skip(1)) {
+    Ok(args) => args,
+    Err(err) => {
+      eprintln!("{err}");
+      eprintln!("{USAGE}");
+      std::process::exit(1);
+    }
+  };
+  let code = std::fs::read_to_string(&path).unwrap();
   let event_loop: EventLoop<FrameMessage> = EventLoopBuilder::with_user_event().build().unwrap();
   let window = Rc::new(
     WindowBuilder::new()
-      .with_inner_size(Size::Logical(LogicalSize::new(HEIGHT as f64, WIDTH as f64)))
+      .with_title("anarchy_gui")
+      .with_inner_size(Size::Logical(LogicalSize::new(width as f64, height as f64)))
       .build(&event_loop)
       .unwrap(),
   );
@@ -32,89 +189,131 @@ fn main() {
   let mut surface = softbuffer::Surface::new(&context, window.clone()).unwrap();
   surface
     .resize(
-      NonZeroU32::new(WIDTH as u32).unwrap(),
-      NonZeroU32::new(HEIGHT as u32).unwrap(),
+      NonZeroU32::new(width as u32).unwrap(),
+      NonZeroU32::new(height as u32).unwrap(),
     )
     .unwrap();
 
-  let context = Rc::new(Mutex::new(ExecutionContext::default()));
-  let parsed_language = parse(context.clone(), &code).unwrap();
-  println!("Finished parsing!");
-  let mut context = Rc::try_unwrap(context).unwrap().into_inner().unwrap();
-  let r_identifier = context.register(VariableKey {
-    name: "r".to_string(),
-    scope: "".to_string(),
-  });
-  let g_identifier = context.register(VariableKey {
-    name: "g".to_string(),
-    scope: "".to_string(),
-  });
-  let b_identifier = context.register(VariableKey {
-    name: "b".to_string(),
-    scope: "".to_string(),
-  });
-  let time_identifier = context.register(VariableKey {
-    name: "time".to_string(),
-    scope: "".to_string(),
-  });
-  let random_identifier = context.register(VariableKey {
-    name: "random".to_string(),
-    scope: "".to_string(),
-  });
-  let x_identifier = context.register(VariableKey {
-    name: "x".to_string(),
-    scope: "".to_string(),
-  });
-  let y_identifier = context.register(VariableKey {
-    name: "y".to_string(),
-    scope: "".to_string(),
-  });
+  let program = match load(&code) {
+    Ok(program) => Arc::new(RwLock::new(Arc::new(program))),
+    Err(err) => {
+      eprintln!("{}", err.render(&code));
+      std::process::exit(1);
+    }
+  };
+  // Editors tend to save by replacing the file, so watch its directory
+  let directory = match path.parent() {
+    Some(directory) if !directory.as_os_str().is_empty() => directory,
+    _ => Path::new("."),
+  };
+  let mut watcher = {
+    let program = Arc::clone(&program);
+    let path = path.clone();
+    let mut last_code = code;
+    notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+      let Ok(event) = event else {
+        return;
+      };
+      if !event
+        .paths
+        .iter()
+        .any(|changed| changed.file_name() == path.file_name())
+      {
+        return;
+      }
+      // One save usually fires a few events
+      let Ok(code) = std::fs::read_to_string(&path) else {
+        return;
+      };
+      if code == last_code {
+        return;
+      }
+      match load(&code) {
+        Ok(reloaded) => {
+          println!("Reloaded {}", path.display());
+          *program.write().unwrap() = Arc::new(reloaded);
+        }
+        // Keep rendering the last program that worked
+        Err(err) => println!("{}", err.render(&code)),
+      }
+      last_code = code;
+    })
+    .unwrap()
+  };
+  watcher
+    .watch(directory, RecursiveMode::NonRecursive)
+    .unwrap();
+
   let random: f32 = rand::random();
+  // Where the cursor is on the canvas, in pixels. Parked in the middle while
+  // it's outside the window
+  let canvas_center = (width as f32 / 2.0, height as f32 / 2.0);
+  let mouse = Arc::new(RwLock::new(canvas_center));
+  // How workers and the display thread share out frames:
+  // - A worker claims the next time after latest_queued_time, but never one at
+  //   or before latest_drawn_time, so its frame is newer than whatever was on
+  //   screen when it started
+  // - The display thread throws away frames at or before latest_drawn_time and
+  //   draws one of the rest. With none left it blocks on the channel
+  // So a frame is only ever thrown away because something newer was drawn, and
+  // however slow the program is the display keeps moving forward
   let latest_drawn_time = Arc::new(RwLock::new(Instant::now()));
   let latest_queued_time = Arc::new(Mutex::new(Instant::now()));
-  let start_time = Instant::now();
+  let clock = Arc::new(RwLock::new(Clock::new()));
 
   let (frame_tx, frame_rx) = std::sync::mpsc::channel();
 
   const WORKER_COUNT: u32 = 16;
+  // None when uncapped
+  let frame_interval = (fps > 0).then(|| Duration::from_secs(1) / fps);
 
   for _ in 0..WORKER_COUNT {
-    let scope_locations = context.export_scope_locations();
+    let shared_program = Arc::clone(&program);
+    let mouse = Arc::clone(&mouse);
+    let clock = Arc::clone(&clock);
     let frame_tx = frame_tx.clone();
-    let parsed_language = parsed_language.clone();
     let latest_queued_time = Arc::clone(&latest_queued_time);
     let latest_drawn_time = Arc::clone(&latest_drawn_time);
-    std::thread::spawn(move || {
+    // Sized for recursive programs, see STACK_SIZE
+    let worker = std::thread::Builder::new().stack_size(STACK_SIZE);
+    let spawned = worker.spawn(move || {
       let mut last_render_durations = HeapRb::<Duration>::new(16);
-      let random = Value::Number(random);
-      let mut context = ExecutionContext::new_with_scope_locations(scope_locations);
+      let mut rgba = vec![0; height * width * 4];
+      let mut program = Arc::clone(&shared_program.read().unwrap());
+      let mut compiled = program.compile();
       loop {
+        let latest = Arc::clone(&shared_program.read().unwrap());
+        if !Arc::ptr_eq(&latest, &program) {
+          program = latest;
+          compiled = program.compile();
+        }
+        let avg_render_time = {
+          let length = last_render_durations.len() as u64;
+          let mut total_ms = 0_u64;
+          for frame_time in last_render_durations.iter() {
+            println!("Avg entry: {frame_time:?}");
+            total_ms += frame_time.as_millis() as u64;
+          }
+          Duration::from_millis(total_ms.checked_div(length).unwrap_or(100))
+        };
+        println!("Current avg render time is {avg_render_time:?}");
         let mut message = FrameMessage {
-          buffer: Vec::with_capacity(HEIGHT * WIDTH),
+          buffer: Vec::new(),
+          error: None,
           time: {
             let mut latest_queued_time = latest_queued_time.lock().unwrap();
-            let avg_render_time = {
-              let length = last_render_durations.len() as u64;
-              let mut average_ms = 0_u64;
-              for frame_time in last_render_durations.iter() {
-                println!("Avg entry: {frame_time:?}");
-                average_ms += frame_time.as_millis() as u64;
-              }
-              if length == 0 {
-                average_ms = 100;
-              } else {
-                average_ms /= length;
-              }
-              Duration::from_millis(average_ms)
+            // No point rendering frames closer together than they're shown
+            let spacing = match frame_interval {
+              Some(frame_interval) => frame_interval.max(avg_render_time / WORKER_COUNT),
+              None => avg_render_time / WORKER_COUNT,
             };
-            println!("Current avg render time is {avg_render_time:?}");
-
-            let our_time = *latest_queued_time + avg_render_time / WORKER_COUNT;
+            let our_time = *latest_queued_time + spacing;
             let latest_drawn_time = latest_drawn_time.read().unwrap();
-            let our_time = if *latest_drawn_time > our_time {
-              // We're falling behind, catch up:
+            let our_time = if *latest_drawn_time >= our_time {
+              // We're falling behind, catch up. Landing on the drawn frame's
+              // own time would get this one thrown away
               println!("Falling behind, catching up!");
-              *latest_drawn_time
+              *latest_drawn_time + spacing
             } else {
               our_time
             };
@@ -122,36 +321,58 @@ fn main() {
             our_time
           },
         };
-        message.buffer.resize(HEIGHT * WIDTH, 0u32);
-        let time = Value::Number((message.time - start_time).as_millis() as f32);
+        if frame_interval.is_some() {
+          // With the frames spaced out, the queue runs ahead of the clock, so
+          // hold off until this one would be finished just in time
+          if let Some(start) = message.time.checked_sub(avg_render_time) {
+            std::thread::sleep(start.saturating_duration_since(Instant::now()));
+          }
+        }
+        let (mouse_x, mouse_y) = *mouse.read().unwrap();
+        let params = FrameParams {
+          width,
+          height,
+          time: clock.read().unwrap().time(message.time),
+          random,
+          identifiers: compiled.identifiers(),
+          variables: &[
+            (program.mouse_x_identifier, mouse_x),
+            (program.mouse_y_identifier, mouse_y),
+          ],
+          invariant: None,
+          clear_color: CLEAR_COLOR,
+          blend: BlendMode::Replace,
+          non_finite: NonFiniteMode::Error,
+          missing_channel: MissingChannelMode::Error,
+        };
 
         let render_start = Instant::now();
-        for index in 0..HEIGHT * WIDTH {
-          let x = index % WIDTH;
-          let y = index / WIDTH;
-          context.reset();
-          context.set(x_identifier, Value::Number(x as f32));
-          context.set(y_identifier, Value::Number(y as f32));
-          context.set(time_identifier, time.clone());
-          context.set(random_identifier, random.clone());
-          Result::from(anarchy_core::execute(&mut context, &parsed_language)).unwrap();
-          let red: f32 = UntrackedValue(context.unattributed_get(r_identifier).unwrap())
-            .try_into()
-            .unwrap();
-          let green: f32 = UntrackedValue(context.unattributed_get(g_identifier).unwrap())
-            .try_into()
-            .unwrap();
-          let blue: f32 = UntrackedValue(context.unattributed_get(b_identifier).unwrap())
-            .try_into()
-            .unwrap();
-          message.buffer[index] =
-            ((blue as u32) & 0xff) | (((green as u32) & 0xff) << 8) | (((red as u32) & 0xff) << 16);
+        // If it fails partway, show what rendered so far along with the error
+        for pixel in rgba.chunks_exact_mut(4) {
+          pixel.copy_from_slice(&CLEAR_COLOR);
+        }
+        if let Err(error) = compiled.render(params, &mut rgba) {
+          message.error = Some(error.render(&program.code));
+        }
+        message.buffer = rgba
+          .chunks_exact(4)
+          .map(|pixel| u32::from_be_bytes([0, pixel[0], pixel[1], pixel[2]]))
+          .collect();
+        #[cfg(feature = "profile")]
+        {
+          let profile = compiled.context_mut().take_profile();
+          println!(
+            "{:?}/frame, {:.1} ops/pixel",
+            render_start.elapsed(),
+            profile.evaluations_per_pixel()
+          );
         }
         last_render_durations.push_overwrite(render_start.elapsed());
         println!("Alright, sending. We took {:?}", render_start.elapsed());
         frame_tx.send(message).unwrap();
       }
     });
+    spawned.unwrap();
   }
 
   {
@@ -162,7 +383,7 @@ fn main() {
       loop {
         // println!("Starting a loop...");
         if frame_queue.is_empty() {
-          // println!("Waiting for events...");
+          // Nothing to choose from, so wait rather than spin
           let frame = frame_rx.recv().unwrap();
           frame_queue.push(frame);
         }
@@ -175,15 +396,13 @@ fn main() {
         // println!("Picking the best! (We have {})", frame_queue.len());
 
         let mut chosen_frame: Option<(FrameMessage, Duration)> = None;
-        let mut now = Instant::now();
+        // Held only while choosing, workers need it to claim their next frame
         let mut latest_drawn_time = latest_drawn_time.write().unwrap();
         {
           let latest_drawn_time = *latest_drawn_time;
           frame_queue.retain(|frame| frame.time > latest_drawn_time);
         }
-        if *latest_drawn_time > now {
-          now = *latest_drawn_time;
-        }
+        let now = Instant::now().max(*latest_drawn_time);
         for frame in frame_queue.iter() {
           let delta = if frame.time > now {
             frame.time - now
@@ -198,21 +417,32 @@ fn main() {
             chosen_frame = Some((frame.clone(), delta));
           }
         }
-        if let Some((chosen_frame, _)) = chosen_frame {
+        if let Some((chosen_frame, _)) = &chosen_frame {
           *latest_drawn_time = chosen_frame.time;
+        }
+        drop(latest_drawn_time);
+        if let Some((chosen_frame, _)) = chosen_frame {
           // Retain only frames after this one:
           frame_queue.retain(|frame| frame.time > chosen_frame.time);
           drawn_frames.push(Instant::now());
           drawn_frames.retain(|then| Instant::now() - *then < Duration::from_secs(10));
           println!("FPS: {}", drawn_frames.len() / 10);
+          let presented = Instant::now();
           event_loop.send_event(chosen_frame).unwrap();
+          if let Some(frame_interval) = frame_interval {
+            std::thread::sleep(frame_interval.saturating_sub(presented.elapsed()));
+          }
         } else {
+          // Everything queued was stale, and now the queue is empty so the
+          // next pass waits for a fresh frame
           println!("We're starving!");
         }
       }
     });
   }
 
+  let mut displayed_frame: Option<FrameMessage> = None;
+  let mut shown_error: Option<String> = None;
   event_loop
     .run(move |event, elwt| {
       elwt.set_control_flow(ControlFlow::Wait);
@@ -224,15 +454,99 @@ fn main() {
         } if window_id == window.id() => {
           elwt.exit();
         }
+        Event::WindowEvent {
+          event:
+            WindowEvent::KeyboardInput {
+              event:
+                KeyEvent {
+                  logical_key,
+                  state: ElementState::Pressed,
+                  repeat,
+                  ..
+                },
+              ..
+            },
+          window_id,
+        } if window_id == window.id() => match logical_key.as_ref() {
+          Key::Named(NamedKey::Space) if !repeat => clock.write().unwrap().toggle_pause(),
+          // Held down, these scrub through time
+          Key::Character(".") => clock.write().unwrap().step(FRAME_STEP_MS),
+          Key::Character(",") => clock.write().unwrap().step(-FRAME_STEP_MS),
+          Key::Character(key) if !repeat && key.eq_ignore_ascii_case("s") => {
+            if let Some(frame) = &displayed_frame {
+              save_screenshot(frame.buffer.clone(), width, height);
+            }
+          }
+          _ => {}
+        },
+        Event::WindowEvent {
+          event: WindowEvent::CursorMoved { position, .. },
+          window_id,
+        } if window_id == window.id() => {
+          let size = window.inner_size();
+          *mouse.write().unwrap() = (
+            position.x as f32 / size.width as f32 * width as f32,
+            position.y as f32 / size.height as f32 * height as f32,
+          );
+        }
+        Event::WindowEvent {
+          event: WindowEvent::CursorLeft { .. },
+          window_id,
+        } if window_id == window.id() => {
+          *mouse.write().unwrap() = canvas_center;
+        }
         Event::UserEvent(event) => {
+          let error = event.error.as_ref().map(|error| error.to_string());
+          if error != shown_error {
+            match &error {
+              Some(error) => {
+                println!("{error}");
+                // Only the first line, the rest is the source snippet
+                let summary = error.lines().next().unwrap_or_default();
+                window.set_title(&format!("anarchy_gui - {summary}"));
+              }
+              None => window.set_title("anarchy_gui"),
+            }
+            shown_error = error;
+          }
           let mut buffer = surface.buffer_mut().unwrap();
-          for index in 0..(WIDTH * HEIGHT) {
+          for index in 0..(width * height) {
             buffer[index] = event.buffer[index];
           }
           buffer.present().unwrap();
+          displayed_frame = Some(event);
         }
         _ => {}
       }
     })
     .unwrap();
 }
+
+// Writes a 0x00RRGGBB frame to screenshot-<unix millis>.png in the
+// background so rendering doesn't stall
+fn save_screenshot(buffer: Vec<u32>, width: usize, height: usize) {
+  std::thread::spawn(move || {
+    let rgba = buffer
+      .iter()
+      .flat_map(|pixel| {
+        let [_, red, green, blue] = pixel.to_be_bytes();
+        [red, green, blue, 255]
+      })
+      .collect::<Vec<u8>>();
+    let timestamp = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap()
+      .as_millis();
+    let path = format!("screenshot-{timestamp}.png");
+    match image::save_buffer(
+      &path,
+      &rgba,
+      width as u32,
+      height as u32,
+      image::ColorType::Rgba8,
+    ) {
+      Ok(()) => println!("Saved {path}"),
+      Err(err) => println!("Couldn't save {path}: {err}"),
+    }
+  });
+}
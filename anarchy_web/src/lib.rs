@@ -1,13 +1,16 @@
 use anarchy_core::pest::error::LineColLocation;
 use anarchy_core::{
-  ExecutionContext, LanguageError, Location, ParseError, ParsedLanguage, PestError, UntrackedValue,
-  VariableKey,
+  BlendMode, CompiledProgram, ExecutionContext, FrameParams, LanguageError, Location,
+  MissingChannelMode, NonFiniteMode, ParseError, ParseOptions, PestError,
 };
 use serde::Serialize;
-use std::rc::Rc;
-use std::sync::Mutex;
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use wasm_bindgen::prelude::*;
 
+#[allow(unused_macros)]
 macro_rules! console_log {
     // Note that this is using the `log` function imported above during
     // `bare_bones`
@@ -37,21 +40,23 @@ pub fn init() {
   console_error_panic_hook::set_once();
 }
 
-struct ParsedLanguageBundle {
-  execution_context: ExecutionContext,
-  parsed_language: ParsedLanguage,
-  x_identifier: usize,
-  y_identifier: usize,
-  time_identifier: usize,
-  random_identifier: usize,
-  r_identifier: usize,
-  g_identifier: usize,
-  b_identifier: usize,
+// A parsed program along with the state it renders with. Independent of
+// any other Program, so a page can run several at once
+#[wasm_bindgen]
+pub struct Program {
+  compiled: CompiledProgram,
+  // Extra variables pushed in from JS, set alongside time and random
+  uniforms: Vec<(usize, f32)>,
+  // How many lines print() has logged this frame
+  prints: Arc<AtomicUsize>,
+  spans: Vec<WebSpan>,
 }
 
-thread_local! {
-    static PARSED_LANGUAGE: Rc<Mutex<Option<ParsedLanguageBundle>>> = Rc::new(Mutex::new(None));
-}
+// Logging is slow, and print() runs for every pixel
+const PRINTS_PER_FRAME: usize = 20;
+// Rust's default stack for wasm32. It's fixed when the module is linked,
+// so recursion has to stay within it instead
+const WASM_STACK_SIZE: usize = 1 << 20;
 
 #[derive(Serialize, Debug, Clone)]
 enum ErrorLocation {
@@ -70,53 +75,175 @@ struct WebError {
   message: String,
   error_type: ErrorType,
 }
+// Where an expression is in the source, see ParsedLanguage::spans
+#[derive(Serialize, Debug, Clone)]
+struct WebSpan {
+  start: (u32, u32),
+  end: (u32, u32),
+  kind: String,
+}
 
 #[wasm_bindgen]
-pub fn parse(code: String) -> Result<(), JsValue> {
-  let context = Rc::new(Mutex::new(ExecutionContext::default()));
-  let parsed_language = match anarchy_core::parse(context.clone(), &code) {
-    Ok(parsed_language) => parsed_language,
-    Err(err) => {
-      return Err(serde_wasm_bindgen::to_value(&WebError::from(err)).unwrap());
+impl Program {
+  #[wasm_bindgen(constructor)]
+  pub fn new(code: String) -> Result<Program, JsValue> {
+    let mut context = ExecutionContext::default();
+    context.fit_call_depth_to_stack(WASM_STACK_SIZE);
+    let options = ParseOptions {
+      implicit_color_output: true,
+    };
+    let mut parsed_language = match options.parse(&mut context, &code) {
+      Ok(parsed_language) => parsed_language,
+      Err(err) => {
+        return Err(serde_wasm_bindgen::to_value(&WebError::from(err)).unwrap());
+      }
+    };
+    // Before optimizing folds any of them away
+    let spans = parsed_language
+      .spans()
+      .into_iter()
+      .map(|(location, kind)| WebSpan {
+        start: (location.start_line as u32, location.start_column as u32),
+        end: (location.end_line as u32, location.end_column as u32),
+        kind: format!("{kind:?}"),
+      })
+      .collect();
+    parsed_language.optimize();
+    let prints = Arc::new(AtomicUsize::new(0));
+    {
+      let prints = Arc::clone(&prints);
+      context.set_print_sink(move |line| {
+        if prints.fetch_add(1, Ordering::Relaxed) < PRINTS_PER_FRAME {
+          log(line);
+        }
+      });
     }
-  };
-  let mut context = Rc::try_unwrap(context).unwrap().into_inner().unwrap();
-  PARSED_LANGUAGE.with(|language| {
-    language.lock().unwrap().replace(ParsedLanguageBundle {
-      x_identifier: context.register(VariableKey {
-        name: "x".to_string(),
-        scope: "".to_string(),
-      }),
-      y_identifier: context.register(VariableKey {
-        name: "y".to_string(),
-        scope: "".to_string(),
-      }),
-      r_identifier: context.register(VariableKey {
-        name: "r".to_string(),
-        scope: "".to_string(),
-      }),
-      g_identifier: context.register(VariableKey {
-        name: "g".to_string(),
-        scope: "".to_string(),
-      }),
-      b_identifier: context.register(VariableKey {
-        name: "b".to_string(),
-        scope: "".to_string(),
-      }),
-      time_identifier: context.register(VariableKey {
-        name: "time".to_string(),
-        scope: "".to_string(),
-      }),
-      random_identifier: context.register(VariableKey {
-        name: "random".to_string(),
-        scope: "".to_string(),
-      }),
-      execution_context: context,
-      parsed_language,
-    });
-  });
-
-  Ok(())
+    Ok(Program {
+      compiled: CompiledProgram::new(context, parsed_language),
+      uniforms: Vec::new(),
+      prints,
+      spans,
+    })
+  }
+
+  // Every expression's [line, column] start and end, along with its kind
+  // (Variable, Number, Operator and so on), for highlighting
+  pub fn spans(&self) -> JsValue {
+    serde_wasm_bindgen::to_value(&self.spans).unwrap()
+  }
+
+  // Makes `name` read as `value` from the next frame on. Harmless if the
+  // program never mentions it
+  pub fn set_uniform(&mut self, name: &str, value: f32) {
+    let identifier = self.compiled.register(name);
+    match self
+      .uniforms
+      .iter_mut()
+      .find(|(uniform, _)| *uniform == identifier)
+    {
+      Some((_, uniform_value)) => *uniform_value = value,
+      None => self.uniforms.push((identifier, value)),
+    }
+  }
+
+  // clear_color is packed as 0xRRGGBBAA
+  pub fn execute(
+    &mut self,
+    image: &mut [u8],
+    width: usize,
+    height: usize,
+    time: u32,
+    random: f32,
+    clear_color: u32,
+  ) -> Result<(), JsValue> {
+    self.prints.store(0, Ordering::Relaxed);
+    self.render(image, width, height, time, random, clear_color, 0..height)
+  }
+
+  // Like execute, but only draws `row_count` rows starting at `first_row`.
+  // A big frame rendered a slice at a time, yielding to the event loop in
+  // between, leaves the worker free to take a new program mid-frame. It
+  // should then drop the half-drawn frame instead of calling this again, see
+  // renderFrame in www/worker.ts. Every slice of a frame needs the same time
+  // and random
+  #[allow(clippy::too_many_arguments)]
+  pub fn execute_rows(
+    &mut self,
+    image: &mut [u8],
+    width: usize,
+    height: usize,
+    time: u32,
+    random: f32,
+    clear_color: u32,
+    first_row: usize,
+    row_count: usize,
+  ) -> Result<(), JsValue> {
+    if first_row == 0 {
+      self.prints.store(0, Ordering::Relaxed);
+    }
+    self.render(
+      image,
+      width,
+      height,
+      time,
+      random,
+      clear_color,
+      first_row..first_row + row_count,
+    )
+  }
+
+  // What `name` held at the end of the last pixel execute() ran, or null if
+  // it wasn't set or isn't a number
+  pub fn get_variable(&self, name: &str) -> Option<f32> {
+    self.compiled.context().get_runtime(name)?.as_number()
+  }
+
+  // Every top-level number from the last pixel execute() ran, as a plain
+  // { name: value } object
+  pub fn dump_state(&self) -> JsValue {
+    let state = self
+      .compiled
+      .context()
+      .variables()
+      .filter(|(key, _)| key.scope.is_empty())
+      .filter_map(|(key, value)| Some((key.name.as_str(), value.as_number()?)))
+      .collect::<BTreeMap<&str, f32>>();
+    state
+      .serialize(&serde_wasm_bindgen::Serializer::json_compatible())
+      .unwrap()
+  }
+}
+
+impl Program {
+  #[allow(clippy::too_many_arguments)]
+  fn render(
+    &mut self,
+    image: &mut [u8],
+    width: usize,
+    height: usize,
+    time: u32,
+    random: f32,
+    clear_color: u32,
+    rows: Range<usize>,
+  ) -> Result<(), JsValue> {
+    let params = FrameParams {
+      width,
+      height,
+      time: time as f32,
+      random,
+      identifiers: self.compiled.identifiers(),
+      variables: &self.uniforms,
+      invariant: None,
+      clear_color: clear_color.to_be_bytes(),
+      blend: BlendMode::Replace,
+      non_finite: NonFiniteMode::Error,
+      missing_channel: MissingChannelMode::Zero,
+    };
+    self
+      .compiled
+      .render_rows(params, rows, image)
+      .map_err(|err| serde_wasm_bindgen::to_value(&WebError::from(err)).unwrap())
+  }
 }
 
 impl From<LanguageError> for WebError {
@@ -165,71 +292,30 @@ impl From<ParseError> for WebError {
   }
 }
 
-#[wasm_bindgen]
-pub fn execute(
-  image: &mut [u8],
-  width: usize,
-  height: usize,
-  time: u32,
-  random: f32,
-) -> Result<(), JsValue> {
-  execute_inner(image, width, height, time, random)
-    .map_err(|err| serde_wasm_bindgen::to_value(&WebError::from(err)).unwrap())
-}
-fn execute_inner(
-  image: &mut [u8],
-  width: usize,
-  height: usize,
-  time: u32,
-  random: f32,
-) -> Result<(), LanguageError> {
-  PARSED_LANGUAGE.with(|language| {
-    let mut parsed_language = language.lock().unwrap();
-    let parsed_language = parsed_language.as_mut().unwrap();
-    for y in 0..height {
-      for x in 0..width {
-        parsed_language
-          .execution_context
-          .set(parsed_language.x_identifier, (x as f32).into());
-        parsed_language
-          .execution_context
-          .set(parsed_language.y_identifier, (y as f32).into());
-        parsed_language
-          .execution_context
-          .set(parsed_language.time_identifier, (time as f32).into());
-        parsed_language
-          .execution_context
-          .set(parsed_language.random_identifier, random.into());
-
-        Result::from(anarchy_core::execute(
-          &mut parsed_language.execution_context,
-          &parsed_language.parsed_language,
-        ))?;
-
-        let base_position = width * y * 4 + x * 4;
-        let r: f32 = UntrackedValue(
-          parsed_language
-            .execution_context
-            .unattributed_get(parsed_language.r_identifier)?,
-        )
-        .try_into()?;
-        let g: f32 = UntrackedValue(
-          parsed_language
-            .execution_context
-            .unattributed_get(parsed_language.g_identifier)?,
-        )
-        .try_into()?;
-        let b: f32 = UntrackedValue(
-          parsed_language
-            .execution_context
-            .unattributed_get(parsed_language.b_identifier)?,
-        )
-        .try_into()?;
-        image[base_position] = r as u8;
-        image[base_position + 1] = g as u8;
-        image[base_position + 2] = b as u8;
-      }
+#[cfg(test)]
+mod tests {
+  use super::Program;
+
+  fn render(code: &str) -> Vec<u8> {
+    let mut program = Program::new(code.to_string()).unwrap();
+    let mut image = vec![0u8; 2 * 2 * 4];
+    program.execute(&mut image, 2, 2, 0, 0.0, 0).unwrap();
+    image
+  }
+
+  #[test]
+  fn programs_can_set_partial_transparency() {
+    let image = render("r = 255; g = 10; b = 20; a = 128;");
+    for pixel in image.chunks_exact(4) {
+      assert_eq!(pixel, [255, 10, 20, 128]);
     }
-    Ok(())
-  })
+  }
+
+  #[test]
+  fn pixels_are_opaque_unless_the_program_sets_alpha() {
+    let image = render("r = 1; g = 2; b = 3;");
+    for pixel in image.chunks_exact(4) {
+      assert_eq!(pixel, [1, 2, 3, 255]);
+    }
+  }
 }
@@ -7,24 +7,64 @@ import {
 } from "./workerTypes";
 
 let anarchy: typeof import("anarchy_web") | null = null;
+let program: import("anarchy_web").Program | null = null;
 
 async function start() {
   anarchy = await import("anarchy_web");
   anarchy.init();
   const code = await getSavedCode();
-  anarchy.parse(code);
+  program = new anarchy.Program(code);
   sendMessage({type: "parsed", code});
   run();
 }
 
+// Rows drawn between checks for new messages, so an edit to a slow program
+// doesn't have to wait for its frame to finish
+const ROWS_PER_SLICE = 10;
+// Bumped whenever the program is swapped out, which abandons any frame still
+// being drawn with the old one
+let generation = 0;
+let rendering = false;
+
 let runInterval = null as null | number;
 function run() {
   runInterval = setInterval(() => {
-    const imageBuffer = new ArrayBuffer(HEIGHT * WIDTH * 4);
-    const buffer = new Uint8Array(imageBuffer);
-    buffer.fill(255);
+    if (!rendering) {
+      rendering = true;
+      renderFrame().finally(() => {
+        rendering = false;
+      });
+    }
+  });
+}
+
+// Draws a frame ROWS_PER_SLICE rows at a time, yielding in between so
+// messages get handled. If parse() swaps the program in the meantime the
+// half-drawn frame is dropped rather than sent
+async function renderFrame() {
+  const started = generation;
+  const imageBuffer = new ArrayBuffer(HEIGHT * WIDTH * 4);
+  const buffer = new Uint8Array(imageBuffer);
+  // The same for every slice, or the frame would tear
+  const frameTime = Date.now() - time;
+  for (let row = 0; row < HEIGHT; row += ROWS_PER_SLICE) {
+    if (row > 0) {
+      await new Promise((resolve) => setTimeout(resolve));
+    }
+    if (generation != started || !program) {
+      return;
+    }
     try {
-      anarchy?.execute(buffer, WIDTH, HEIGHT, Date.now() - time, random);
+      program.execute_rows(
+        buffer,
+        WIDTH,
+        HEIGHT,
+        frameTime,
+        random,
+        CLEAR_COLOR,
+        row,
+        ROWS_PER_SLICE,
+      );
     } catch (err) {
       if (err && typeof err == "object" && (err as WebError).error_type) {
         const newError = err as WebError;
@@ -32,11 +72,11 @@ function run() {
       }
       return;
     }
+  }
 
-    sendMessage({
-      type: "draw",
-      data: imageBuffer,
-    });
+  sendMessage({
+    type: "draw",
+    data: imageBuffer,
   });
 }
 
@@ -48,6 +88,8 @@ start();
 
 const WIDTH = 100;
 const HEIGHT = 100;
+// 0xRRGGBBAA, used for pixels the program returns early from
+const CLEAR_COLOR = 0xffffffff;
 // const IMAGE_BUFFER = new SharedArrayBuffer(WIDTH * HEIGHT * 4);
 // () => {
 //   const array = new Uint8ClampedArray(IMAGE_BUFFER);
@@ -66,7 +108,13 @@ function parse(code: string) {
   random = Math.random();
   sendMessage({type: "runtimeError", error: null});
   try {
-    anarchy?.parse(code);
+    if (anarchy) {
+      const parsed = new anarchy.Program(code);
+      // Only swapped out once the new code parses
+      program?.free();
+      program = parsed;
+      generation++;
+    }
   } catch (err) {
     if (err && typeof err == "object" && (err as WebError).error_type) {
       const newError = err as WebError;